@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use color_eyre::{eyre::eyre, Result};
+use http_body_util::BodyExt;
+use hyper::body::Bytes;
+use hyper::{HeaderMap, Response, StatusCode};
+
+use crate::luax::utils::url_matches;
+use crate::server::{full, ResponseBody};
+
+pub const DEFAULT_MAX_AGE: u64 = 60;
+pub const DEFAULT_MAX_ENTRIES: usize = 256;
+
+/// How a response may be cached, as configured by the manifest's `cache` table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachePolicy {
+    pub max_age: Duration,
+    pub max_entries: usize,
+}
+
+impl CachePolicy {
+    /// Get the policy for a path from the manifest, if it matches one of the
+    /// patterns in `cache.paths`
+    pub fn from_manifest<'lua>(
+        ctx: rlua::Context<'lua>,
+        manifest: &rlua::Table<'lua>,
+        path: &str,
+    ) -> rlua::Result<Option<Self>> {
+        let cache: rlua::Table = match manifest.get("cache")? {
+            Some(cache) => cache,
+            None => return Ok(None),
+        };
+
+        let patterns: Vec<String> = cache.get::<_, Option<_>>("paths")?.unwrap_or_default();
+
+        for pattern in patterns {
+            if url_matches(ctx, (pattern, path.to_string(), None))? {
+                let max_age: Option<u64> = cache.get("max_age")?;
+                let max_entries: Option<usize> = cache.get("max_entries")?;
+
+                return Ok(Some(Self {
+                    max_age: Duration::from_secs(max_age.unwrap_or(DEFAULT_MAX_AGE)),
+                    max_entries: max_entries.unwrap_or(DEFAULT_MAX_ENTRIES),
+                }));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    expires: Instant,
+    // the generation of the Lua states which rendered it
+    generation: u64,
+}
+
+impl Entry {
+    fn is_fresh(&self, generation: u64, now: Instant) -> bool {
+        self.generation == generation && self.expires > now
+    }
+}
+
+/// An in-memory cache of rendered `GET` responses, keyed by path
+///
+/// Entries rendered before a reload of the Lua states are never served, so
+/// that template changes show up right away.
+#[derive(Default)]
+pub struct ResponseCache {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl ResponseCache {
+    /// Get a fresh cached response for a path
+    pub fn get(&self, path: &str, generation: u64) -> Option<Response<ResponseBody>> {
+        let mut entries = self.entries.lock().unwrap();
+
+        let entry = entries.get(path)?;
+        if !entry.is_fresh(generation, Instant::now()) {
+            entries.remove(path);
+            return None;
+        }
+
+        let mut res = Response::new(full(entry.body.clone()));
+        *res.status_mut() = entry.status;
+        *res.headers_mut() = entry.headers.clone();
+
+        Some(res)
+    }
+
+    /// Cache a response for a path, returning it to be sent
+    ///
+    /// When the cache is full, stale entries are dropped first, then the one
+    /// closest to expiring.
+    pub async fn store(
+        &self,
+        path: &str,
+        generation: u64,
+        policy: CachePolicy,
+        res: Response<ResponseBody>,
+    ) -> Result<Response<ResponseBody>> {
+        let (parts, body) = res.into_parts();
+        let body = body.collect().await.map_err(|e| eyre!(e))?.to_bytes();
+
+        if policy.max_entries == 0 {
+            return Ok(Response::from_parts(parts, full(body)));
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+
+        if entries.len() >= policy.max_entries && !entries.contains_key(path) {
+            entries.retain(|_, entry| entry.is_fresh(generation, now));
+        }
+
+        if entries.len() >= policy.max_entries && !entries.contains_key(path) {
+            let closest_to_expiring = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(path, _)| path.clone());
+
+            if let Some(path) = closest_to_expiring {
+                entries.remove(&path);
+            }
+        }
+
+        entries.insert(
+            path.to_string(),
+            Entry {
+                status: parts.status,
+                headers: parts.headers.clone(),
+                body: body.clone(),
+                expires: now + policy.max_age,
+                generation,
+            },
+        );
+
+        Ok(Response::from_parts(parts, full(body)))
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http_body_util::BodyExt;
+    use hyper::{Response, StatusCode};
+
+    use super::{CachePolicy, ResponseCache};
+    use crate::server::full;
+
+    fn policy(max_age: u64, max_entries: usize) -> CachePolicy {
+        CachePolicy {
+            max_age: Duration::from_secs(max_age),
+            max_entries,
+        }
+    }
+
+    async fn store(cache: &ResponseCache, path: &str, generation: u64, policy: CachePolicy) {
+        let res = Response::builder()
+            .header("Content-Type", "text/plain")
+            .body(full(path.to_string()))
+            .unwrap();
+
+        let res = cache.store(path, generation, policy, res).await.unwrap();
+        assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), path);
+    }
+
+    #[tokio::test]
+    async fn hit() {
+        let cache = ResponseCache::default();
+        store(&cache, "/a", 0, policy(60, 10)).await;
+
+        let res = cache.get("/a", 0).unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "text/plain");
+        assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), "/a");
+
+        assert!(cache.get("/b", 0).is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_or_reloaded() {
+        let cache = ResponseCache::default();
+        store(&cache, "/expired", 0, policy(0, 10)).await;
+        store(&cache, "/reloaded", 0, policy(60, 10)).await;
+
+        assert!(cache.get("/expired", 0).is_none());
+        assert!(cache.get("/reloaded", 1).is_none());
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn bounded() {
+        let cache = ResponseCache::default();
+        store(&cache, "/a", 0, policy(10, 2)).await;
+        store(&cache, "/b", 0, policy(60, 2)).await;
+        store(&cache, "/c", 0, policy(60, 2)).await;
+
+        assert_eq!(cache.len(), 2);
+        // the entry closest to expiring goes first
+        assert!(cache.get("/a", 0).is_none());
+        assert!(cache.get("/b", 0).is_some());
+        assert!(cache.get("/c", 0).is_some());
+
+        store(&cache, "/d", 0, policy(60, 0)).await;
+        assert!(cache.get("/d", 0).is_none());
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::Result;
+use serde::Deserialize;
+
+use crate::error::ReluaxError;
+
+pub const CONFIG_FILE: &str = "reluax.toml";
+
+pub const DEFAULT_HOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+pub const DEFAULT_PORT: u16 = 4310;
+pub const DEFAULT_MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+/// Project-wide defaults for the `serve` and `dev` commands, read from
+/// `reluax.toml` in the project root. Flags passed on the command line take
+/// precedence over these.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: Option<IpAddr>,
+    pub port: Option<u16>,
+    /// Relative to the project root
+    pub public_dir: Option<PathBuf>,
+    pub workers: Option<usize>,
+    pub body_limit: Option<usize>,
+    /// Take the client address from `X-Forwarded-For`
+    pub trust_proxy: Option<bool>,
+    /// In seconds
+    pub route_timeout: Option<u64>,
+    pub max_connections: Option<NonZeroUsize>,
+    /// Keep HTTP/1.1 connections open between requests
+    pub keep_alive: Option<bool>,
+    /// Requests a second from each client IP
+    pub rate_limit: Option<u32>,
+    /// Requests a client can make at once, `rate_limit` by default
+    pub rate_burst: Option<u32>,
+    /// The key values are signed with, unless `RELUAX_SECRET` is set
+    pub secret: Option<String>,
+    /// Run the Lua code of `serve` without `io`, `debug` and most of `os`
+    pub sandbox: Option<bool>,
+    /// In MiB, for each Lua state of `serve`
+    pub memory_limit: Option<usize>,
+    /// Lua instructions a route can run in `serve`
+    pub instruction_limit: Option<u64>,
+}
+
+impl Config {
+    /// Load the config of the project in `project_dir`, which is empty if there
+    /// is no config file
+    pub fn load(project_dir: &Path) -> Result<Self> {
+        let path = project_dir.join(CONFIG_FILE);
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut config: Self =
+            toml::from_str(&contents).map_err(|e| ReluaxError::Config(e.to_string()))?;
+
+        config.public_dir = config.public_dir.map(|dir| project_dir.join(dir));
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_config() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert_eq!(Config::load(dir.path()).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn full_config() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join(CONFIG_FILE),
+            r#"
+                host = "0.0.0.0"
+                port = 8080
+                public_dir = "public"
+                workers = 4
+                body_limit = 1024
+                trust_proxy = true
+                route_timeout = 30
+                max_connections = 64
+                keep_alive = false
+                rate_limit = 10
+                rate_burst = 20
+                secret = "s3cret"
+                sandbox = true
+                memory_limit = 256
+                instruction_limit = 1000000
+            "#,
+        )
+        .unwrap();
+
+        let config = Config::load(dir.path()).unwrap();
+
+        assert_eq!(config.host, Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)));
+        assert_eq!(config.port, Some(8080));
+        assert_eq!(config.public_dir, Some(dir.path().join("public")));
+        assert_eq!(config.workers, Some(4));
+        assert_eq!(config.body_limit, Some(1024));
+        assert_eq!(config.trust_proxy, Some(true));
+        assert_eq!(config.route_timeout, Some(30));
+        assert_eq!(config.max_connections, NonZeroUsize::new(64));
+        assert_eq!(config.keep_alive, Some(false));
+        assert_eq!(config.rate_limit, Some(10));
+        assert_eq!(config.rate_burst, Some(20));
+        assert_eq!(config.secret, Some("s3cret".to_string()));
+        assert_eq!(config.sandbox, Some(true));
+        assert_eq!(config.memory_limit, Some(256));
+        assert_eq!(config.instruction_limit, Some(1_000_000));
+    }
+
+    #[test]
+    fn invalid_config() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(CONFIG_FILE), "prot = 8080").unwrap();
+
+        assert!(Config::load(dir.path()).is_err());
+
+        std::fs::write(dir.path().join(CONFIG_FILE), "max_connections = 0").unwrap();
+
+        assert!(Config::load(dir.path()).is_err());
+    }
+}
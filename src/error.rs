@@ -1,15 +1,34 @@
 use std::fmt::{self, Display, Formatter};
+use std::path::PathBuf;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum LuaXError {
     InvalidStart, // used internally
     NonTableChildren,
     NonTableAttrs,
+    NonStringAttr,
     NeededToken(String),
     ExpectedVar,
     ExpectedExpression,
     UnterminatedStringLiteral,
     NonJsonType,
+    InvalidHtml(String),
+    UnclosedTag {
+        tag: String,
+        line: usize,
+        column: usize,
+    },
+    MismatchedClosingTag {
+        expected: String,
+        found: String,
+        line: usize,
+        column: usize,
+    },
+    NotLuaOperator {
+        found: String,
+        instead: String,
+    },
+    Include(String),
 }
 
 impl std::error::Error for LuaXError {}
@@ -20,20 +39,68 @@ impl Display for LuaXError {
             LuaXError::InvalidStart => write!(f, "Invalid start"),
             LuaXError::NonTableChildren => write!(f, "Children must be tables"),
             LuaXError::NonTableAttrs => write!(f, "Attrs must be tables"),
+            LuaXError::NonStringAttr => write!(f, "Attribute values must be strings"),
             LuaXError::NeededToken(token) => write!(f, "Needed token: {}", token),
             LuaXError::ExpectedVar => write!(f, "Expected variable"),
             LuaXError::ExpectedExpression => write!(f, "Expected expression"),
             LuaXError::UnterminatedStringLiteral => write!(f, "Unterminated string literal"),
             LuaXError::NonJsonType => write!(f, "Type cannot be represented in JSON"),
+            LuaXError::InvalidHtml(problem) => write!(f, "Invalid HTML: {}", problem),
+            LuaXError::UnclosedTag { tag, line, column } => write!(
+                f,
+                "Unclosed tag: <{}> opened at line {}, column {} is never closed",
+                tag, line, column
+            ),
+            LuaXError::MismatchedClosingTag {
+                expected,
+                found,
+                line,
+                column,
+            } => write!(
+                f,
+                "Mismatched closing tag: expected </{}>, found </{}> at line {}, column {}",
+                expected, found, line, column
+            ),
+            LuaXError::NotLuaOperator { found, instead } => write!(
+                f,
+                "`{}` is not a Lua operator, use `{}` instead",
+                found, instead
+            ),
+            LuaXError::Include(problem) => write!(f, "Invalid include: {}", problem),
         }
     }
 }
 
+/// LuaX which failed to preprocess, with the Lua written before the error and
+/// the line and column of the LuaX where it was found
+#[derive(Debug)]
+pub struct PreprocessError {
+    pub error: color_eyre::Report,
+    pub output: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::error::Error for PreprocessError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.error)
+    }
+}
+
+impl Display for PreprocessError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
 #[derive(Debug)]
 pub enum ReluaxError {
     LuaX(LuaXError),
     Lua(rlua::Error),
     Server(String),
+    Validation(usize),
+    Config(String),
+    Preprocess(Vec<(PathBuf, color_eyre::Report)>),
 }
 
 impl std::error::Error for ReluaxError {}
@@ -44,6 +111,15 @@ impl Display for ReluaxError {
             ReluaxError::LuaX(err) => write!(f, "{}", err),
             ReluaxError::Lua(err) => write!(f, "{}", err),
             ReluaxError::Server(err) => write!(f, "{}", err),
+            ReluaxError::Validation(count) => write!(f, "{} HTML validation problems found", count),
+            ReluaxError::Config(err) => write!(f, "Invalid reluax.toml: {}", err),
+            ReluaxError::Preprocess(failures) => {
+                write!(f, "{} LuaX files failed to preprocess:", failures.len())?;
+                for (path, err) in failures {
+                    write!(f, "\n  {}: {}", path.display(), err)?;
+                }
+                Ok(())
+            }
         }
     }
 }
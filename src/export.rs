@@ -0,0 +1,177 @@
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use color_eyre::{eyre::eyre, owo_colors::OwoColorize, Result};
+use http_body_util::BodyExt;
+use hyper::StatusCode;
+
+use crate::error::ReluaxError;
+use crate::server::{self, LuaPool};
+use crate::warn;
+
+/// Render the pages of a project into files in `output_dir`, so that the site
+/// can be hosted statically
+///
+/// The paths to render are taken from the manifest's `paths` table, or the
+/// result of its `sitemap` function. Pages which don't render with a 200 are
+/// skipped with a warning.
+pub async fn export(lua: Arc<LuaPool>, output_dir: &Path) -> Result<usize> {
+    let mut exported = 0;
+
+    for path in export_paths(&lua)? {
+        let file = match output_path(output_dir, &path) {
+            Some(file) => file,
+            None => {
+                warn!("⚠️  Skipping {}: not a page path", path.bright_yellow());
+                continue;
+            }
+        };
+
+        let res = match server::render(lua.clone(), &path) {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("⚠️  Skipping {}: {}", path.bright_yellow(), e.bright_red());
+                continue;
+            }
+        };
+
+        if res.status() != StatusCode::OK {
+            warn!(
+                "⚠️  Skipping {}: route returned {}",
+                path.bright_yellow(),
+                res.status().bright_red()
+            );
+            continue;
+        }
+
+        let body = res
+            .into_body()
+            .collect()
+            .await
+            .map_err(|e| eyre!(e))?
+            .to_bytes();
+
+        if let Some(parent) = file.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&file, body)?;
+
+        exported += 1;
+    }
+
+    Ok(exported)
+}
+
+fn export_paths(lua: &LuaPool) -> Result<Vec<String>> {
+    let lua = lua.get();
+
+    lua.context(|ctx| -> Result<Vec<String>> {
+        let manifest: rlua::Table = ctx.load("require('reluax')").eval()?;
+
+        if let Some(paths) = manifest.get::<_, Option<Vec<String>>>("paths")? {
+            return Ok(paths);
+        }
+
+        if let Some(sitemap) = manifest.get::<_, Option<rlua::Function>>("sitemap")? {
+            return Ok(sitemap.call(())?);
+        }
+
+        Err(ReluaxError::Server(
+            "The manifest needs a `paths` table or a `sitemap` function to export".to_string(),
+        )
+        .into())
+    })
+}
+
+/// Map the path of a page to the file it is exported to, e.g. `/about` to
+/// `about/index.html`. Paths with an extension, like `/feed.xml`, are kept
+/// as they are.
+fn output_path(output_dir: &Path, path: &str) -> Option<PathBuf> {
+    let relative = Path::new(path.trim_start_matches('/'));
+
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    if relative.extension().is_some() {
+        Some(output_dir.join(relative))
+    } else {
+        Some(output_dir.join(relative).join("index.html"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use crate::server::LuaPool;
+
+    #[test]
+    fn output_path() {
+        let cases = vec![
+            ("/", Some("out/index.html")),
+            ("/about", Some("out/about/index.html")),
+            ("/blog/first-post/", Some("out/blog/first-post/index.html")),
+            ("/feed.xml", Some("out/feed.xml")),
+            ("/../secret", None),
+            ("/a/./b", Some("out/a/b/index.html")),
+        ];
+
+        for (path, expected) in cases {
+            assert_eq!(
+                super::output_path(Path::new("out"), path),
+                expected.map(PathBuf::from),
+                "exporting {}",
+                path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn export() {
+        let lua = crate::luax::prepare_lua(false).unwrap();
+        let src = crate::luax::preprocess(
+            r#"
+            package.preload['reluax'] = function()
+                return {
+                    sitemap = function()
+                        return { "/", "/about", "/missing", "/broken" }
+                    end,
+                    route = function(path)
+                        if path == "/" then
+                            return 200, <h1>Home</h1>
+                        elseif path == "/about" then
+                            return 200, <h1>About</h1>
+                        elseif path == "/broken" then
+                            return 500, "Broken"
+                        end
+                        return 404, "Not found"
+                    end,
+                }
+            end
+            "#,
+        )
+        .unwrap();
+        lua.context(|ctx| ctx.load(&src).exec()).unwrap();
+        let lua = Arc::new(LuaPool::new(vec![lua]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let exported = super::export(lua, dir.path()).await.unwrap();
+
+        assert_eq!(exported, 2);
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("index.html")).unwrap(),
+            "<!DOCTYPE html>\n<h1>Home</h1>"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("about/index.html")).unwrap(),
+            "<!DOCTYPE html>\n<h1>About</h1>"
+        );
+        assert!(!dir.path().join("missing").exists());
+        assert!(!dir.path().join("broken").exists());
+    }
+}
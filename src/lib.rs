@@ -0,0 +1,27 @@
+//! Reluax as a library, for embedding the LuaX preprocessor, the HTML and JSON
+//! renderers, and the server in other Rust programs. The `reluax` binary is a
+//! thin command line interface over the same modules.
+
+// the binary compiles these modules as well, and uses parts of them which the
+// library doesn't
+#[allow(dead_code)]
+mod cache;
+#[allow(dead_code)]
+mod error;
+#[allow(dead_code)]
+mod log;
+#[allow(dead_code)]
+mod luax;
+#[allow(dead_code)]
+mod rate_limit;
+#[allow(dead_code)]
+mod server;
+
+pub(crate) use log::{debug, error, info, warn};
+
+pub use error::{LuaXError, PreprocessError, ReluaxError};
+pub use luax::{
+    prepare_lua, preprocess, table_to_html, table_to_json, table_to_markup, table_to_xml, Syntax,
+};
+pub use rate_limit::RateLimit;
+pub use server::{render as render_route, LuaPool, Options as ServerOptions, ResponseBody, Server};
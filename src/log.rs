@@ -0,0 +1,105 @@
+//! Leveled diagnostics, so the command line can be made quieter with `-q` or
+//! more verbose with `--verbose`
+//!
+//! Messages are written with the `error!`, `warn!`, `info!` and `debug!`
+//! macros, which take the same arguments as `println!`. Errors and warnings go
+//! to stderr, the rest to stdout.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How important a message is, from most to least
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn from_u8(level: u8) -> Self {
+        match level {
+            0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Show the messages of `level` and more important ones from now on
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// The least important level of messages being shown, `Info` by default
+pub fn level() -> Level {
+    Level::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= self::level()
+}
+
+#[doc(hidden)]
+pub fn write(level: Level, args: std::fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+
+    match level {
+        Level::Error | Level::Warn => eprintln!("{}", args),
+        Level::Info | Level::Debug => println!("{}", args),
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Error, format_args!($($arg)*))
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Info, format_args!($($arg)*))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {debug, error, info, warn};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels() {
+        let default = level();
+        assert_eq!(default, Level::Info);
+        assert!(enabled(Level::Error));
+        assert!(enabled(Level::Info));
+        assert!(!enabled(Level::Debug));
+
+        set_level(Level::Warn);
+        assert!(enabled(Level::Warn));
+        assert!(!enabled(Level::Info));
+
+        set_level(Level::Debug);
+        assert!(enabled(Level::Debug));
+
+        set_level(default);
+    }
+}
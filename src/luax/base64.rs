@@ -0,0 +1,70 @@
+/// The two common Base64 alphabets, differing only in their last two characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alphabet {
+    /// `+` and `/`, padded with `=`
+    Standard,
+    /// `-` and `_`, safe in URLs and file names, without padding
+    UrlSafe,
+}
+
+impl Alphabet {
+    fn chars(self) -> &'static [u8; 64] {
+        match self {
+            Alphabet::Standard => {
+                b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/"
+            }
+            Alphabet::UrlSafe => {
+                b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_"
+            }
+        }
+    }
+
+    fn sextet(self, c: u8) -> Option<u32> {
+        self.chars().iter().position(|&a| a == c).map(|i| i as u32)
+    }
+}
+
+/// Encode bytes as Base64
+pub fn encode(bytes: &[u8], alphabet: Alphabet) -> String {
+    let chars = alphabet.chars();
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0, |n, (i, &b)| n | ((b as u32) << (16 - 8 * i)));
+
+        for i in 0..chunk.len() + 1 {
+            out.push(chars[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+        }
+        if alphabet == Alphabet::Standard {
+            for _ in chunk.len()..3 {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+/// Decode Base64, with or without its `=` padding
+pub fn decode(s: &str, alphabet: Alphabet) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=').as_bytes();
+    if s.len() % 4 == 1 {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    for chunk in s.chunks(4) {
+        let mut n = 0;
+        for (i, &c) in chunk.iter().enumerate() {
+            n |= alphabet.sextet(c)? << (18 - 6 * i);
+        }
+
+        let bytes = [(n >> 16) as u8, (n >> 8) as u8, n as u8];
+        out.extend_from_slice(&bytes[..chunk.len() - 1]);
+    }
+
+    Some(out)
+}
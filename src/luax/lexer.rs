@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 use crate::error::LuaXError;
 use color_eyre::Result;
 
@@ -35,9 +37,17 @@ pub struct Lexer<'s> {
     current: Option<char>,
     // Positioning
     current_pos_in_bytes: usize,
+    token_start: usize,
+    line: usize,
+    token_line: usize,
     // EOF
     emitted_eof: bool,
     html_text_mode: usize,
+    // skipped before the last token, which matters in HTML text
+    whitespace_before: Cow<'s, str>,
+    // Lua code block delimiters
+    lua_start: &'s str,
+    lua_end: &'s str,
 }
 
 #[derive(Debug)]
@@ -50,22 +60,79 @@ enum TokenizeResult<'s> {
     Error(LuaXError),
 }
 
+pub const DEFAULT_LUA_START: &str = "{$";
+pub const DEFAULT_LUA_END: &str = "$}";
+
+/// Read the Lua code block delimiters from a pragma on the first line of a file,
+/// in the form of `--! delimiters <% %>`. Being a comment, the pragma is otherwise
+/// ignored by the lexer.
+fn pragma_delimiters(src: &str) -> Option<(&str, &str)> {
+    let line = src.lines().next()?.trim();
+    let mut words = line.strip_prefix("--!")?.split_whitespace();
+
+    if words.next()? != "delimiters" {
+        return None;
+    }
+
+    let start = words.next()?;
+    let end = words.next()?;
+
+    if words.next().is_some() {
+        return None;
+    }
+
+    Some((start, end))
+}
+
 impl<'s> Lexer<'s> {
     pub fn new(src: &'s str) -> Self {
+        let (lua_start, lua_end) =
+            pragma_delimiters(src).unwrap_or((DEFAULT_LUA_START, DEFAULT_LUA_END));
+
+        Self::with_delimiters(src, lua_start, lua_end)
+    }
+
+    pub fn with_delimiters(src: &'s str, lua_start: &'s str, lua_end: &'s str) -> Self {
         let mut chars = src.chars();
         let current = chars.next();
-        Self {
+        let mut lexer = Self {
             src,
             chars,
             current,
             current_pos_in_bytes: 0,
+            token_start: 0,
+            line: 1,
+            token_line: 1,
             emitted_eof: false,
             html_text_mode: 0,
+            whitespace_before: Cow::Borrowed(""),
+            lua_start,
+            lua_end,
+        };
+
+        // like Lua, skip a shebang line, so scripts can be made executable
+        if src.starts_with("#!") {
+            while lexer.current.map_or(false, |c| c != '\n') {
+                lexer.advance();
+            }
         }
+
+        lexer
     }
 
     pub fn next_token(&mut self) -> Result<Option<Token<'s>>> {
+        self.whitespace_before = Cow::Borrowed("");
+
         if self.html_text_mode > 0 {
+            while self.template_comment(true) {}
+
+            self.token_start = self.current_pos_in_bytes;
+            self.token_line = self.line;
+
+            if self.match_str(self.lua_start) {
+                return Ok(Some(self.lua_start_token()));
+            }
+
             let c = self.current;
             self.advance();
 
@@ -77,14 +144,7 @@ impl<'s> Lexer<'s> {
                         Ok(Some(Token::Lt))
                     }
                 }
-                Some(' ' | '\t' | '\n') => Ok(Some(Token::Whitespace)),
-                Some('{') => {
-                    if self.match_char('$') {
-                        Ok(Some(Token::LuaStart))
-                    } else {
-                        Ok(Some(Token::HtmlTextChar('{')))
-                    }
-                }
+                Some(c @ (' ' | '\t' | '\n')) => Ok(Some(Token::Whitespace(c))),
                 Some(c) => Ok(Some(Token::HtmlTextChar(c))),
                 None => {
                     if self.emitted_eof {
@@ -116,6 +176,28 @@ impl<'s> Lexer<'s> {
         }
     }
 
+    /// The whitespace skipped before the last token, outside of HTML text mode,
+    /// without the template comments in it
+    pub fn whitespace_before(&self) -> &str {
+        &self.whitespace_before
+    }
+
+    /// The line where the last token starts, counted from 1
+    pub fn token_line(&self) -> usize {
+        self.token_line
+    }
+
+    /// The line and column where the last token starts, both counted from 1
+    pub fn token_position(&self) -> (usize, usize) {
+        let before = &self.src[..self.token_start];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+
+        (
+            before.matches('\n').count() + 1,
+            before[line_start..].chars().count() + 1,
+        )
+    }
+
     pub fn enable_html_text_mode(&mut self) {
         self.html_text_mode += 1;
     }
@@ -128,24 +210,102 @@ impl<'s> Lexer<'s> {
         self.skip_whitespace();
 
         try_all_paths!(
+            self.lua_delimiter(),
             self.single_char_token(),
             self.double_char_token(),
+            self.number(),
             self.triple_char_token(),
             self.string(),
-            self.number(),
             self.comment(),
             self.identifier_or_keyword(),
         )
     }
 
     fn skip_whitespace(&mut self) {
-        while let Some(c) = self.current {
-            match c {
-                ' ' | '\t' | '\n' => {
-                    self.advance();
+        let mut start = self.current_pos_in_bytes;
+        // the whitespace before template comments, which are cut out of it
+        let mut around_comments: Option<String> = None;
+
+        loop {
+            while let Some(c) = self.current {
+                match c {
+                    ' ' | '\t' | '\n' => {
+                        self.advance();
+                    }
+                    _ => break,
                 }
-                _ => break,
             }
+
+            let comment_start = self.current_pos_in_bytes;
+            if !self.template_comment(false) {
+                break;
+            }
+
+            around_comments
+                .get_or_insert_with(String::new)
+                .push_str(&self.src[start..comment_start]);
+            start = self.current_pos_in_bytes;
+        }
+
+        let whitespace = &self.src[start..self.current_pos_in_bytes];
+        self.whitespace_before = match around_comments {
+            Some(mut before) => {
+                before.push_str(whitespace);
+                Cow::Owned(before)
+            }
+            None => Cow::Borrowed(whitespace),
+        };
+        self.token_start = self.current_pos_in_bytes;
+        self.token_line = self.line;
+    }
+
+    /// Skip a `{# ... #}` template comment if one starts here, returning
+    /// whether it did
+    ///
+    /// Outside of HTML text, `{#` also starts a table whose first field is a
+    /// length, like `{#t}`, so there the comment has to start with whitespace,
+    /// e.g. `{# note #}`. Without a closing `#}`, nothing is a comment.
+    fn template_comment(&mut self, in_text: bool) -> bool {
+        let rest = &self.src[self.current_pos_in_bytes..];
+        let body = match rest.strip_prefix("{#") {
+            Some(body) => body,
+            None => return false,
+        };
+
+        if !in_text && !body.starts_with([' ', '\t', '\n', '\r']) && !body.starts_with("#}") {
+            return false;
+        }
+
+        let end = match body.find("#}") {
+            Some(end) => end,
+            None => return false,
+        };
+
+        let comment = &rest[.."{#".len() + end + "#}".len()];
+        for _ in comment.chars() {
+            self.advance();
+        }
+
+        true
+    }
+
+    fn lua_delimiter(&mut self) -> TokenizeResult<'s> {
+        if self.match_str(self.lua_start) {
+            TokenizeResult::Some(self.lua_start_token())
+        } else if self.match_str(self.lua_end) {
+            TokenizeResult::Some(Token::LuaEnd)
+        } else {
+            TokenizeResult::None
+        }
+    }
+
+    /// The token for a Lua block start which was just matched, raw if it is
+    /// directly followed by `=`
+    fn lua_start_token(&mut self) -> Token<'s> {
+        if self.match_char('=') {
+            Token::LuaStartRaw
+        } else {
+            Token::LuaStart
         }
     }
 
@@ -187,6 +347,7 @@ impl<'s> Lexer<'s> {
             self.single_char_token_case('#', Token::Hash),
             self.single_char_token_case('(', Token::OpenParen),
             self.single_char_token_case(')', Token::CloseParen),
+            self.single_char_token_case('{', Token::OpenBrace),
             self.single_char_token_case('}', Token::CloseBrace),
             self.single_char_token_case(']', Token::CloseBracket),
             self.single_char_token_case(';', Token::Semicolon),
@@ -252,8 +413,6 @@ impl<'s> Lexer<'s> {
                 Some(Token::Gt),
                 [Token::Ge, Token::GtGt]
             ),
-            self.double_char_token_case('{', '$', Some(Token::OpenBrace), Token::LuaStart),
-            self.double_char_token_case('$', '}', None, Token::LuaEnd),
             self.double_char_token_case('=', '=', Some(Token::Eq), Token::EqEq),
             self.double_char_token_case('~', '=', Some(Token::Tilde), Token::TildeEq),
             self.double_char_token_case('/', '/', Some(Token::Slash), Token::SlashSlash),
@@ -297,35 +456,54 @@ impl<'s> Lexer<'s> {
     }
 
     fn number(&mut self) -> TokenizeResult<'s> {
-        if !self.current.map_or(false, |c| c.is_numeric()) {
+        // a float can start with its decimal point, like `.5`
+        let leading_dot = self.current == Some('.')
+            && self.src[self.current_pos_in_bytes + 1..].starts_with(|c: char| c.is_ascii_digit());
+
+        if !leading_dot && !self.current.map_or(false, |c| c.is_numeric()) {
             return TokenizeResult::None;
         }
 
         let start = self.current_pos_in_bytes;
 
-        while self.current.map_or(false, |c| c.is_numeric()) {
+        let rest = &self.src[start..];
+        let hex = rest.starts_with("0x") || rest.starts_with("0X");
+        if hex {
+            self.advance();
             self.advance();
         }
 
+        self.digits(hex);
+
         if self.match_char('.') {
-            while self.current.map_or(false, |c| c.is_numeric()) {
-                self.advance();
-            }
+            self.digits(hex);
         }
 
-        if self.match_char('e') || self.match_char('E') {
-            if self.match_char('-') || self.match_char('+') {
-                self.advance();
-            }
-            while self.current.map_or(false, |c| c.is_numeric()) {
-                self.advance();
+        let exponent = if hex { ['p', 'P'] } else { ['e', 'E'] };
+        if self.current.map_or(false, |c| exponent.contains(&c)) {
+            self.advance();
+            if !self.match_char('-') {
+                self.match_char('+');
             }
+            self.digits(false);
         }
         let end = self.current_pos_in_bytes;
 
         TokenizeResult::Some(Token::Number(&self.src[start..end]))
     }
 
+    fn digits(&mut self, hex: bool) {
+        while self.current.map_or(false, |c| {
+            if hex {
+                c.is_ascii_hexdigit()
+            } else {
+                c.is_numeric()
+            }
+        }) {
+            self.advance();
+        }
+    }
+
     fn string(&mut self) -> TokenizeResult<'s> {
         let ty = if self.match_char('"') {
             StringType::Double
@@ -345,32 +523,18 @@ impl<'s> Lexer<'s> {
         let mut finished = false;
 
         match ty {
-            StringType::Single => {
-                let mut escaped = false;
-                while self.current.is_some() {
-                    if self.match_char('\\') {
-                        escaped = true;
-                    } else if self.match_char('\'') && !escaped {
-                        finished = true;
-                        break;
-                    } else {
-                        escaped = false;
-                    }
-                    self.advance();
-                }
-            }
-            StringType::Double => {
-                let mut escaped = false;
+            StringType::Single | StringType::Double => {
+                let quote = if ty == StringType::Single { '\'' } else { '"' };
                 while self.current.is_some() {
                     if self.match_char('\\') {
-                        escaped = true;
-                    } else if self.match_char('"') && !escaped {
+                        // skip the escaped character, which may be a quote
+                        self.advance();
+                    } else if self.match_char(quote) {
                         finished = true;
                         break;
                     } else {
-                        escaped = false;
+                        self.advance();
                     }
-                    self.advance();
                 }
             }
             StringType::DoubleBracket => {
@@ -445,12 +609,27 @@ impl<'s> Lexer<'s> {
     }
 
     fn advance(&mut self) -> Option<char> {
+        if self.current == Some('\n') {
+            self.line += 1;
+        }
         self.current_pos_in_bytes += self.current.map_or(0, |c| c.len_utf8());
         self.current = self.chars.next();
 
         self.current
     }
 
+    fn match_str(&mut self, s: &str) -> bool {
+        if s.is_empty() || !self.src[self.current_pos_in_bytes..].starts_with(s) {
+            return false;
+        }
+
+        for _ in s.chars() {
+            self.advance();
+        }
+
+        true
+    }
+
     fn match_char(&mut self, c: char) -> bool {
         if self.current == Some(c) {
             self.advance();
@@ -461,11 +640,13 @@ impl<'s> Lexer<'s> {
     }
 
     fn is_valid_in_identifier(c: char) -> bool {
-        Self::is_valid_identifier_start(c) || c.is_numeric()
+        unicode_ident::is_xid_continue(c)
     }
 
+    /// Identifiers follow Unicode's `XID_Start` and `XID_Continue` properties,
+    /// like Rust's, with `_` allowed anywhere. This accepts names like `café` or
+    /// `名前`, but not ones starting with a digit, or containing emoji.
     fn is_valid_identifier_start(c: char) -> bool {
-        c.is_alphabetic() || c == '_' // TODO: Support some more unicode characters
-                                      //       like emojis (or even emoji modifier sequences?)
+        unicode_ident::is_xid_start(c) || c == '_'
     }
 }
@@ -1,24 +1,79 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
-use rlua::Lua;
+use rayon::prelude::*;
+use rlua::{Lua, StdLib};
 
-use crate::{error::LuaXError, Result};
+use crate::{
+    debug,
+    error::{LuaXError, PreprocessError, ReluaxError},
+    Result,
+};
 
+mod base64;
 mod lexer;
 mod preprocessor;
+mod signing;
 #[cfg(test)]
 mod tests;
 mod tokens;
 
+pub use preprocessor::SourceMap;
+pub use signing::set_default_secret;
+
+/// How elements are closed when rendering a table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// Every element gets a closing tag
+    Html,
+    /// Elements without children are self-closing, e.g. `<br/>`, as XHTML and
+    /// SVG need
+    Xml,
+}
+
 pub fn table_to_html<W: std::io::Write>(table: rlua::Table, f: &mut W) -> Result<()> {
-    let tag_name: Option<String> = table.get("tag").unwrap();
+    table_to_markup(table, f, Syntax::Html)
+}
+
+pub fn table_to_markup<W: std::io::Write>(
+    table: rlua::Table,
+    f: &mut W,
+    syntax: Syntax,
+) -> Result<()> {
+    write_markup(table, f, syntax, false)
+}
+
+/// Render a table as markup, escaping text and attribute values if `escape` is
+/// set, as XML documents which aren't pages need
+fn write_markup<W: std::io::Write>(
+    table: rlua::Table,
+    f: &mut W,
+    syntax: Syntax,
+    escape: bool,
+) -> Result<()> {
+    let text = |s: rlua::String| -> Result<String> {
+        let s = s.to_str()?;
+        Ok(if escape {
+            escape_html(s)
+        } else {
+            s.to_string()
+        })
+    };
+
+    // trusted HTML, e.g. rendered Markdown, is written out as it is
+    if let Some(raw) = table.get::<_, Option<rlua::String>>("raw")? {
+        write!(f, "{}", raw.to_str()?)?;
+        return Ok(());
+    }
+
+    let tag_name: Option<String> = table.get("tag")?;
 
     if tag_name.is_none() {
         // we might be in a list
         for child in table.sequence_values::<rlua::Value>() {
             match child? {
-                rlua::Value::Table(child) => table_to_html(child, f)?,
-                rlua::Value::String(s) => write!(f, "{}", s.to_str()?)?,
+                rlua::Value::Table(child) => write_markup(child, f, syntax, escape)?,
+                rlua::Value::String(s) => write!(f, "{}", text(s)?)?,
                 _ => return Err(LuaXError::NonTableChildren.into()),
             }
         }
@@ -47,23 +102,50 @@ pub fn table_to_html<W: std::io::Write>(table: rlua::Table, f: &mut W) -> Result
     }
     if let Some(attrs) = attrs {
         if let rlua::Value::Table(attrs) = attrs {
-            for pair in attrs.pairs::<String, rlua::String>() {
-                let (key, value) = pair?;
-                write!(f, " {}=\"{}\"", key, value.to_str()?)?;
+            for (key, value) in sorted_attrs(attrs)? {
+                match value {
+                    // optional attributes are left out, and `true` is a boolean
+                    // attribute, e.g. `disabled`
+                    rlua::Value::Nil | rlua::Value::Boolean(false) => {}
+                    rlua::Value::Boolean(true) if escape => write!(f, " {}=\"true\"", key)?,
+                    rlua::Value::Boolean(true) => write!(f, " {}", key)?,
+                    rlua::Value::String(s) => write!(f, " {}=\"{}\"", key, text(s)?)?,
+                    rlua::Value::Integer(n) => write!(f, " {}=\"{}\"", key, n)?,
+                    rlua::Value::Number(n) => write!(f, " {}=\"{}\"", key, n)?,
+                    rlua::Value::Table(t) if key == "class" => {
+                        write!(f, " class=\"{}\"", class_list(t)?)?
+                    }
+                    rlua::Value::Table(t) if key == "style" => {
+                        write!(f, " style=\"{}\"", style_declarations(t)?)?
+                    }
+                    _ => return Err(LuaXError::NonStringAttr.into()),
+                }
             }
         } else {
             return Err(LuaXError::NonTableAttrs.into());
         }
     }
+
+    if syntax == Syntax::Xml {
+        let empty = match &children {
+            Some(rlua::Value::Table(children)) => children.raw_len() == 0,
+            _ => children.is_none(),
+        };
+        if empty {
+            write!(f, "/>")?;
+            return Ok(());
+        }
+    }
     write!(f, ">")?;
 
     if let Some(children) = children {
         if let rlua::Value::Table(children) = children {
             for child in children.sequence_values::<rlua::Value>() {
                 match child? {
-                    rlua::Value::Table(child) => table_to_html(child, f)?,
-                    rlua::Value::String(s) => write!(f, "{}", s.to_str()?)?,
+                    rlua::Value::Table(child) => write_markup(child, f, syntax, escape)?,
+                    rlua::Value::String(s) => write!(f, "{}", text(s)?)?,
                     rlua::Value::Boolean(b) => write!(f, "{}", b)?,
+                    rlua::Value::Integer(n) => write!(f, "{}", n)?,
                     rlua::Value::Number(n) => write!(f, "{}", n)?,
                     _ => return Err(LuaXError::NonTableChildren.into()),
                 }
@@ -78,24 +160,154 @@ pub fn table_to_html<W: std::io::Write>(table: rlua::Table, f: &mut W) -> Result
     Ok(())
 }
 
+/// Join the classes given as a table, either a list like
+/// `{"btn", active and "btn-active"}`, or a map like `{btn=true, active=false}`
+/// whose keys are used if their value is truthy
+///
+/// `nil` and `false` entries are skipped, and the keys of a map are sorted, so
+/// that the output doesn't depend on the table's iteration order.
+fn class_list(table: rlua::Table) -> Result<String> {
+    let mut listed = Vec::new();
+    let mut keyed = Vec::new();
+
+    for pair in table.pairs::<rlua::Value, rlua::Value>() {
+        match pair? {
+            (_, rlua::Value::Nil | rlua::Value::Boolean(false)) => {}
+            (rlua::Value::Integer(i), rlua::Value::String(class)) => {
+                listed.push((i, class.to_str()?.to_string()))
+            }
+            (rlua::Value::String(class), _) => keyed.push(class.to_str()?.to_string()),
+            _ => return Err(LuaXError::NonStringAttr.into()),
+        }
+    }
+
+    listed.sort();
+    keyed.sort();
+
+    Ok(listed
+        .into_iter()
+        .map(|(_, class)| class)
+        .chain(keyed)
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+/// The attributes of an element sorted by name, so the same tree always renders the same way
+fn sorted_attrs(attrs: rlua::Table) -> Result<Vec<(String, rlua::Value)>> {
+    let mut sorted = attrs
+        .pairs::<String, rlua::Value>()
+        .collect::<rlua::Result<Vec<_>>>()?;
+    sorted.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    Ok(sorted)
+}
+
+/// Serialize a table of CSS properties, like `{color="red", ["font-size"]="12px"}`,
+/// into `color:red;font-size:12px`
+///
+/// Properties are sorted by name, so that the output is stable.
+fn style_declarations(table: rlua::Table) -> Result<String> {
+    let mut declarations = Vec::new();
+
+    for pair in table.pairs::<String, rlua::Value>() {
+        let (property, value) = pair?;
+        let value = match value {
+            rlua::Value::Nil | rlua::Value::Boolean(false) => continue,
+            rlua::Value::String(s) => s.to_str()?.to_string(),
+            rlua::Value::Integer(n) => n.to_string(),
+            rlua::Value::Number(n) => n.to_string(),
+            _ => return Err(LuaXError::NonStringAttr.into()),
+        };
+        declarations.push((property, value));
+    }
+
+    declarations.sort();
+
+    Ok(declarations
+        .into_iter()
+        .map(|(property, value)| format!("{}:{}", property, value))
+        .collect::<Vec<_>>()
+        .join(";"))
+}
+
+/// Escape the characters reserved in text and double-quoted attribute values
+pub(crate) fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Render a table as XML, e.g. for RSS feeds and sitemaps
+///
+/// Unlike HTML, text and attribute values are escaped, `true` attributes are
+/// written as `key="true"`, and elements without children are self-closing.
+pub fn table_to_xml<W: std::io::Write>(table: rlua::Table, f: &mut W) -> Result<()> {
+    write_markup(table, f, Syntax::Xml, true)
+}
+
+/// Whether a table is a sequence, with keys exactly `1..=n`, to be written as a
+/// JSON array
+fn is_sequence(table: &rlua::Table) -> Result<bool> {
+    let len = table.raw_len();
+    if len == 0 {
+        return Ok(false);
+    }
+
+    let mut count = 0;
+    for pair in table.clone().pairs::<rlua::Value, rlua::Value>() {
+        match pair?.0 {
+            rlua::Value::Integer(i) if (1..=len).contains(&i) => count += 1,
+            _ => return Ok(false),
+        }
+    }
+
+    Ok(count == len)
+}
+
+fn value_to_json<W: std::io::Write>(value: rlua::Value, f: &mut W) -> Result<()> {
+    match value {
+        rlua::Value::Table(t) => table_to_json(t, f)?,
+        rlua::Value::String(s) => write!(f, "\"{}\"", s.to_str()?)?,
+        rlua::Value::Boolean(b) => write!(f, "{}", b)?,
+        rlua::Value::Integer(n) => write!(f, "{}", n)?,
+        rlua::Value::Number(n) => write!(f, "{}", n)?,
+        rlua::Value::Nil => write!(f, "null")?,
+        _ => return Err(LuaXError::NonJsonType.into()),
+    }
+
+    Ok(())
+}
+
 pub fn table_to_json<W: std::io::Write>(table: rlua::Table, f: &mut W) -> Result<()> {
+    if is_sequence(&table)? {
+        write!(f, "[")?;
+        for (i, value) in table.sequence_values::<rlua::Value>().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            value_to_json(value?, f)?;
+        }
+        write!(f, "]")?;
+
+        return Ok(());
+    }
+
+    // keys are sorted so the same table is always written the same way
+    let mut pairs = table
+        .pairs::<String, rlua::Value>()
+        .collect::<rlua::Result<Vec<_>>>()?;
+    pairs.sort_by(|(a, _), (b, _)| a.cmp(b));
+
     let mut first = true;
     write!(f, "{{")?;
-    for pair in table.pairs::<String, rlua::Value>() {
-        let (key, value) = pair?;
+    for (key, value) in pairs {
         if !first {
             write!(f, ",")?;
         }
         first = false;
         write!(f, "\"{}\":", key)?;
-        match value {
-            rlua::Value::Table(t) => table_to_json(t, f)?,
-            rlua::Value::String(s) => write!(f, "\"{}\"", s.to_str()?)?,
-            rlua::Value::Boolean(b) => write!(f, "{}", b)?,
-            rlua::Value::Number(n) => write!(f, "{}", n)?,
-            rlua::Value::Nil => write!(f, "null")?,
-            _ => return Err(LuaXError::NonJsonType.into()),
-        }
+        value_to_json(value, f)?;
     }
     write!(f, "}}")?;
 
@@ -103,24 +315,304 @@ pub fn table_to_json<W: std::io::Write>(table: rlua::Table, f: &mut W) -> Result
 }
 
 pub fn preprocess(s: &str) -> Result<String> {
+    Ok(preprocess_with_source_map(s)?.0)
+}
+
+/// Preprocess LuaX into Lua, along with a map from the lines of the Lua back to
+/// the lines of the LuaX
+pub fn preprocess_with_source_map(s: &str) -> Result<(String, SourceMap)> {
+    preprocess_at(s, None)
+}
+
+/// Preprocess LuaX read from a file, so that its `<include>`s are found next
+/// to it rather than in the current directory
+pub fn preprocess_at(s: &str, path: Option<&Path>) -> Result<(String, SourceMap)> {
     let mut buf = Vec::new();
-    let preprocessor = preprocessor::Preprocessor::new(s, &mut buf)?;
+    let preprocessor = preprocessor::Preprocessor::new(s, path, &mut buf)?;
 
-    match preprocessor.preprocess() {
-        Ok(_) => {}
-        Err(e) => {
-            println!("got up to: {}", String::from_utf8_lossy(&buf));
+    let source_map = match preprocessor.preprocess_mapped() {
+        Ok((_, source_map)) => source_map,
+        Err(mut e) => {
+            // the Lua written so far, for callers to show where it went wrong
+            if let Some(e) = e.downcast_mut::<PreprocessError>() {
+                e.output = String::from_utf8_lossy(&buf).into_owned();
+            }
             return Err(e);
         }
-    }
+    };
 
     let s = String::from_utf8(buf).unwrap();
 
-    Ok(s)
+    Ok((s, source_map))
+}
+
+/// Rewrite the `file.lua:LINE:` locations in a Lua error message or traceback
+/// to point into `file.luax`, for the Lua files preprocessed from LuaX ones
+/// next to them
+///
+/// The LuaX files are preprocessed again to get their source maps, which is
+/// fine for showing errors in dev mode, but not much else.
+pub fn map_lua_error_lines(text: &str) -> String {
+    let mut source_maps: HashMap<PathBuf, Option<SourceMap>> = HashMap::new();
+    let mut mapped = String::new();
+    let mut rest = text;
+
+    while let Some(i) = rest.find(".lua:") {
+        let (before, after) = rest.split_at(i + ".lua:".len());
+        let path_start = before[..i]
+            .rfind(|c: char| c.is_ascii_whitespace() || c == '"' || c == '\'')
+            .map_or(0, |j| j + 1);
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+
+        let luax_path = PathBuf::from(format!("{}.luax", &before[path_start..i]));
+        let input_line = after[..digits].parse().ok().and_then(|line| {
+            source_maps
+                .entry(luax_path.clone())
+                .or_insert_with(|| {
+                    let source = std::fs::read_to_string(&luax_path).ok()?;
+                    Some(preprocess_at(&source, Some(&luax_path)).ok()?.1)
+                })
+                .as_ref()?
+                .input_line(line)
+        });
+
+        match input_line {
+            Some(line) => {
+                mapped.push_str(&before[..path_start]);
+                mapped.push_str(&format!("{}:{}", luax_path.display(), line));
+                rest = &after[digits..];
+            }
+            None => {
+                mapped.push_str(before);
+                rest = after;
+            }
+        }
+    }
+
+    mapped.push_str(rest);
+
+    mapped
+}
+
+/// Run the preprocessor only to find HTML problems which are statically
+/// determinable, like void elements with children or duplicate ids in a page
+pub fn validate(s: &str) -> Result<Vec<LuaXError>> {
+    validate_at(s, None)
+}
+
+/// Validate LuaX read from a file, finding its `<include>`s next to it
+pub fn validate_at(s: &str, path: Option<&Path>) -> Result<Vec<LuaXError>> {
+    let preprocessor = preprocessor::Preprocessor::new(s, path, std::io::sink())?;
+
+    preprocessor.preprocess()
+}
+
+/// Validate all LuaX files in a directory, returning the problems prefixed
+/// with the file they were found in
+pub fn validate_dir(path: &Path) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.is_dir() {
+            problems.extend(validate_dir(&path)?);
+        } else {
+            if path.extension().unwrap_or_default() != "luax" {
+                continue;
+            }
+            let s = std::fs::read_to_string(&path)?;
+            for problem in validate_at(&s, Some(&path))? {
+                problems.push(format!("{}: {}", path.display(), problem));
+            }
+        }
+    }
+
+    Ok(problems)
 }
 
+/// How many LuaX files were preprocessed into a directory, and how many were
+/// skipped because their output was up to date
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Preprocessed {
+    pub processed: usize,
+    pub skipped: usize,
+    /// Outputs of LuaX files which don't exist anymore that were removed
+    pub pruned: usize,
+}
+
+/// The list of Lua files generated in an output directory, relative to it
+const OUTPUTS_FILE: &str = ".reluax-outputs";
+
+/// Preprocess all LuaX files in a directory into Lua files in the output
+/// directory, keeping the directory structure, and return how many there were
+///
+/// A file failing to preprocess doesn't stop the others, the failures are all
+/// reported together in a `ReluaxError::Preprocess` at the end.
 pub fn preprocess_dir(path: &Path, output_path: &Path) -> Result<usize> {
-    let mut preprocessed = 0;
+    let mut files = Vec::new();
+    collect_luax_files(path, output_path, &mut files)?;
+
+    preprocess_files(&files)?;
+
+    Ok(files.len())
+}
+
+/// Preprocess the LuaX files in a directory like `preprocess_dir`, but unless
+/// `force` is set, skip the ones whose output isn't older than them
+///
+/// Files with an `<include>` are always preprocessed, since what they include
+/// might have changed. With `prune`, the Lua files generated by an earlier run
+/// whose LuaX file was since renamed or deleted are removed; other Lua files
+/// in the output directory are left alone.
+pub fn preprocess_dir_incremental(
+    path: &Path,
+    output_path: &Path,
+    force: bool,
+    prune: bool,
+) -> Result<Preprocessed> {
+    // the output directories are created up front, so that the files can be
+    // preprocessed in parallel
+    let mut files = Vec::new();
+    collect_luax_files(path, output_path, &mut files)?;
+
+    let pruned = record_outputs(output_path, &files, prune)?;
+
+    let found = files.len();
+    if !force {
+        files.retain(|(path, out_path)| !is_up_to_date(path, out_path));
+    }
+
+    preprocess_files(&files)?;
+
+    Ok(Preprocessed {
+        processed: files.len(),
+        skipped: found - files.len(),
+        pruned,
+    })
+}
+
+/// Preprocess LuaX files in parallel, reporting all the failures together
+fn preprocess_files(files: &[(PathBuf, PathBuf)]) -> Result<()> {
+    let failures: Vec<(PathBuf, color_eyre::Report)> = files
+        .par_iter()
+        .filter_map(|(path, out_path)| {
+            preprocess_file(path, out_path)
+                .err()
+                .map(|e| (path.clone(), e))
+        })
+        .collect();
+
+    if !failures.is_empty() {
+        for (path, e) in failures.iter() {
+            if let Some(e) = e.downcast_ref::<PreprocessError>() {
+                debug!(
+                    "{} failed at line {}, column {}, after preprocessing into:\n{}",
+                    path.display(),
+                    e.line,
+                    e.column,
+                    e.output
+                );
+            }
+        }
+
+        return Err(ReluaxError::Preprocess(failures).into());
+    }
+
+    Ok(())
+}
+
+/// Update the list of generated files in the output directory, removing the
+/// ones that aren't generated anymore if `prune` is set, and return how many
+/// were removed
+fn record_outputs(output_path: &Path, files: &[(PathBuf, PathBuf)], prune: bool) -> Result<usize> {
+    let list_path = output_path.join(OUTPUTS_FILE);
+    let previous = match std::fs::read_to_string(&list_path) {
+        Ok(list) => list,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut outputs: Vec<String> = files
+        .iter()
+        .filter_map(|(_, out_path)| out_path.strip_prefix(output_path).ok())
+        .map(|relative| {
+            relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+        })
+        .collect();
+
+    let mut pruned = 0;
+    for stale in previous.lines().filter(|line| !line.is_empty()) {
+        if outputs.iter().any(|output| output == stale) {
+            continue;
+        }
+
+        // never reach out of the output directory, whatever the list says
+        let relative = Path::new(stale);
+        if relative.extension().unwrap_or_default() != "lua"
+            || !relative
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_)))
+        {
+            continue;
+        }
+
+        let stale_path = output_path.join(stale);
+        if prune {
+            match std::fs::remove_file(&stale_path) {
+                Ok(()) => pruned += 1,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        } else if stale_path.is_file() {
+            // kept track of, so that a later run can still prune it
+            outputs.push(stale.to_string());
+        }
+    }
+
+    outputs.sort();
+    let mut list = outputs.join("\n");
+    list.push('\n');
+    std::fs::write(list_path, list)?;
+
+    Ok(pruned)
+}
+
+/// Whether the output of a LuaX file was written after the file was last
+/// changed, and doesn't depend on any other file
+///
+/// Equal times count as changed, since on file systems with coarse timestamps
+/// the file may have been written again in the same tick as its output
+fn is_up_to_date(path: &Path, out_path: &Path) -> bool {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified());
+
+    match (modified(path), modified(out_path)) {
+        (Ok(source), Ok(output)) if output > source => {
+            std::fs::read_to_string(path).map_or(false, |source| !source.contains("<include"))
+        }
+        _ => false,
+    }
+}
+
+fn preprocess_file(path: &Path, out_path: &Path) -> Result<()> {
+    let s = std::fs::read_to_string(path)?;
+    let (s, _) = preprocess_at(&s, Some(path))?;
+
+    std::fs::write(out_path, s)?;
+
+    Ok(())
+}
+
+/// Find the LuaX files in a directory along with the paths of their outputs,
+/// creating the output directories on the way
+fn collect_luax_files(
+    path: &Path,
+    output_path: &Path,
+    files: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<()> {
     for entry in std::fs::read_dir(path)? {
         let entry = entry?;
         let path = entry.path();
@@ -136,46 +628,157 @@ pub fn preprocess_dir(path: &Path, output_path: &Path) -> Result<usize> {
             if !output_dir.exists() {
                 std::fs::create_dir(&output_dir)?;
             }
-            preprocessed += preprocess_dir(&path, &output_path.join(path.file_name().unwrap()))?;
+            collect_luax_files(&path, &output_dir, files)?;
         } else {
             if path.extension().unwrap_or_default() != "luax" {
                 continue;
             }
-            let s = std::fs::read_to_string(&path)?;
-            let s = preprocess(&s)?;
 
             let out_path = output_path
                 .join(path.file_name().unwrap())
                 .with_extension("lua");
 
-            std::fs::write(out_path, s)?;
-            preprocessed += 1;
+            files.push((path, out_path));
         }
     }
 
-    Ok(preprocessed)
+    Ok(())
 }
 
 pub fn prepare_lua(dev_mode: bool) -> Result<Lua> {
-    let lua = Lua::new();
+    prepare(Lua::new(), dev_mode)
+}
+
+/// The functions of the `os` library kept in a sandbox, which only tell the time
+const SANDBOX_OS: &[&str] = &["clock", "date", "difftime", "time"];
+
+/// Prepare a Lua state for code which isn't trusted with the machine it runs on
+///
+/// On top of `debug`, which is never loaded, and the C module loaders, which
+/// are removed, this leaves out the `io` library, `dofile` and `loadfile`, and
+/// everything in `os` but `clock`, `date`, `difftime` and `time`.
+pub fn prepare_sandboxed_lua(dev_mode: bool) -> Result<Lua> {
+    let lua = Lua::new_with(StdLib::ALL_NO_DEBUG - StdLib::IO);
+
+    lua.context(|ctx| -> Result<()> {
+        let globals = ctx.globals();
+        globals.set("dofile", rlua::Value::Nil)?;
+        globals.set("loadfile", rlua::Value::Nil)?;
+
+        let os: rlua::Table = globals.get("os")?;
+        let sandboxed_os = ctx.create_table()?;
+        for name in SANDBOX_OS {
+            sandboxed_os.set(*name, os.get::<_, rlua::Function>(*name)?)?;
+        }
+        globals.set("os", sandboxed_os.clone())?;
+
+        // `require('os')` gives the same table
+        let loaded: rlua::Table = globals.get::<_, rlua::Table>("package")?.get("loaded")?;
+        loaded.set("os", sandboxed_os)?;
+        loaded.set("io", rlua::Value::Nil)?;
+
+        Ok(())
+    })?;
+
+    prepare(lua, dev_mode)
+}
+
+/// The key of the original `collectgarbage` in the registry of a Lua state,
+/// kept for the server to measure memory with even if the global is replaced
+pub(crate) const COLLECTGARBAGE_KEY: &str = "reluax.collectgarbage";
 
+/// The key of the variables `reluax.env` may read in the registry of a Lua
+/// state, copied from the manifest so that later changes to it have no effect
+const ENV_ALLOWLIST_KEY: &str = "reluax.env_allowlist";
+
+fn prepare(lua: Lua, dev_mode: bool) -> Result<Lua> {
     // create a table called "reluax" with common utility functions
     // and put it in the global scope
     lua.context(|ctx| -> Result<()> {
+        let collectgarbage: rlua::Function = ctx.globals().get("collectgarbage")?;
+        ctx.set_named_registry_value(COLLECTGARBAGE_KEY, collectgarbage)?;
+
         let reluax = ctx.create_table()?;
 
         let url_matches = ctx.create_function(utils::url_matches)?;
         reluax.set("url_matches", url_matches)?;
         let url_extract = ctx.create_function(utils::url_extract)?;
         reluax.set("url_extract", url_extract)?;
+        let url_for = ctx.create_function(utils::url_for)?;
+        reluax.set("url_for", url_for)?;
+        let router = ctx.create_function(utils::router)?;
+        reluax.set("router", router)?;
         let html = ctx.create_function(utils::wrap_html)?;
         reluax.set("html", html)?;
+        let h = ctx.create_function(utils::h)?;
+        reluax.set("h", h)?;
         let html_page = ctx.create_function(utils::wrap_html_page)?;
         reluax.set("html_page", html_page)?;
+        let xhtml = ctx.create_function(utils::wrap_xhtml)?;
+        reluax.set("xhtml", xhtml)?;
+        let xml = ctx.create_function(utils::wrap_xml)?;
+        reluax.set("xml", xml)?;
+        let text = ctx.create_function(utils::wrap_text)?;
+        reluax.set("text", text)?;
         let json = ctx.create_function(utils::wrap_json)?;
         reluax.set("json", json)?;
+        let redirect = ctx.create_function(utils::redirect)?;
+        reluax.set("redirect", redirect)?;
+        let static_file = ctx.create_function(utils::static_file)?;
+        reluax.set("static", static_file)?;
+        let stream = ctx.create_function(utils::stream)?;
+        reluax.set("stream", stream)?;
         let headers = ctx.create_function(utils::wrap_headers)?;
         reluax.set("headers", headers)?;
+        let merge_classes = ctx.create_function(utils::merge_classes)?;
+        reluax.set("merge_classes", merge_classes)?;
+        let flash = ctx.create_function(utils::flash)?;
+        reluax.set("flash", flash)?;
+        let flashes = ctx.create_function(utils::flashes)?;
+        reluax.set("flashes", flashes)?;
+        let paginate = ctx.create_function(utils::paginate)?;
+        reluax.set("paginate", paginate)?;
+        let segments = ctx.create_function(utils::segments)?;
+        reluax.set("segments", segments)?;
+        let parse_form = ctx.create_function(utils::parse_form)?;
+        reluax.set("parse_form", parse_form)?;
+        let parse_multipart = ctx.create_function(utils::parse_multipart)?;
+        reluax.set("parse_multipart", parse_multipart)?;
+        let body_bytes = ctx.create_function(utils::body_bytes)?;
+        reluax.set("body_bytes", body_bytes)?;
+        let expect_content_type = ctx.create_function(utils::expect_content_type)?;
+        reluax.set("expect_content_type", expect_content_type)?;
+        let url_encode = ctx.create_function(utils::url_encode)?;
+        reluax.set("url_encode", url_encode)?;
+        let url_decode = ctx.create_function(utils::url_decode)?;
+        reluax.set("url_decode", url_decode)?;
+        let base64_encode = ctx.create_function(utils::base64_encode)?;
+        reluax.set("base64_encode", base64_encode)?;
+        let base64_decode = ctx.create_function(utils::base64_decode)?;
+        reluax.set("base64_decode", base64_decode)?;
+        let sign = ctx.create_function(utils::sign)?;
+        reluax.set("sign", sign)?;
+        let verify = ctx.create_function(utils::verify)?;
+        reluax.set("verify", verify)?;
+        let csrf_token = ctx.create_function(utils::csrf_token)?;
+        reluax.set("csrf_token", csrf_token)?;
+        let csrf_check = ctx.create_function(utils::csrf_check)?;
+        reluax.set("csrf_check", csrf_check)?;
+        let check_basic_auth = ctx.create_function(utils::check_basic_auth)?;
+        reluax.set("check_basic_auth", check_basic_auth)?;
+        let check_bearer = ctx.create_function(utils::check_bearer)?;
+        reluax.set("check_bearer", check_bearer)?;
+        let date = ctx.create_function(utils::date)?;
+        reluax.set("date", date)?;
+        let markdown = ctx.create_function(utils::markdown)?;
+        reluax.set("markdown", markdown)?;
+        let env = ctx.create_function(move |ctx, name| utils::env(ctx, name, dev_mode))?;
+        reluax.set("env", env)?;
+        // the project is served from the current directory, wherever it is
+        // later on
+        let root = std::env::current_dir()?;
+        let read_file = ctx.create_function(move |ctx, path| utils::read_file(ctx, path, &root))?;
+        reluax.set("read_file", read_file)?;
         reluax.set("dev_mode", dev_mode)?;
 
         ctx.globals().set("reluax", reluax)?;
@@ -186,8 +789,16 @@ pub fn prepare_lua(dev_mode: bool) -> Result<Lua> {
     Ok(lua)
 }
 
-mod utils {
-    use rlua::{Context, Result, Table};
+pub(crate) mod utils {
+    use std::fmt::Write;
+    use std::path::{Component, Path};
+
+    use chrono::TimeZone;
+    use chrono_tz::Tz;
+    use rlua::{Context, Function, MultiValue, Result, Table, ToLuaMulti, Value, Variadic};
+
+    use super::base64::{self, Alphabet};
+    use super::{signing, ENV_ALLOWLIST_KEY};
 
     /// Check if a path matches a pattern
     ///
@@ -201,8 +812,27 @@ mod utils {
     /// '/foo/*' -> in a case of '/foo/baz' will match
     ///
     /// This function only checks if the path matches the pattern, it does not
-    /// capture any values.
-    pub fn url_matches(_: Context<'_>, (pattern, path): (String, String)) -> Result<bool> {
+    /// capture any values. The whole path has to match the whole pattern, so
+    /// `/foo` matches neither `/foobar` nor `/foo/`.
+    ///
+    /// An optional table of options can be passed as the third argument:
+    /// - `trailing_slash = true` ignores a trailing `/` on both the pattern and
+    ///   the path, so that `/foo` and `/foo/` match each other
+    pub fn url_matches(
+        _: Context<'_>,
+        (pattern, path, options): (String, String, Option<Table<'_>>),
+    ) -> Result<bool> {
+        let trailing_slash = match options {
+            Some(options) => options.get::<_, Option<bool>>("trailing_slash")?,
+            None => None,
+        };
+
+        let (pattern, path) = if trailing_slash.unwrap_or(false) {
+            (trim_trailing_slash(&pattern), trim_trailing_slash(&path))
+        } else {
+            (pattern.as_str(), path.as_str())
+        };
+
         let mut path = path.chars();
         let mut pattern = pattern.chars();
 
@@ -210,8 +840,11 @@ mod utils {
         let mut pattern_char = pattern.next();
 
         loop {
-            if path_char.is_none() && (pattern_char.is_none() || pattern_char == Some('*')) {
+            if path_char.is_none() && pattern_char.is_none() {
                 return Ok(true);
+            } else if path_char.is_none() && pattern_char == Some('*') {
+                // a `*` can match nothing, but only at the end of the pattern
+                return Ok(pattern.next().is_none());
             } else if path_char == pattern_char {
                 path_char = path.next();
                 pattern_char = pattern.next();
@@ -237,22 +870,29 @@ mod utils {
                 while path_char != Some('/') && path_char.is_some() {
                     path_char = path.next();
                 }
-                if path_char.is_none() {
-                    return Ok(true);
-                }
             } else {
                 return Ok(false);
             }
         }
     }
 
+    fn trim_trailing_slash(s: &str) -> &str {
+        match s.strip_suffix('/') {
+            Some(trimmed) if !trimmed.is_empty() => trimmed,
+            _ => s,
+        }
+    }
+
     /// Extract values from a path using a pattern
     ///
     /// A pattern can contain the following:
     /// - `*` matches any number of characters
     /// - `{name}` matches anything except `/` and captures the value as `name`
     ///
-    /// This function will return a table with the captured values.
+    /// This function will return a table with the captured values. The text
+    /// matched by each `*` is captured under consecutive numbers, starting at 1,
+    /// and the last one is also available as `splat`, so that `/files/*` captures
+    /// the rest of the path as `splat`.
     pub fn url_extract(ctx: Context<'_>, (pattern, path): (String, String)) -> Result<Table> {
         let mut path = path.chars();
         let mut pattern = pattern.chars();
@@ -261,24 +901,37 @@ mod utils {
         let mut pattern_char = pattern.next();
 
         let params = ctx.create_table()?;
+        let mut splats = 0;
 
         loop {
             if path_char.is_none() && (pattern_char.is_none() || pattern_char == Some('*')) {
+                if pattern_char == Some('*') {
+                    splats += 1;
+                    set_splat(&params, splats, String::new())?;
+                }
                 return Ok(params);
             } else if path_char == pattern_char {
                 path_char = path.next();
                 pattern_char = pattern.next();
             } else if pattern_char == Some('*') {
                 pattern_char = pattern.next();
+                splats += 1;
+                let mut splat = String::new();
                 if pattern_char.is_none() {
+                    splat.extend(path_char);
+                    splat.extend(path);
+                    set_splat(&params, splats, splat)?;
                     return Ok(params);
                 }
                 while path_char != pattern_char {
+                    splat.extend(path_char);
                     path_char = path.next();
                     if path_char.is_none() {
+                        set_splat(&params, splats, splat)?;
                         return Ok(params);
                     }
                 }
+                set_splat(&params, splats, splat)?;
             } else if pattern_char == Some('{') {
                 pattern_char = pattern.next();
                 let mut param_name = String::new();
@@ -302,36 +955,1146 @@ mod utils {
         }
     }
 
+    /// Build a path from a pattern, filling in the values from a table
+    ///
+    /// This is the inverse of `url_extract`: `{name}` is replaced with the value
+    /// of `name`, and each `*` with its numbered capture, or `splat` if there is
+    /// none. Values are inserted as they are, without escaping.
+    pub fn url_for(
+        _: Context<'_>,
+        (pattern, params): (String, Option<Table<'_>>),
+    ) -> Result<String> {
+        let missing = |name: &str| {
+            rlua::Error::RuntimeError(format!(
+                "missing parameter `{}` for pattern {}",
+                name, pattern
+            ))
+        };
+
+        let mut url = String::new();
+        let mut splats = 0;
+        let mut chars = pattern.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' => {
+                    let name: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                    let value = get_param(params.as_ref(), name.as_str())?;
+                    url.push_str(&value.ok_or_else(|| missing(&name))?);
+                }
+                '*' => {
+                    splats += 1;
+                    let value = match get_param(params.as_ref(), splats)? {
+                        Some(value) => Some(value),
+                        None => get_param(params.as_ref(), "splat")?,
+                    };
+                    url.push_str(&value.ok_or_else(|| missing("splat"))?);
+                }
+                c => url.push(c),
+            }
+        }
+
+        Ok(url)
+    }
+
+    /// Create a router, dispatching requests to handlers by path and method
+    ///
+    /// Handlers are registered with `router:get(pattern, handler)`, and the same
+    /// for `post`, `put`, `patch`, `delete` and `options`, or `any` for every
+    /// method. `router:match(path, method, ...)` calls the first handler with a
+    /// matching pattern and method, passing it the values extracted from the path
+    /// followed by the rest of the arguments, and returns what it returns. If the
+    /// path matches but no method does, it returns `405` along with the allowed
+    /// methods, and if nothing matches, nothing.
+    pub fn router(ctx: Context<'_>, _: ()) -> Result<Table<'_>> {
+        let router = ctx.create_table()?;
+        router.set("routes", ctx.create_table()?)?;
+
+        for method in ["get", "post", "put", "patch", "delete", "options"] {
+            let method_name = method.to_uppercase();
+            let add = ctx.create_function(
+                move |ctx, (router, pattern, handler): (Table, String, Function)| {
+                    add_route(ctx, router, method_name.clone(), pattern, handler)
+                },
+            )?;
+            router.set(method, add)?;
+        }
+
+        let any = ctx.create_function(
+            |ctx, (router, pattern, handler): (Table, String, Function)| {
+                add_route(ctx, router, "*".to_string(), pattern, handler)
+            },
+        )?;
+        router.set("any", any)?;
+        router.set("match", ctx.create_function(router_match)?)?;
+
+        Ok(router)
+    }
+
+    fn add_route<'lua>(
+        ctx: Context<'lua>,
+        router: Table<'lua>,
+        method: String,
+        pattern: String,
+        handler: Function<'lua>,
+    ) -> Result<Table<'lua>> {
+        let route = ctx.create_table()?;
+        route.set("method", method)?;
+        route.set("pattern", pattern)?;
+        route.set("handler", handler)?;
+
+        let routes: Table = router.get("routes")?;
+        routes.set(routes.raw_len() + 1, route)?;
+
+        // return the router, so that registrations can be chained
+        Ok(router)
+    }
+
+    fn router_match<'lua>(
+        ctx: Context<'lua>,
+        (router, path, method, args): (Table<'lua>, String, String, MultiValue<'lua>),
+    ) -> Result<MultiValue<'lua>> {
+        let routes: Table = router.get("routes")?;
+        let mut allowed = Vec::new();
+
+        for route in routes.sequence_values::<Table>() {
+            let route = route?;
+            let pattern: String = route.get("pattern")?;
+
+            if !url_matches(ctx, (pattern.clone(), path.clone(), None))? {
+                continue;
+            }
+
+            let route_method: String = route.get("method")?;
+            if route_method != "*" && !route_method.eq_ignore_ascii_case(&method) {
+                if !allowed.contains(&route_method) {
+                    allowed.push(route_method);
+                }
+                continue;
+            }
+
+            let params = url_extract(ctx, (pattern, path))?;
+            let handler: Function = route.get("handler")?;
+
+            return handler.call(
+                std::iter::once(Value::Table(params))
+                    .chain(args)
+                    .collect::<MultiValue>(),
+            );
+        }
+
+        if !allowed.is_empty() {
+            return (405, "Method Not Allowed", allowed).to_lua_multi(ctx);
+        }
+
+        Ok(MultiValue::new())
+    }
+
+    fn get_param<'lua, K: rlua::ToLua<'lua>>(
+        params: Option<&Table<'lua>>,
+        key: K,
+    ) -> Result<Option<String>> {
+        match params {
+            Some(params) => params.get(key),
+            None => Ok(None),
+        }
+    }
+
+    fn set_splat(params: &Table<'_>, index: usize, splat: String) -> Result<()> {
+        params.set(index, splat.as_str())?;
+        params.set("splat", splat)
+    }
+
     /// Wrap a table in a table to signal that it should be rendered as HTML
     pub fn wrap_html<'lua>(_: Context<'lua>, table: Table<'lua>) -> Result<Table<'lua>> {
         table.set("type", "html")?;
         Ok(table)
     }
 
-    /// Wrap a table in a table to signal that it should be rendered as a full HTML page
-    pub fn wrap_html_page<'lua>(_: Context<'lua>, table: Table<'lua>) -> Result<Table<'lua>> {
-        table.set("type", "html-page")?;
+    /// Build an element from its tag, attributes and children, the same way a
+    /// LuaX tag would, for building trees without the template syntax
+    ///
+    /// The attributes and children can be left out, and a single child doesn't
+    /// have to be wrapped in a list: `h("p", nil, "hello")`.
+    pub fn h<'lua>(
+        ctx: Context<'lua>,
+        (tag, attrs, children): (String, Option<Table<'lua>>, Value<'lua>),
+    ) -> Result<Table<'lua>> {
+        if tag.is_empty() {
+            return Err(rlua::Error::RuntimeError(
+                "an element needs a tag".to_string(),
+            ));
+        }
+
+        let children = match children {
+            Value::Nil => ctx.create_table()?,
+            // a single element, rather than a list of them
+            Value::Table(t) if t.contains_key("tag")? || t.contains_key("raw")? => {
+                ctx.create_sequence_from([t])?
+            }
+            Value::Table(t) => t,
+            child => ctx.create_sequence_from([child])?,
+        };
+
+        let table = ctx.create_table()?;
+        table.set("tag", tag)?;
+        table.set("attrs", attrs.map_or_else(|| ctx.create_table(), Ok)?)?;
+        table.set("children", children)?;
         Ok(table)
     }
 
-    /// Wrap a table in a table to signal that it should be rendered as JSON
-    pub fn wrap_json<'lua>(_: Context<'lua>, table: Table<'lua>) -> Result<Table<'lua>> {
-        table.set("type", "json")?;
+    /// Wrap a table in a table to signal that it should be rendered as a full HTML page
+    ///
+    /// The options can set the `doctype` written before the page (`html` by
+    /// default), and the page's `lang`, which is set on the `<html>` element,
+    /// wrapping the table in one if it isn't one already.
+    pub fn wrap_html_page<'lua>(
+        ctx: Context<'lua>,
+        (table, options): (Table<'lua>, Option<Table<'lua>>),
+    ) -> Result<Table<'lua>> {
+        let (doctype, lang) = match options {
+            Some(options) => (
+                options.get::<_, Option<String>>("doctype")?,
+                options.get::<_, Option<String>>("lang")?,
+            ),
+            None => (None, None),
+        };
+
+        let page = match lang {
+            Some(lang) if table.get::<_, Option<String>>("tag")?.as_deref() == Some("html") => {
+                let attrs = match table.get::<_, Option<Table>>("attrs")? {
+                    Some(attrs) => attrs,
+                    None => {
+                        let attrs = ctx.create_table()?;
+                        table.set("attrs", attrs.clone())?;
+                        attrs
+                    }
+                };
+                if !attrs.contains_key("lang")? {
+                    attrs.set("lang", lang)?;
+                }
+                table
+            }
+            Some(lang) => {
+                let attrs = ctx.create_table()?;
+                attrs.set("lang", lang)?;
+
+                let page = ctx.create_table()?;
+                page.set("tag", "html")?;
+                page.set("attrs", attrs)?;
+                page.set("children", ctx.create_sequence_from([table])?)?;
+                page
+            }
+            None => table,
+        };
+
+        page.set("type", "html-page")?;
+        page.set("doctype", doctype)?;
+        Ok(page)
+    }
+
+    /// Wrap a table in a table to signal that it should be rendered as XHTML,
+    /// with `application/xhtml+xml` as the MIME type by default
+    ///
+    /// Elements without children are self-closing, so it also works for SVG
+    /// with `image/svg+xml`.
+    pub fn wrap_xhtml<'lua>(
+        _: Context<'lua>,
+        (table, mime_type): (Table<'lua>, Option<String>),
+    ) -> Result<Table<'lua>> {
+        table.set("type", "xhtml")?;
+        table.set("mime_type", mime_type)?;
         Ok(table)
     }
 
-    /// Wrap a table to add headers to the response
-    pub fn wrap_headers<'lua>(
+    /// Wrap a table in a table to signal that it should be rendered as XML,
+    /// with `application/xml` as the MIME type by default
+    pub fn wrap_xml<'lua>(
         _: Context<'lua>,
-        (table, headers): (Table<'lua>, Table<'lua>),
+        (table, mime_type): (Table<'lua>, Option<String>),
     ) -> Result<Table<'lua>> {
-        table.set("headers", headers)?;
+        table.set("type", "xml")?;
+        table.set("mime_type", mime_type)?;
         Ok(table)
     }
 
-    #[cfg(test)]
-    mod tests {
-        use rlua::Lua;
+    /// Wrap a string to signal that it should be sent as it is, as plain text by
+    /// default or with the given MIME type
+    pub fn wrap_text<'lua>(
+        ctx: Context<'lua>,
+        (text, mime_type): (rlua::String<'lua>, Option<String>),
+    ) -> Result<Table<'lua>> {
+        let table = ctx.create_table()?;
+        table.set("type", "text")?;
+        table.set("text", text)?;
+        table.set("mime_type", mime_type)?;
+        Ok(table)
+    }
+
+    /// Wrap a table in a table to signal that it should be rendered as JSON
+    ///
+    /// The table is kept in `value`, so that the response's own keys, like its
+    /// `headers`, don't end up in the JSON.
+    pub fn wrap_json<'lua>(ctx: Context<'lua>, table: Table<'lua>) -> Result<Table<'lua>> {
+        let wrapper = ctx.create_table()?;
+        wrapper.set("type", "json")?;
+        wrapper.set("value", table)?;
+        Ok(wrapper)
+    }
+
+    /// Create a response redirecting to another location, with a `302 Found`
+    /// status by default
+    ///
+    /// Both temporary (302, 303, 307) and permanent (301, 308) redirects can be
+    /// made. The response carries its own status, so it can be returned from a
+    /// route on its own.
+    pub fn redirect(
+        ctx: Context<'_>,
+        (location, status): (String, Option<u16>),
+    ) -> Result<Table<'_>> {
+        let status = status.unwrap_or(302);
+
+        if ![301, 302, 303, 307, 308].contains(&status) {
+            return Err(rlua::Error::RuntimeError(format!(
+                "{} is not a redirect status",
+                status
+            )));
+        }
+
+        let table = ctx.create_table()?;
+        table.set("type", "redirect")?;
+        table.set("location", location)?;
+        table.set("status", status)?;
+        Ok(table)
+    }
+
+    /// Create a response delegating to the file in the public directory at the
+    /// given path, or the request path by default
+    ///
+    /// The file is served with the status the route returns it with, or `200 OK`
+    /// if it's returned on its own. If there's no such file, the response is a
+    /// `404 Not Found`.
+    pub fn static_file(ctx: Context<'_>, path: Option<String>) -> Result<Table<'_>> {
+        let table = ctx.create_table()?;
+        table.set("type", "static")?;
+        table.set("path", path)?;
+        Ok(table)
+    }
+
+    /// Create a response whose body is streamed from a producer function
+    ///
+    /// The producer is called for each chunk, returning either a string or a
+    /// LuaX table, which is rendered as HTML, and `nil` once the body is done.
+    /// Iterators like `coroutine.wrap(...)` work as producers. The Lua state is
+    /// only held for each call, but a producer which blocks still holds it.
+    pub fn stream<'lua>(
+        ctx: Context<'lua>,
+        (producer, mime_type): (Function<'lua>, Option<String>),
+    ) -> Result<Table<'lua>> {
+        let table = ctx.create_table()?;
+        table.set("type", "stream")?;
+        table.set("producer", producer)?;
+        table.set("mime_type", mime_type)?;
+        Ok(table)
+    }
+
+    /// Wrap a table to add headers to the response
+    pub fn wrap_headers<'lua>(
+        _: Context<'lua>,
+        (table, headers): (Table<'lua>, Table<'lua>),
+    ) -> Result<Table<'lua>> {
+        table.set("headers", headers)?;
+        Ok(table)
+    }
+
+    /// Get the conflict group of a utility class, which is everything before
+    /// its last `-` (`p-4` -> `p`, `hover:text-red-500` -> `hover:text-red`).
+    /// Classes without a `-` are only in conflict with themselves.
+    fn class_group(class: &str) -> &str {
+        match class.rfind('-') {
+            Some(i) if i > 0 => &class[..i],
+            _ => class,
+        }
+    }
+
+    /// Merge two class strings, letting classes from `override` win
+    ///
+    /// Duplicate classes are removed, and any class in `base` sharing a
+    /// conflict group with a class in `override` is dropped, so that
+    /// `merge_classes("p-4 m-2", "p-2")` results in `"m-2 p-2"`. Classes of the
+    /// same string never conflict with each other, like `"flex flex-col"`.
+    pub fn merge_classes(
+        _: Context<'_>,
+        (base, overrides): (Option<String>, Option<String>),
+    ) -> Result<String> {
+        let base = base.unwrap_or_default();
+        let overrides = overrides.unwrap_or_default();
+
+        // each class with whether it comes from `overrides`
+        let mut merged: Vec<(&str, bool)> = Vec::new();
+        for class in base.split_whitespace() {
+            if !merged.iter().any(|(c, _)| *c == class) {
+                merged.push((class, false));
+            }
+        }
+        for class in overrides.split_whitespace() {
+            if merged.iter().any(|(c, _)| *c == class) {
+                continue;
+            }
+            merged.retain(|(c, overriding)| *overriding || class_group(c) != class_group(class));
+            merged.push((class, true));
+        }
+
+        let merged: Vec<&str> = merged.into_iter().map(|(c, _)| c).collect();
+        Ok(merged.join(" "))
+    }
+
+    /// Decode `%XX` escapes in a string. Invalid escapes are kept as they are,
+    /// and invalid UTF-8 is replaced.
+    pub fn percent_decode(s: &str) -> String {
+        String::from_utf8_lossy(&percent_decode_bytes(s.as_bytes())).into_owned()
+    }
+
+    /// Decode `%XX` escapes in raw bytes, keeping invalid escapes as they are
+    pub fn percent_decode_bytes(bytes: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::with_capacity(bytes.len());
+
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'%' {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|h| std::str::from_utf8(h).ok())
+                    .and_then(|h| u8::from_str_radix(h, 16).ok());
+                if let Some(byte) = hex {
+                    decoded.push(byte);
+                    i += 3;
+                    continue;
+                }
+            }
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+
+        decoded
+    }
+
+    /// Encode bytes by `application/x-www-form-urlencoded` rules: alphanumerics
+    /// and `*-._` are kept, spaces become `+`, and everything else is
+    /// percent-encoded, byte by byte
+    pub fn form_encode_bytes(bytes: &[u8]) -> String {
+        let mut encoded = String::with_capacity(bytes.len());
+
+        for &b in bytes {
+            match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'*' | b'-' | b'.' | b'_' => {
+                    encoded.push(b as char)
+                }
+                b' ' => encoded.push('+'),
+                b => encoded.push_str(&format!("%{:02X}", b)),
+            }
+        }
+
+        encoded
+    }
+
+    /// Decode bytes by `application/x-www-form-urlencoded` rules, with `+` as a
+    /// space and `%XX` escapes percent-decoded
+    pub fn form_decode_bytes(bytes: &[u8]) -> Vec<u8> {
+        let bytes: Vec<u8> = bytes
+            .iter()
+            .map(|&b| if b == b'+' { b' ' } else { b })
+            .collect();
+
+        percent_decode_bytes(&bytes)
+    }
+
+    /// Encode a string to be put in a query string or form body
+    pub fn url_encode(_: Context<'_>, s: rlua::String<'_>) -> Result<String> {
+        Ok(form_encode_bytes(s.as_bytes()))
+    }
+
+    /// Decode a string from a query string or form body, keeping the raw bytes,
+    /// which don't have to be valid UTF-8
+    pub fn url_decode<'lua>(
+        ctx: Context<'lua>,
+        s: rlua::String<'lua>,
+    ) -> Result<rlua::String<'lua>> {
+        ctx.create_string(&form_decode_bytes(s.as_bytes()))
+    }
+
+    /// Pick the Base64 alphabet by name, `standard` by default or `url`
+    fn base64_alphabet(name: Option<String>) -> Result<Alphabet> {
+        match name.as_deref() {
+            None | Some("standard") => Ok(Alphabet::Standard),
+            Some("url") => Ok(Alphabet::UrlSafe),
+            Some(name) => Err(rlua::Error::RuntimeError(format!(
+                "unknown Base64 alphabet `{}`, expected `standard` or `url`",
+                name
+            ))),
+        }
+    }
+
+    /// Encode the raw bytes of a string as Base64, with the standard alphabet,
+    /// or the URL-safe one without padding if the second argument is `url`
+    pub fn base64_encode(
+        _: Context<'_>,
+        (bytes, alphabet): (rlua::String<'_>, Option<String>),
+    ) -> Result<String> {
+        Ok(base64::encode(bytes.as_bytes(), base64_alphabet(alphabet)?))
+    }
+
+    /// Decode Base64 into the raw bytes it encodes, or `nil` if it's invalid
+    ///
+    /// Padding is optional, and the alphabet is picked like for `base64_encode`.
+    pub fn base64_decode<'lua>(
+        ctx: Context<'lua>,
+        (s, alphabet): (String, Option<String>),
+    ) -> Result<Option<rlua::String<'lua>>> {
+        match base64::decode(&s, base64_alphabet(alphabet)?) {
+            Some(bytes) => Ok(Some(ctx.create_string(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Split a path into an array of its percent-decoded segments
+    ///
+    /// Empty segments are skipped, so `/a//b/` results in `{ "a", "b" }`, and
+    /// `/` in an empty table.
+    pub fn segments(ctx: Context<'_>, path: String) -> Result<Table<'_>> {
+        ctx.create_sequence_from(
+            path.split('/')
+                .filter(|segment| !segment.is_empty())
+                .map(percent_decode),
+        )
+    }
+
+    /// Parse an `application/x-www-form-urlencoded` body into a table
+    ///
+    /// `+` is decoded as a space, and `%XX` escapes are percent-decoded. A key
+    /// appearing more than once maps to a sequence of its values, in order.
+    /// Values are kept as raw bytes, so they don't need to be valid UTF-8.
+    pub fn parse_form<'lua>(
+        ctx: Context<'lua>,
+        body: Option<rlua::String<'lua>>,
+    ) -> Result<Table<'lua>> {
+        let form = ctx.create_table()?;
+
+        let decode = |s: &[u8]| ctx.create_string(&form_decode_bytes(s));
+
+        let body = body.as_ref().map(|b| b.as_bytes()).unwrap_or_default();
+
+        for pair in body.split(|&b| b == b'&') {
+            if pair.is_empty() {
+                continue;
+            }
+
+            let (key, value) = match pair.iter().position(|&b| b == b'=') {
+                Some(i) => (&pair[..i], &pair[i + 1..]),
+                None => (pair, &[][..]),
+            };
+
+            add_field(ctx, &form, decode(key)?, Value::String(decode(value)?))?;
+        }
+
+        Ok(form)
+    }
+
+    /// Set a field of a parsed form, turning it into a sequence of values if
+    /// it appears more than once
+    fn add_field<'lua>(
+        ctx: Context<'lua>,
+        form: &Table<'lua>,
+        key: rlua::String<'lua>,
+        value: Value<'lua>,
+    ) -> Result<()> {
+        match form.get::<_, Value>(key.clone())? {
+            Value::Nil => form.set(key, value),
+            Value::Table(values) if is_field_sequence(&values)? => {
+                values.set(values.raw_len() + 1, value)
+            }
+            first => form.set(key, ctx.create_sequence_from([first, value])?),
+        }
+    }
+
+    /// Tell sequences of repeated fields apart from the tables describing
+    /// uploaded files
+    fn is_field_sequence(table: &Table<'_>) -> Result<bool> {
+        Ok(!table.contains_key("data")?)
+    }
+
+    /// Find the first occurrence of `needle` in `haystack`
+    fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+    }
+
+    /// Parse a `multipart/form-data` body into a table, given the request's
+    /// `Content-Type`, which holds the boundary between the parts
+    ///
+    /// Plain fields map to their values, and uploaded files to a table with
+    /// their `filename`, `content_type` and `data`. Everything is kept as raw
+    /// bytes, so binary uploads round-trip unchanged. Like in `parse_form`, a
+    /// name appearing more than once maps to a sequence.
+    pub fn parse_multipart<'lua>(
+        ctx: Context<'lua>,
+        (body, content_type): (rlua::String<'lua>, String),
+    ) -> Result<Table<'lua>> {
+        let boundary = content_type
+            .split(';')
+            .filter_map(|param| param.trim().split_once('='))
+            .find(|(key, _)| key.eq_ignore_ascii_case("boundary"))
+            .map(|(_, value)| value.trim_matches('"').to_string())
+            .ok_or_else(|| {
+                rlua::Error::RuntimeError("multipart body without a boundary".to_string())
+            })?;
+
+        let delimiter = format!("--{}", boundary).into_bytes();
+        let form = ctx.create_table()?;
+
+        let mut rest = body.as_bytes();
+        // skip the preamble
+        match find_bytes(rest, &delimiter) {
+            Some(start) => rest = &rest[start + delimiter.len()..],
+            None => return Ok(form),
+        }
+
+        // every part starts after a delimiter, and the last one is followed by `--`
+        while !rest.starts_with(b"--") {
+            let end = find_bytes(rest, &delimiter).ok_or_else(|| {
+                rlua::Error::RuntimeError("unterminated multipart body".to_string())
+            })?;
+            let part = &rest[..end];
+            rest = &rest[end + delimiter.len()..];
+
+            // each part is surrounded by line breaks
+            let part = part.strip_prefix(b"\r\n").unwrap_or(part);
+            let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+
+            let (head, data) = match find_bytes(part, b"\r\n\r\n") {
+                Some(i) => (&part[..i], &part[i + 4..]),
+                None => continue,
+            };
+
+            let mut name = None;
+            let mut filename = None;
+            let mut part_type = None;
+
+            for line in String::from_utf8_lossy(head).split("\r\n") {
+                let (key, value) = match line.split_once(':') {
+                    Some(header) => header,
+                    None => continue,
+                };
+
+                if key.trim().eq_ignore_ascii_case("content-type") {
+                    part_type = Some(value.trim().to_string());
+                } else if key.trim().eq_ignore_ascii_case("content-disposition") {
+                    for param in value.split(';').filter_map(|p| p.trim().split_once('=')) {
+                        let value = param.1.trim_matches('"').to_string();
+                        match param.0 {
+                            "name" => name = Some(value),
+                            "filename" => filename = Some(value),
+                            _ => (),
+                        }
+                    }
+                }
+            }
+
+            let name = match name {
+                Some(name) => ctx.create_string(&name)?,
+                None => continue,
+            };
+
+            let value = match filename {
+                Some(filename) => {
+                    let file = ctx.create_table()?;
+                    file.set("filename", filename)?;
+                    file.set("content_type", part_type)?;
+                    file.set("data", ctx.create_string(data)?)?;
+                    Value::Table(file)
+                }
+                None => Value::String(ctx.create_string(data)?),
+            };
+
+            add_field(ctx, &form, name, value)?;
+        }
+
+        Ok(form)
+    }
+
+    /// Get the bytes of a string, like a raw request body, as a sequence of
+    /// numbers, optionally only from `from` to `to` (1-based and inclusive,
+    /// like `string.sub`)
+    ///
+    /// Unlike `string.byte`, this works on strings of any length.
+    pub fn body_bytes<'lua>(
+        ctx: Context<'lua>,
+        (body, from, to): (rlua::String<'lua>, Option<i64>, Option<i64>),
+    ) -> Result<Table<'lua>> {
+        let bytes = body.as_bytes();
+        let len = bytes.len() as i64;
+
+        // negative positions count from the end
+        let position = |i: i64| if i < 0 { len + i + 1 } else { i };
+        let from = position(from.unwrap_or(1)).max(1);
+        let to = position(to.unwrap_or(-1)).min(len);
+
+        if from > to {
+            return ctx.create_table();
+        }
+
+        ctx.create_sequence_from(bytes[from as usize - 1..to as usize].iter().copied())
+    }
+
+    /// Check if the request's `Content-Type` is the expected media type
+    ///
+    /// Parameters like `; charset=utf-8` are ignored, and the comparison is
+    /// case-insensitive. A request without a `Content-Type` never matches.
+    pub fn expect_content_type(
+        _: Context<'_>,
+        (headers, expected): (Table<'_>, String),
+    ) -> Result<bool> {
+        let mut content_type = None;
+        for pair in headers.pairs::<String, String>() {
+            let (key, value) = pair?;
+            if key.eq_ignore_ascii_case("content-type") {
+                content_type = Some(value);
+                break;
+            }
+        }
+
+        let media_type = |s: &str| {
+            s.split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_lowercase()
+        };
+
+        Ok(content_type.map_or(false, |ct| media_type(&ct) == media_type(&expected)))
+    }
+
+    /// Sign a value with HMAC-SHA256, so that it can be handed to a client, e.g.
+    /// in a cookie, and trusted when it comes back
+    ///
+    /// The secret defaults to `RELUAX_SECRET` or the `secret` in `reluax.toml`.
+    pub fn sign(_: Context<'_>, (value, secret): (String, Option<String>)) -> Result<String> {
+        Ok(match secret {
+            Some(secret) => signing::sign_with(secret.as_bytes(), &value),
+            None => signing::sign(&value)?,
+        })
+    }
+
+    /// Check a value signed with `reluax.sign` and the same secret, returning
+    /// the original value, or `nil` if it was tampered with
+    pub fn verify(
+        _: Context<'_>,
+        (signed, secret): (String, Option<String>),
+    ) -> Result<Option<String>> {
+        let value = match &secret {
+            Some(secret) => signing::verify_with(secret.as_bytes(), &signed),
+            None => signing::verify(&signed)?,
+        };
+
+        Ok(value.map(str::to_string))
+    }
+
+    /// How long a CSRF token stays valid by default, in seconds
+    const CSRF_MAX_AGE: u64 = 60 * 60;
+
+    fn unix_now() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+    }
+
+    /// Derive the key CSRF tokens are signed with from a secret, so that values
+    /// signed by `reluax.sign` with the same secret can't pass for tokens
+    fn csrf_key(secret: &[u8]) -> String {
+        signing::signature_with(secret, "reluax.csrf")
+    }
+
+    fn csrf_message(issued: u64, session_id: &str) -> String {
+        format!("{}:{}", issued, session_id)
+    }
+
+    /// Make a CSRF token for a session at a given time, as `issued.signature`,
+    /// where the signature covers both the time and the session id
+    fn csrf_token_at(key: &[u8], session_id: &str, now: u64) -> String {
+        let key = csrf_key(key);
+        let signature = signing::signature_with(key.as_bytes(), &csrf_message(now, session_id));
+
+        format!("{}.{}", now, signature)
+    }
+
+    fn csrf_check_at(key: &[u8], token: &str, session_id: &str, max_age: u64, now: u64) -> bool {
+        let (issued, signature) = match token.split_once('.') {
+            Some(parts) => parts,
+            None => return false,
+        };
+        let issued: u64 = match issued.parse() {
+            Ok(issued) => issued,
+            Err(_) => return false,
+        };
+
+        if issued > now || now - issued > max_age {
+            return false;
+        }
+
+        let key = csrf_key(key);
+        let expected = signing::signature_with(key.as_bytes(), &csrf_message(issued, session_id));
+        signing::constant_time_eq(signature.as_bytes(), expected.as_bytes())
+    }
+
+    /// Make a token to embed in a form as a hidden field, tied to the session
+    ///
+    /// The secret defaults to the one used by `reluax.sign`. Tokens are checked
+    /// with `reluax.csrf_check` when the form is submitted.
+    pub fn csrf_token(
+        _: Context<'_>,
+        (secret, session_id): (Option<String>, String),
+    ) -> Result<String> {
+        let key = match secret {
+            Some(secret) => secret.into_bytes(),
+            None => signing::default_secret()?.to_vec(),
+        };
+
+        Ok(csrf_token_at(&key, &session_id, unix_now()))
+    }
+
+    /// Check a token made by `reluax.csrf_token` with the same secret and session
+    ///
+    /// Tokens older than `max_age` seconds (an hour by default) are rejected.
+    pub fn csrf_check(
+        _: Context<'_>,
+        (token, secret, session_id, max_age): (Option<String>, Option<String>, String, Option<u64>),
+    ) -> Result<bool> {
+        let token = match token {
+            Some(token) => token,
+            None => return Ok(false),
+        };
+        let key = match secret {
+            Some(secret) => secret.into_bytes(),
+            None => signing::default_secret()?.to_vec(),
+        };
+
+        Ok(csrf_check_at(
+            &key,
+            &token,
+            &session_id,
+            max_age.unwrap_or(CSRF_MAX_AGE),
+            unix_now(),
+        ))
+    }
+
+    /// Get the credentials of the request's `Authorization` header for a scheme,
+    /// which is matched case-insensitively
+    fn authorization(headers: &Table<'_>, scheme: &str) -> Result<Option<String>> {
+        for pair in headers.pairs::<String, String>() {
+            let (key, value) = pair?;
+            if key.eq_ignore_ascii_case("authorization") {
+                return Ok(value
+                    .trim()
+                    .split_once(' ')
+                    .filter(|(s, _)| s.eq_ignore_ascii_case(scheme))
+                    .map(|(_, credentials)| credentials.trim().to_string()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Check if the request's `Authorization` header carries the given user and
+    /// password with the `Basic` scheme
+    ///
+    /// The password is compared in constant time.
+    pub fn check_basic_auth(
+        _: Context<'_>,
+        (headers, user, password): (Table<'_>, String, String),
+    ) -> Result<bool> {
+        let credentials = match authorization(&headers, "Basic")?
+            .and_then(|c| base64::decode(&c, Alphabet::Standard))
+        {
+            Some(credentials) => credentials,
+            None => return Ok(false),
+        };
+
+        let (given_user, given_password) = match credentials.iter().position(|&b| b == b':') {
+            Some(i) => (&credentials[..i], &credentials[i + 1..]),
+            None => return Ok(false),
+        };
+
+        // both are compared, so that a wrong user takes as long as a wrong password
+        let user_matches = signing::constant_time_eq(given_user, user.as_bytes());
+        let password_matches = signing::constant_time_eq(given_password, password.as_bytes());
+
+        Ok(user_matches && password_matches)
+    }
+
+    /// Check if the request's `Authorization` header carries the given token
+    /// with the `Bearer` scheme, comparing it in constant time
+    pub fn check_bearer(_: Context<'_>, (headers, token): (Table<'_>, String)) -> Result<bool> {
+        Ok(authorization(&headers, "Bearer")?.map_or(false, |given| {
+            signing::constant_time_eq(given.as_bytes(), token.as_bytes())
+        }))
+    }
+
+    /// Format a Unix timestamp with a `strftime`-like format string
+    ///
+    /// The timestamp is shown in the given IANA timezone (e.g. `Europe/Warsaw`),
+    /// or UTC if none is given. The format defaults to `%Y-%m-%d %H:%M:%S`.
+    pub fn date(
+        _: Context<'_>,
+        (timestamp, format, tz): (i64, Option<String>, Option<String>),
+    ) -> Result<String> {
+        let tz: Tz = match tz {
+            Some(name) => name
+                .parse()
+                .map_err(|_| rlua::Error::RuntimeError(format!("unknown timezone: {}", name)))?,
+            None => Tz::UTC,
+        };
+
+        let date = tz.timestamp_opt(timestamp, 0).single().ok_or_else(|| {
+            rlua::Error::RuntimeError(format!("timestamp out of range: {}", timestamp))
+        })?;
+
+        let format = format.unwrap_or_else(|| "%Y-%m-%d %H:%M:%S".to_string());
+
+        let mut formatted = String::new();
+        write!(formatted, "{}", date.format(&format))
+            .map_err(|_| rlua::Error::RuntimeError(format!("invalid date format: {}", format)))?;
+
+        Ok(formatted)
+    }
+
+    /// Render Markdown into a raw HTML node, which can be used as a child
+    ///
+    /// GitHub-flavored tables, strikethrough and task lists are supported. The
+    /// Markdown is trusted, so any HTML inside it is kept as it is.
+    pub fn markdown(ctx: Context<'_>, source: String) -> Result<Table<'_>> {
+        let options = pulldown_cmark::Options::ENABLE_TABLES
+            | pulldown_cmark::Options::ENABLE_STRIKETHROUGH
+            | pulldown_cmark::Options::ENABLE_TASKLISTS;
+        let parser = pulldown_cmark::Parser::new_ext(&source, options);
+
+        let mut html = String::new();
+        pulldown_cmark::html::push_html(&mut html, parser);
+
+        let node = ctx.create_table()?;
+        node.set("raw", html)?;
+        Ok(node)
+    }
+
+    /// Read an environment variable, returning `nil` if it is not set
+    ///
+    /// In dev mode any variable can be read. Otherwise, only the ones listed in
+    /// the `env` table of the manifest are available, so that templates can't
+    /// read arbitrary secrets from the environment.
+    pub fn env(ctx: Context<'_>, name: String, dev_mode: bool) -> Result<Option<String>> {
+        if !dev_mode && !env_allowed(ctx, &name)? {
+            return Ok(None);
+        }
+
+        Ok(std::env::var(&name).ok())
+    }
+
+    /// Read a file of the project, given its path relative to the project root,
+    /// returning `nil` if it can't be read
+    ///
+    /// Absolute paths and paths going up with `..` are errors, as are symlinks
+    /// leading out of the project, so only the project's own files can be read.
+    pub fn read_file<'lua>(
+        ctx: Context<'lua>,
+        path: String,
+        root: &Path,
+    ) -> Result<Option<rlua::String<'lua>>> {
+        let relative = Path::new(&path);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(rlua::Error::RuntimeError(format!(
+                "cannot read `{}`, only relative paths inside the project are allowed",
+                path
+            )));
+        }
+
+        let (root, file) = match (root.canonicalize(), root.join(relative).canonicalize()) {
+            (Ok(root), Ok(file)) => (root, file),
+            _ => return Ok(None),
+        };
+        if !file.starts_with(&root) {
+            return Err(rlua::Error::RuntimeError(format!(
+                "cannot read `{}`, it leads out of the project",
+                path
+            )));
+        }
+
+        match std::fs::read(file) {
+            Ok(bytes) => Ok(Some(ctx.create_string(&bytes)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Copy the `env` table of a manifest, as the variables `reluax.env` may
+    /// read from then on in this Lua state
+    ///
+    /// The server does this as soon as it requires the manifest, before any
+    /// route could change the table.
+    pub fn snapshot_env_allowlist<'lua>(
+        ctx: Context<'lua>,
+        manifest: &Table<'lua>,
+    ) -> Result<Table<'lua>> {
+        let snapshot = ctx.create_table()?;
+        if let Some(allowed) = manifest.get::<_, Option<Table>>("env")? {
+            for name in allowed.sequence_values::<String>() {
+                snapshot.set(name?, true)?;
+            }
+        }
+        ctx.set_named_registry_value(ENV_ALLOWLIST_KEY, snapshot.clone())?;
+
+        Ok(snapshot)
+    }
+
+    fn env_allowed(ctx: Context<'_>, name: &str) -> Result<bool> {
+        let allowed = match ctx.named_registry_value::<_, Option<Table>>(ENV_ALLOWLIST_KEY)? {
+            Some(allowed) => allowed,
+            None => {
+                let loaded: Table = ctx.globals().get::<_, Table>("package")?.get("loaded")?;
+
+                // while the manifest is still loading, this is not a table yet
+                match loaded.get::<_, Value>("reluax")? {
+                    Value::Table(manifest) => snapshot_env_allowlist(ctx, &manifest)?,
+                    _ => return Ok(false),
+                }
+            }
+        };
+
+        Ok(allowed.get::<_, Option<bool>>(name)?.unwrap_or(false))
+    }
+
+    const FLASH_COOKIE: &str = "reluax_flash";
+
+    /// Find a cookie by name in the value of a `Cookie` request header
+    fn find_cookie<'a>(cookies: &'a str, name: &str) -> Option<&'a str> {
+        cookies
+            .split(';')
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v)
+    }
+
+    /// Store one-time messages to be shown on the next request
+    ///
+    /// Returns the value of a `Set-Cookie` header, which has to be added to the
+    /// response, e.g. `reluax.headers(body, { ["Set-Cookie"] = reluax.flash("Saved!") })`.
+    /// The messages are kept in a short-lived signed cookie, so no server-side
+    /// storage is needed.
+    pub fn flash(_: Context<'_>, messages: Variadic<String>) -> Result<String> {
+        let value = messages
+            .iter()
+            .map(|m| signing::hex_encode(m.as_bytes()))
+            .collect::<Vec<_>>()
+            .join("-");
+
+        Ok(format!(
+            "{}={}; Max-Age=60; Path=/; HttpOnly; SameSite=Lax",
+            FLASH_COOKIE,
+            signing::sign(&value)?
+        ))
+    }
+
+    /// Read the messages stored with `reluax.flash` from the request headers
+    ///
+    /// Returns the messages as an array, and, if there were any, the value of a
+    /// `Set-Cookie` header clearing them, which should be added to the response.
+    pub fn flashes<'lua>(
+        ctx: Context<'lua>,
+        headers: Table<'lua>,
+    ) -> Result<(Table<'lua>, Option<String>)> {
+        let messages = ctx.create_table()?;
+
+        let cookies: Option<String> = headers.get("cookie")?;
+        let cookie = match cookies
+            .as_deref()
+            .and_then(|c| find_cookie(c, FLASH_COOKIE))
+        {
+            Some(cookie) => cookie,
+            None => return Ok((messages, None)),
+        };
+
+        if let Some(value) = signing::verify(cookie)? {
+            for message in value.split('-').filter(|m| !m.is_empty()) {
+                if let Some(bytes) = signing::hex_decode(message) {
+                    messages.set(
+                        messages.len()? + 1,
+                        String::from_utf8_lossy(&bytes).to_string(),
+                    )?;
+                }
+            }
+        }
+
+        let clear = format!(
+            "{}=; Max-Age=0; Path=/; HttpOnly; SameSite=Lax",
+            FLASH_COOKIE
+        );
+
+        Ok((messages, Some(clear)))
+    }
+
+    /// Split an array into pages, returning the requested page and metadata for a pager
+    ///
+    /// The returned table contains:
+    /// - `items`: the items on the page
+    /// - `page`, `per_page`, `total_items`, `total_pages`
+    /// - `has_prev`, `has_next`, and `prev`/`next` page numbers (`nil` if there is none)
+    /// - `pages`: an array of all page numbers
+    ///
+    /// Pages are numbered from 1, and out-of-range pages are clamped to the
+    /// first or last page. `per_page` defaults to 10.
+    pub fn paginate<'lua>(
+        ctx: Context<'lua>,
+        (items, page, per_page): (Table<'lua>, Option<i64>, Option<i64>),
+    ) -> Result<Table<'lua>> {
+        let per_page = per_page.unwrap_or(10).max(1);
+        let total_items = items.len()?;
+        // a page never holds more than all the items, which keeps the arithmetic
+        // below from overflowing on huge sizes like `math.maxinteger`
+        let page_size = per_page.min(total_items.max(1));
+        let total_pages = (total_items.saturating_add(page_size - 1) / page_size).max(1);
+        let page = page.unwrap_or(1).clamp(1, total_pages);
+
+        let page_items = ctx.create_table()?;
+        let first = (page - 1).saturating_mul(page_size).saturating_add(1);
+        let last = page.saturating_mul(page_size).min(total_items);
+        for i in first..=last {
+            page_items.set(i - first + 1, items.get::<_, rlua::Value>(i)?)?;
+        }
+
+        let pages = ctx.create_table()?;
+        for i in 1..=total_pages {
+            pages.set(i, i)?;
+        }
+
+        let res = ctx.create_table()?;
+        res.set("items", page_items)?;
+        res.set("page", page)?;
+        res.set("per_page", per_page)?;
+        res.set("total_items", total_items)?;
+        res.set("total_pages", total_pages)?;
+        res.set("has_prev", page > 1)?;
+        res.set("has_next", page < total_pages)?;
+        res.set("prev", (page > 1).then_some(page - 1))?;
+        res.set("next", (page < total_pages).then_some(page + 1))?;
+        res.set("pages", pages)?;
+
+        Ok(res)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use rlua::Lua;
 
         #[test]
         fn url_matches() {
@@ -349,13 +2112,24 @@ mod utils {
                 ("/{name}/b", "/a/b", true),
                 ("/{name}/b", "/a/c", false),
                 ("/{name}/b", "/a/b/c", false),
+                ("/{name}/b", "/a", false),
+                ("/{name}/b", "/a/", false),
+                ("/a/*/c", "/a/", false),
+                ("/a/*", "/a/", true),
+                ("/a/*", "/a", false),
+                ("/foo", "/foobar", false),
+                ("/foo", "/foo/", false),
+                ("/foo/", "/foo", false),
+                ("/foo/", "/foo/", true),
             ];
 
             let lua = Lua::new();
 
             for (pattern, path, expected) in cases {
                 let res: bool = lua
-                    .context(|ctx| super::url_matches(ctx, (pattern.to_string(), path.to_string())))
+                    .context(|ctx| {
+                        super::url_matches(ctx, (pattern.to_string(), path.to_string(), None))
+                    })
                     .unwrap();
                 if res != expected {
                     if expected {
@@ -368,16 +2142,52 @@ mod utils {
         }
 
         #[test]
-        fn url_extract() {
+        fn url_matches_trailing_slash() {
             let cases = vec![
-                ("/", "/", vec![]),
-                ("/{name}", "/a", vec![("name", "a")]),
-                ("/{name}/b", "/a/b", vec![("name", "a")]),
-                ("/{name}/b", "/a/c", vec![]),
-                ("/{name}/b", "/a/b/c", vec![]),
-                (
-                    "/{name}/b/{name2}",
-                    "/a/b/c",
+                ("/", "/", true),
+                ("/foo", "/foo", true),
+                ("/foo", "/foo/", true),
+                ("/foo/", "/foo", true),
+                ("/foo", "/foo//", false),
+                ("/foo", "/foobar/", false),
+                ("/{name}", "/a/", true),
+                ("/{name}/b", "/a/", false),
+                ("/a/*", "/a/b/", true),
+            ];
+
+            let lua = Lua::new();
+
+            for (pattern, path, expected) in cases {
+                let res: bool = lua
+                    .context(|ctx| {
+                        let options = ctx.create_table()?;
+                        options.set("trailing_slash", true)?;
+                        super::url_matches(
+                            ctx,
+                            (pattern.to_string(), path.to_string(), Some(options)),
+                        )
+                    })
+                    .unwrap();
+
+                assert_eq!(
+                    res, expected,
+                    "matching {} against {} ignoring trailing slashes",
+                    path, pattern
+                );
+            }
+        }
+
+        #[test]
+        fn url_extract() {
+            let cases = vec![
+                ("/", "/", vec![]),
+                ("/{name}", "/a", vec![("name", "a")]),
+                ("/{name}/b", "/a/b", vec![("name", "a")]),
+                ("/{name}/b", "/a/c", vec![]),
+                ("/{name}/b", "/a/b/c", vec![]),
+                (
+                    "/{name}/b/{name2}",
+                    "/a/b/c",
                     vec![("name", "a"), ("name2", "c")],
                 ),
                 (
@@ -386,6 +2196,22 @@ mod utils {
                     vec![("name", "a"), ("name2", "c")],
                 ),
                 ("/{name}/b/{name2}", "/a/b", vec![("name", "a")]),
+                (
+                    "/files/*",
+                    "/files/docs/readme.md",
+                    vec![("1", "docs/readme.md"), ("splat", "docs/readme.md")],
+                ),
+                ("/files/*", "/files/", vec![("1", ""), ("splat", "")]),
+                (
+                    "/*/b/*",
+                    "/a/b/c/d",
+                    vec![("1", "a"), ("2", "c/d"), ("splat", "c/d")],
+                ),
+                (
+                    "/{user}/files/*",
+                    "/ala/files/x/y",
+                    vec![("user", "ala"), ("1", "x/y"), ("splat", "x/y")],
+                ),
             ];
 
             let lua = Lua::new();
@@ -415,5 +2241,1087 @@ mod utils {
                 })
             }
         }
+
+        #[test]
+        fn url_for() {
+            let cases = vec![
+                ("/", vec![], "/"),
+                ("/users/{id}", vec![("id", "42")], "/users/42"),
+                (
+                    "/users/{id}/posts/{post}",
+                    vec![("id", "42"), ("post", "hello")],
+                    "/users/42/posts/hello",
+                ),
+                (
+                    "/users/{id}",
+                    vec![("id", "42"), ("extra", "x")],
+                    "/users/42",
+                ),
+                (
+                    "/files/*",
+                    vec![("splat", "docs/readme.md")],
+                    "/files/docs/readme.md",
+                ),
+                ("/*/b/*", vec![("1", "a"), ("2", "c/d")], "/a/b/c/d"),
+            ];
+
+            let lua = Lua::new();
+
+            for (pattern, params, expected) in cases {
+                let res = lua
+                    .context(|ctx| {
+                        let table = ctx.create_table()?;
+                        for (key, value) in params {
+                            match key.parse::<i64>() {
+                                Ok(index) => table.set(index, value)?,
+                                Err(_) => table.set(key, value)?,
+                            }
+                        }
+                        super::url_for(ctx, (pattern.to_string(), Some(table)))
+                    })
+                    .unwrap();
+
+                assert_eq!(res, expected, "filling in {}", pattern);
+            }
+        }
+
+        #[test]
+        fn url_for_missing_param() {
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                let table = ctx.create_table().unwrap();
+                table.set("id", 42).unwrap();
+
+                assert!(
+                    super::url_for(ctx, ("/users/{id}".to_string(), Some(table.clone()))).is_ok()
+                );
+                assert!(super::url_for(ctx, ("/users/{name}".to_string(), Some(table))).is_err());
+                assert!(super::url_for(ctx, ("/files/*".to_string(), None)).is_err());
+            });
+        }
+
+        #[test]
+        fn router() {
+            let lua = crate::luax::prepare_lua(false).unwrap();
+
+            lua.context(|ctx| {
+                ctx.load(
+                    r#"
+                    app = reluax.router()
+                    app:get("/users/{id}", function(params)
+                        return 200, "user " .. params.id
+                    end)
+                    app:post("/users", function(params, headers, body)
+                        return 201, body
+                    end)
+                    app:any("/files/*", function(params)
+                        return 200, params.splat
+                    end)
+                    "#,
+                )
+                .exec()
+                .unwrap();
+
+                let dispatch = |args: &str| -> (Option<i64>, Option<String>) {
+                    ctx.load(&format!("return app:match({})", args))
+                        .eval()
+                        .unwrap()
+                };
+
+                assert_eq!(
+                    dispatch(r#""/users/42", "GET""#),
+                    (Some(200), Some("user 42".to_string()))
+                );
+                assert_eq!(
+                    dispatch(r#""/users", "POST", {}, "created""#),
+                    (Some(201), Some("created".to_string()))
+                );
+                assert_eq!(
+                    dispatch(r#""/files/a/b.txt", "DELETE""#),
+                    (Some(200), Some("a/b.txt".to_string()))
+                );
+                assert_eq!(dispatch(r#""/nowhere", "GET""#), (None, None));
+            });
+        }
+
+        #[test]
+        fn router_method_not_allowed() {
+            let lua = crate::luax::prepare_lua(false).unwrap();
+
+            lua.context(|ctx| {
+                let (status, body, allowed): (i64, String, Vec<String>) = ctx
+                    .load(
+                        r#"
+                        local app = reluax.router()
+                            :get("/users/{id}", function() return 200, "ok" end)
+                            :put("/users/{id}", function() return 200, "ok" end)
+                        return app:match("/users/42", "POST")
+                        "#,
+                    )
+                    .eval()
+                    .unwrap();
+
+                assert_eq!(status, 405);
+                assert_eq!(body, "Method Not Allowed");
+                assert_eq!(allowed, vec!["GET", "PUT"]);
+            });
+        }
+
+        #[test]
+        fn flash() {
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                let messages = ["Saved!".to_string(), "With - and ; in it".to_string()];
+                let set_cookie = super::flash(ctx, messages.iter().cloned().collect()).unwrap();
+                let cookie = set_cookie.split(';').next().unwrap();
+
+                // the next request carries the cookie
+                let headers = ctx.create_table().unwrap();
+                headers
+                    .set("cookie", format!("other=1; {}", cookie))
+                    .unwrap();
+                let (res, clear) = super::flashes(ctx, headers).unwrap();
+                let res: Vec<String> = res
+                    .sequence_values::<String>()
+                    .map(|m| m.unwrap())
+                    .collect();
+                assert_eq!(res, messages);
+                assert!(clear.unwrap().contains("Max-Age=0"));
+
+                // after clearing, the cookie is gone
+                let headers = ctx.create_table().unwrap();
+                headers.set("cookie", "other=1").unwrap();
+                let (res, clear) = super::flashes(ctx, headers).unwrap();
+                assert_eq!(res.len().unwrap(), 0);
+                assert!(clear.is_none());
+
+                // tampered cookies are ignored
+                let tampered = cookie.replacen('=', "=00", 1);
+                let headers = ctx.create_table().unwrap();
+                headers.set("cookie", tampered).unwrap();
+                let (res, _) = super::flashes(ctx, headers).unwrap();
+                assert_eq!(res.len().unwrap(), 0);
+            });
+        }
+
+        #[test]
+        fn paginate() {
+            // (page, expected page, expected items, has_prev, has_next)
+            let cases = vec![
+                (Some(1), 1, vec![1, 2, 3], false, true),
+                (Some(2), 2, vec![4, 5, 6], true, true),
+                (Some(4), 4, vec![10], true, false),
+                (Some(7), 4, vec![10], true, false),
+                (Some(-1), 1, vec![1, 2, 3], false, true),
+                (None, 1, vec![1, 2, 3], false, true),
+            ];
+
+            let lua = Lua::new();
+
+            for (page, expected_page, expected_items, has_prev, has_next) in cases {
+                lua.context(|ctx| {
+                    let items = ctx.create_sequence_from(1..=10).unwrap();
+                    let res = super::paginate(ctx, (items, page, Some(3))).unwrap();
+
+                    let items: Vec<i64> = res
+                        .get::<_, rlua::Table>("items")
+                        .unwrap()
+                        .sequence_values::<i64>()
+                        .map(|i| i.unwrap())
+                        .collect();
+                    let pages: Vec<i64> = res
+                        .get::<_, rlua::Table>("pages")
+                        .unwrap()
+                        .sequence_values::<i64>()
+                        .map(|i| i.unwrap())
+                        .collect();
+
+                    assert_eq!(res.get::<_, i64>("page").unwrap(), expected_page);
+                    assert_eq!(items, expected_items, "items of page {:?}", page);
+                    assert_eq!(res.get::<_, i64>("total_pages").unwrap(), 4);
+                    assert_eq!(pages, vec![1, 2, 3, 4]);
+                    assert_eq!(res.get::<_, bool>("has_prev").unwrap(), has_prev);
+                    assert_eq!(res.get::<_, bool>("has_next").unwrap(), has_next);
+                });
+            }
+        }
+
+        #[test]
+        fn paginate_empty() {
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                let items = ctx.create_table().unwrap();
+                let res = super::paginate(ctx, (items, Some(3), None)).unwrap();
+
+                assert_eq!(res.get::<_, i64>("page").unwrap(), 1);
+                assert_eq!(res.get::<_, i64>("total_pages").unwrap(), 1);
+                assert!(!res.get::<_, bool>("has_next").unwrap());
+                assert!(!res.get::<_, bool>("has_prev").unwrap());
+            });
+        }
+
+        #[test]
+        fn paginate_huge_page_size() {
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                let items = ctx.create_sequence_from(1..=10).unwrap();
+                let res = super::paginate(ctx, (items, Some(i64::MAX), Some(i64::MAX))).unwrap();
+
+                let items: Vec<i64> = res
+                    .get::<_, rlua::Table>("items")
+                    .unwrap()
+                    .sequence_values::<i64>()
+                    .map(|i| i.unwrap())
+                    .collect();
+                assert_eq!(items, (1..=10).collect::<Vec<_>>());
+                assert_eq!(res.get::<_, i64>("page").unwrap(), 1);
+                assert_eq!(res.get::<_, i64>("per_page").unwrap(), i64::MAX);
+                assert_eq!(res.get::<_, i64>("total_pages").unwrap(), 1);
+                assert!(!res.get::<_, bool>("has_next").unwrap());
+            });
+        }
+
+        #[test]
+        fn parse_form() {
+            let cases = vec![
+                ("a=1&b=2", vec![("a", "1"), ("b", "2")]),
+                ("", vec![]),
+                ("name=J%C3%B3zef+Nowak", vec![("name", "Józef Nowak")]),
+                ("a%2Bb=1%2B1%3D2", vec![("a+b", "1+1=2")]),
+                (
+                    "flag&empty=&&x=y",
+                    vec![("flag", ""), ("empty", ""), ("x", "y")],
+                ),
+            ];
+
+            let lua = Lua::new();
+
+            for (body, expected) in cases {
+                lua.context(|ctx| {
+                    let body = ctx.create_string(body).unwrap();
+                    let form = super::parse_form(ctx, Some(body)).unwrap();
+
+                    let mut res: Vec<(String, String)> = form
+                        .pairs::<String, String>()
+                        .map(|pair| pair.unwrap())
+                        .collect();
+                    res.sort();
+
+                    let mut expected: Vec<(String, String)> = expected
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect();
+                    expected.sort();
+
+                    assert_eq!(res, expected, "parsing {}", body);
+                });
+            }
+        }
+
+        #[test]
+        fn parse_form_repeated_keys() {
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                let body = ctx.create_string("tag=a&name=x&tag=b+c&tag=d").unwrap();
+                let form = super::parse_form(ctx, Some(body)).unwrap();
+
+                let tags: Vec<String> = form
+                    .get::<_, Table>("tag")
+                    .unwrap()
+                    .sequence_values::<String>()
+                    .map(|s| s.unwrap())
+                    .collect();
+
+                assert_eq!(tags, vec!["a", "b c", "d"]);
+                assert_eq!(form.get::<_, String>("name").unwrap(), "x");
+            });
+        }
+
+        #[test]
+        fn parse_form_binary() {
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                let body = ctx.create_string(b"raw=\xff\xfe&escaped=%FF%00").unwrap();
+                let form = super::parse_form(ctx, Some(body)).unwrap();
+
+                let raw: rlua::String = form.get("raw").unwrap();
+                assert_eq!(raw.as_bytes(), b"\xff\xfe");
+                let escaped: rlua::String = form.get("escaped").unwrap();
+                assert_eq!(escaped.as_bytes(), b"\xff\x00");
+            });
+        }
+
+        #[test]
+        fn parse_multipart() {
+            let lua = Lua::new();
+
+            let mut body = Vec::new();
+            body.extend_from_slice(b"preamble\r\n--XyZ\r\n");
+            body.extend_from_slice(b"Content-Disposition: form-data; name=\"title\"\r\n\r\n");
+            body.extend_from_slice(b"Cat\r\n--XyZ\r\n");
+            body.extend_from_slice(
+                b"Content-Disposition: form-data; name=\"photo\"; filename=\"cat.png\"\r\n",
+            );
+            body.extend_from_slice(b"Content-Type: image/png\r\n\r\n");
+            body.extend_from_slice(b"\x89PNG\r\n\x1a\n\x00\xff");
+            body.extend_from_slice(b"\r\n--XyZ\r\n");
+            body.extend_from_slice(b"Content-Disposition: form-data; name=\"tag\"\r\n\r\na");
+            body.extend_from_slice(b"\r\n--XyZ\r\n");
+            body.extend_from_slice(b"Content-Disposition: form-data; name=\"tag\"\r\n\r\nb");
+            body.extend_from_slice(b"\r\n--XyZ--\r\n");
+
+            lua.context(|ctx| {
+                let body = ctx.create_string(&body).unwrap();
+                let content_type = "multipart/form-data; boundary=\"XyZ\"".to_string();
+                let form = super::parse_multipart(ctx, (body, content_type)).unwrap();
+
+                assert_eq!(form.get::<_, String>("title").unwrap(), "Cat");
+
+                let photo: rlua::Table = form.get("photo").unwrap();
+                assert_eq!(photo.get::<_, String>("filename").unwrap(), "cat.png");
+                assert_eq!(photo.get::<_, String>("content_type").unwrap(), "image/png");
+                let data: rlua::String = photo.get("data").unwrap();
+                assert_eq!(data.as_bytes(), b"\x89PNG\r\n\x1a\n\x00\xff");
+
+                let tags: Vec<String> = form.get("tag").unwrap();
+                assert_eq!(tags, vec!["a", "b"]);
+
+                let body = ctx.create_string("").unwrap();
+                assert!(
+                    super::parse_multipart(ctx, (body, "multipart/form-data".to_string())).is_err()
+                );
+            });
+        }
+
+        #[test]
+        fn body_bytes() {
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                let bytes = |from, to| -> Vec<u8> {
+                    let body = ctx.create_string(b"\x00\x01\xff\x10").unwrap();
+                    super::body_bytes(ctx, (body, from, to))
+                        .unwrap()
+                        .sequence_values::<u8>()
+                        .map(|b| b.unwrap())
+                        .collect()
+                };
+
+                assert_eq!(bytes(None, None), vec![0, 1, 255, 16]);
+                assert_eq!(bytes(Some(2), Some(3)), vec![1, 255]);
+                assert_eq!(bytes(Some(-2), None), vec![255, 16]);
+                assert_eq!(bytes(Some(3), Some(2)), vec![]);
+                assert_eq!(bytes(Some(0), Some(10)), vec![0, 1, 255, 16]);
+            });
+        }
+
+        #[test]
+        fn segments() {
+            let cases = vec![
+                ("/a/b/c", vec!["a", "b", "c"]),
+                ("/", vec![]),
+                ("", vec![]),
+                ("/a//b/", vec!["a", "b"]),
+                (
+                    "/hello%20world/%C5%BC%C3%B3%C5%82w",
+                    vec!["hello world", "żółw"],
+                ),
+                ("/100%/%zz", vec!["100%", "%zz"]),
+            ];
+
+            let lua = Lua::new();
+
+            for (path, expected) in cases {
+                lua.context(|ctx| {
+                    let res: Vec<String> = super::segments(ctx, path.to_string())
+                        .unwrap()
+                        .sequence_values::<String>()
+                        .map(|s| s.unwrap())
+                        .collect();
+
+                    assert_eq!(res, expected, "segments of {}", path);
+                });
+            }
+        }
+
+        #[test]
+        fn expect_content_type() {
+            let cases = vec![
+                (Some("application/json"), "application/json", true),
+                (
+                    Some("application/json; charset=utf-8"),
+                    "application/json",
+                    true,
+                ),
+                (
+                    Some("Application/JSON;charset=UTF-8"),
+                    "application/json",
+                    true,
+                ),
+                (
+                    Some("application/json"),
+                    "application/json; charset=utf-8",
+                    true,
+                ),
+                (
+                    Some("application/x-www-form-urlencoded"),
+                    "application/json",
+                    false,
+                ),
+                (Some("text/plain"), "text/html", false),
+                (None, "application/json", false),
+            ];
+
+            let lua = Lua::new();
+
+            for (content_type, expected, matches) in cases {
+                lua.context(|ctx| {
+                    let headers = ctx.create_table().unwrap();
+                    headers.set("content-type", content_type).unwrap();
+
+                    let res =
+                        super::expect_content_type(ctx, (headers, expected.to_string())).unwrap();
+
+                    assert_eq!(
+                        res, matches,
+                        "content type {:?} against {}",
+                        content_type, expected
+                    );
+                });
+            }
+        }
+
+        #[test]
+        fn date() {
+            let cases = vec![
+                (0, None, None, "1970-01-01 00:00:00"),
+                (1700000000, None, None, "2023-11-14 22:13:20"),
+                (1700000000, Some("%d.%m.%Y %H:%M"), None, "14.11.2023 22:13"),
+                (1700000000, None, Some("UTC"), "2023-11-14 22:13:20"),
+                (1700000000, None, Some("Asia/Tokyo"), "2023-11-15 07:13:20"),
+                (
+                    1700000000,
+                    Some("%H:%M %Z"),
+                    Some("Europe/Warsaw"),
+                    "23:13 CET",
+                ),
+            ];
+
+            let lua = Lua::new();
+
+            for (timestamp, format, tz, expected) in cases {
+                let res = lua
+                    .context(|ctx| {
+                        super::date(
+                            ctx,
+                            (
+                                timestamp,
+                                format.map(str::to_string),
+                                tz.map(str::to_string),
+                            ),
+                        )
+                    })
+                    .unwrap();
+
+                assert_eq!(res, expected, "formatting {} in {:?}", timestamp, tz);
+            }
+        }
+
+        #[test]
+        fn date_errors() {
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                assert!(super::date(ctx, (0, None, Some("Mars/Olympus".to_string()))).is_err());
+                assert!(super::date(ctx, (0, Some("%Q".to_string()), None)).is_err());
+            });
+        }
+
+        #[test]
+        fn h() {
+            let lua = crate::luax::prepare_lua(false).unwrap();
+
+            lua.context(|ctx| {
+                let tree: rlua::Table = ctx
+                    .load(
+                        r#"
+                        local h = reluax.h
+                        local items = {}
+                        for i, name in ipairs({ "one", "two" }) do
+                            items[i] = h("li", { id = "item-" .. i }, name)
+                        end
+                        return h("ul", { class = "list" }, {
+                            items,
+                            h("li", nil, h("b", nil, "three")),
+                            h("li"),
+                        })
+                        "#,
+                    )
+                    .eval()
+                    .unwrap();
+
+                let mut html = Vec::new();
+                crate::luax::table_to_html(tree, &mut html).unwrap();
+                assert_eq!(
+                    String::from_utf8(html).unwrap(),
+                    "<ul class=\"list\"><li id=\"item-1\">one</li><li id=\"item-2\">two</li><li><b>three</b></li><li></li></ul>"
+                );
+
+                assert!(ctx.load(r#"reluax.h("")"#).exec().is_err());
+            });
+        }
+
+        #[test]
+        fn html_page_options() {
+            let lua = crate::luax::prepare_lua(false).unwrap();
+
+            lua.context(|ctx| {
+                let render = |src: &str| {
+                    let page: rlua::Table = ctx.load(src).eval().unwrap();
+                    let mut html = Vec::new();
+                    crate::luax::table_to_html(page.clone(), &mut html).unwrap();
+                    (
+                        String::from_utf8(html).unwrap(),
+                        page.get::<_, String>("type").unwrap(),
+                        page.get::<_, Option<String>>("doctype").unwrap(),
+                    )
+                };
+
+                assert_eq!(
+                    render(r#"return reluax.html_page(reluax.h("html", nil, "hi"))"#),
+                    ("<html>hi</html>".to_string(), "html-page".to_string(), None)
+                );
+                assert_eq!(
+                    render(r#"return reluax.html_page(reluax.h("html", nil, "hi"), { lang = "pl" })"#),
+                    (
+                        "<html lang=\"pl\">hi</html>".to_string(),
+                        "html-page".to_string(),
+                        None
+                    )
+                );
+                assert_eq!(
+                    render(
+                        r#"return reluax.html_page(reluax.h("body", nil, "hi"), { lang = "en", doctype = "html SYSTEM \"about:legacy-compat\"" })"#
+                    ),
+                    (
+                        "<html lang=\"en\"><body>hi</body></html>".to_string(),
+                        "html-page".to_string(),
+                        Some("html SYSTEM \"about:legacy-compat\"".to_string())
+                    )
+                );
+            });
+        }
+
+        #[test]
+        fn url_encode_and_decode() {
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                let cases: Vec<(&[u8], &str)> = vec![
+                    (&b"hello world"[..], "hello+world"),
+                    (&b"a+b=c&d"[..], "a%2Bb%3Dc%26d"),
+                    (&b"/path?q=1#frag"[..], "%2Fpath%3Fq%3D1%23frag"),
+                    (&b"safe*-._~"[..], "safe*-._%7E"),
+                    ("Józef".as_bytes(), "J%C3%B3zef"),
+                    (&b"\xff\x00"[..], "%FF%00"),
+                    (&b""[..], ""),
+                ];
+
+                for (raw, encoded) in cases {
+                    let s = ctx.create_string(raw).unwrap();
+                    assert_eq!(super::url_encode(ctx, s).unwrap(), encoded);
+
+                    let s = ctx.create_string(encoded).unwrap();
+                    assert_eq!(super::url_decode(ctx, s).unwrap().as_bytes(), raw);
+                }
+
+                // `%20` is a space too, and invalid escapes are kept as they are
+                let s = ctx.create_string("a%20b+c%zz%4").unwrap();
+                assert_eq!(super::url_decode(ctx, s).unwrap().as_bytes(), b"a b c%zz%4");
+            });
+        }
+
+        #[test]
+        fn base64() {
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                let encode = |bytes: &[u8], alphabet: Option<&str>| {
+                    let s = ctx.create_string(bytes).unwrap();
+                    super::base64_encode(ctx, (s, alphabet.map(str::to_string))).unwrap()
+                };
+                let decode = |s: &str, alphabet: Option<&str>| {
+                    super::base64_decode(ctx, (s.to_string(), alphabet.map(str::to_string)))
+                        .unwrap()
+                        .map(|s| s.as_bytes().to_vec())
+                };
+
+                let cases: Vec<(&[u8], &str)> = vec![
+                    (&b""[..], ""),
+                    (&b"f"[..], "Zg=="),
+                    (&b"fo"[..], "Zm8="),
+                    (&b"foo"[..], "Zm9v"),
+                    (&b"foobar"[..], "Zm9vYmFy"),
+                    (&b"\xff\xfe\x00\x80"[..], "//4AgA=="),
+                ];
+                for (raw, encoded) in cases {
+                    assert_eq!(encode(raw, None), encoded);
+                    assert_eq!(decode(encoded, None).unwrap(), raw);
+                    assert_eq!(decode(encoded.trim_end_matches('='), None).unwrap(), raw);
+                }
+
+                assert_eq!(encode(b"\xff\xfe\x00\x80", Some("url")), "__4AgA");
+                assert_eq!(decode("__4AgA", Some("url")).unwrap(), b"\xff\xfe\x00\x80");
+                assert_eq!(decode("__4AgA", None), None);
+                assert_eq!(decode("//4AgA==", Some("url")), None);
+                assert_eq!(decode("Zm9v!", None), None);
+                assert_eq!(decode("Zm9vY", None), None);
+
+                let s = ctx.create_string("x").unwrap();
+                assert!(super::base64_encode(ctx, (s, Some("hex".to_string()))).is_err());
+            });
+        }
+
+        #[test]
+        fn sign_and_verify() {
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                let secret = || Some("s3cret".to_string());
+
+                let signed = super::sign(ctx, ("user=42".to_string(), secret())).unwrap();
+                assert!(signed.starts_with("user=42."));
+                assert_eq!(
+                    super::verify(ctx, (signed.clone(), secret())).unwrap(),
+                    Some("user=42".to_string())
+                );
+
+                // a different secret, or the process-wide one
+                assert_eq!(
+                    super::verify(ctx, (signed.clone(), Some("other".to_string()))).unwrap(),
+                    None
+                );
+                assert_eq!(super::verify(ctx, (signed.clone(), None)).unwrap(), None);
+
+                // tampering with the value or the signature
+                let tampered = signed.replacen("42", "43", 1);
+                assert_eq!(super::verify(ctx, (tampered, secret())).unwrap(), None);
+                let mut truncated = signed.clone();
+                truncated.pop();
+                assert_eq!(super::verify(ctx, (truncated, secret())).unwrap(), None);
+                assert_eq!(
+                    super::verify(ctx, ("user=42".to_string(), secret())).unwrap(),
+                    None
+                );
+
+                let signed = super::sign(ctx, ("a.b".to_string(), None)).unwrap();
+                assert_eq!(
+                    super::verify(ctx, (signed, None)).unwrap(),
+                    Some("a.b".to_string())
+                );
+            });
+        }
+
+        #[test]
+        fn csrf() {
+            let key = b"s3cret";
+            let now = 1_700_000_000;
+
+            let token = super::csrf_token_at(key, "session-1", now);
+            assert!(token.starts_with("1700000000."));
+            assert!(super::csrf_check_at(
+                key,
+                &token,
+                "session-1",
+                3600,
+                now + 60
+            ));
+
+            // another session, another secret, or a forged time
+            assert!(!super::csrf_check_at(key, &token, "session-2", 3600, now));
+            assert!(!super::csrf_check_at(
+                b"other",
+                &token,
+                "session-1",
+                3600,
+                now
+            ));
+            let forged = token.replacen("1700000000", "1700000001", 1);
+            assert!(!super::csrf_check_at(
+                key,
+                &forged,
+                "session-1",
+                3600,
+                now + 1
+            ));
+            assert!(!super::csrf_check_at(
+                key,
+                "garbage",
+                "session-1",
+                3600,
+                now
+            ));
+
+            // a value signed with `reluax.sign` and the same secret
+            let signed = super::signing::sign_with(key, "1700000000:session-1");
+            let (_, signature) = signed.rsplit_once('.').unwrap();
+            assert!(!super::csrf_check_at(
+                key,
+                &format!("1700000000.{}", signature),
+                "session-1",
+                3600,
+                now
+            ));
+
+            // expired, or from the future
+            assert!(super::csrf_check_at(
+                key,
+                &token,
+                "session-1",
+                3600,
+                now + 3600
+            ));
+            assert!(!super::csrf_check_at(
+                key,
+                &token,
+                "session-1",
+                3600,
+                now + 3601
+            ));
+            assert!(!super::csrf_check_at(
+                key,
+                &token,
+                "session-1",
+                3600,
+                now - 1
+            ));
+
+            let lua = Lua::new();
+            lua.context(|ctx| {
+                let token = super::csrf_token(ctx, (None, "session-1".to_string())).unwrap();
+                let check = |token: Option<&str>, max_age| {
+                    super::csrf_check(
+                        ctx,
+                        (
+                            token.map(str::to_string),
+                            None,
+                            "session-1".to_string(),
+                            max_age,
+                        ),
+                    )
+                    .unwrap()
+                };
+                assert!(check(Some(token.as_str()), None));
+                assert!(!check(None, None));
+            });
+        }
+
+        #[test]
+        fn basic_auth() {
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                let check = |authorization: Option<&str>, user: &str, password: &str| {
+                    let headers = ctx.create_table().unwrap();
+                    if let Some(authorization) = authorization {
+                        headers.set("Authorization", authorization).unwrap();
+                    }
+                    super::check_basic_auth(ctx, (headers, user.to_string(), password.to_string()))
+                        .unwrap()
+                };
+
+                // admin:hunter2
+                let valid = "Basic YWRtaW46aHVudGVyMg==";
+                assert!(check(Some(valid), "admin", "hunter2"));
+                assert!(check(Some("basic YWRtaW46aHVudGVyMg"), "admin", "hunter2"));
+                assert!(!check(Some(valid), "admin", "hunter3"));
+                assert!(!check(Some(valid), "root", "hunter2"));
+                assert!(!check(
+                    Some("Bearer YWRtaW46aHVudGVyMg=="),
+                    "admin",
+                    "hunter2"
+                ));
+                assert!(!check(Some("Basic not base64!"), "admin", "hunter2"));
+                // admin, without a password
+                assert!(!check(Some("Basic YWRtaW4="), "admin", ""));
+                assert!(!check(None, "admin", "hunter2"));
+            });
+        }
+
+        #[test]
+        fn bearer() {
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                let check = |authorization: &str, token: &str| {
+                    let headers = ctx.create_table().unwrap();
+                    headers.set("authorization", authorization).unwrap();
+                    super::check_bearer(ctx, (headers, token.to_string())).unwrap()
+                };
+
+                assert!(check("Bearer s3cret", "s3cret"));
+                assert!(check("bearer  s3cret ", "s3cret"));
+                assert!(!check("Bearer s3cre", "s3cret"));
+                assert!(!check("Bearer ", ""));
+                assert!(!check("Basic s3cret", "s3cret"));
+                assert!(!check("s3cret", "s3cret"));
+            });
+        }
+
+        #[test]
+        fn redirect() {
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                let t = super::redirect(ctx, ("/login".to_string(), None)).unwrap();
+                assert_eq!(t.get::<_, String>("type").unwrap(), "redirect");
+                assert_eq!(t.get::<_, String>("location").unwrap(), "/login");
+                assert_eq!(t.get::<_, u16>("status").unwrap(), 302);
+
+                let t = super::redirect(ctx, ("/new".to_string(), Some(308))).unwrap();
+                assert_eq!(t.get::<_, u16>("status").unwrap(), 308);
+
+                assert!(super::redirect(ctx, ("/".to_string(), Some(200))).is_err());
+            });
+        }
+
+        #[test]
+        fn static_file() {
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                let t = super::static_file(ctx, None).unwrap();
+                assert_eq!(t.get::<_, String>("type").unwrap(), "static");
+                assert_eq!(t.get::<_, Option<String>>("path").unwrap(), None);
+
+                let t = super::static_file(ctx, Some("/docs/".to_string())).unwrap();
+                assert_eq!(t.get::<_, String>("path").unwrap(), "/docs/");
+            });
+        }
+
+        #[test]
+        fn read_file() {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::create_dir(dir.path().join("data")).unwrap();
+            std::fs::write(dir.path().join("data/items.json"), b"[1, 2]\xff").unwrap();
+
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                let read = |path: &str| {
+                    super::read_file(ctx, path.to_string(), dir.path())
+                        .map(|s| s.map(|s| s.as_bytes().to_vec()))
+                };
+
+                assert_eq!(read("data/items.json").unwrap().unwrap(), b"[1, 2]\xff");
+                assert_eq!(read("./data/items.json").unwrap().unwrap(), b"[1, 2]\xff");
+                assert_eq!(read("data/missing.json").unwrap(), None);
+                assert_eq!(read("data").unwrap(), None);
+
+                assert!(read("../outside.txt").is_err());
+                assert!(read("data/../../outside.txt").is_err());
+                assert!(read(&dir.path().join("data/items.json").display().to_string()).is_err());
+            });
+        }
+
+        #[test]
+        fn markdown() {
+            let cases = vec![
+                ("# Hello", "<h1>Hello</h1>\n"),
+                (
+                    "Some *emphasis* and ~~strikethrough~~",
+                    "<p>Some <em>emphasis</em> and <del>strikethrough</del></p>\n",
+                ),
+                (
+                    "```lua\nprint('hi')\n```",
+                    "<pre><code class=\"language-lua\">print('hi')\n</code></pre>\n",
+                ),
+                (
+                    "| a | b |\n|---|---|\n| 1 | 2 |",
+                    "<table><thead><tr><th>a</th><th>b</th></tr></thead><tbody>\n\
+                     <tr><td>1</td><td>2</td></tr>\n</tbody></table>\n",
+                ),
+            ];
+
+            let lua = Lua::new();
+
+            for (source, expected) in cases {
+                lua.context(|ctx| {
+                    let node = super::markdown(ctx, source.to_string()).unwrap();
+
+                    let mut html = Vec::new();
+                    crate::luax::table_to_html(node, &mut html).unwrap();
+
+                    assert_eq!(
+                        String::from_utf8(html).unwrap(),
+                        expected,
+                        "rendering {}",
+                        source
+                    );
+                });
+            }
+        }
+
+        #[test]
+        fn markdown_as_child() {
+            let lua = Lua::new();
+
+            lua.context(|ctx| {
+                let article = ctx.create_table().unwrap();
+                article.set("tag", "article").unwrap();
+                article
+                    .set(
+                        "children",
+                        vec![super::markdown(ctx, "**bold**".to_string()).unwrap()],
+                    )
+                    .unwrap();
+
+                let mut html = Vec::new();
+                crate::luax::table_to_html(article, &mut html).unwrap();
+
+                assert_eq!(
+                    String::from_utf8(html).unwrap(),
+                    "<article><p><strong>bold</strong></p>\n</article>"
+                );
+            });
+        }
+
+        #[test]
+        fn env() {
+            std::env::set_var("RELUAX_TEST_ENV", "secret");
+
+            let read = |dev_mode: bool, manifest: &str| -> Option<String> {
+                let lua = crate::luax::prepare_lua(dev_mode).unwrap();
+                lua.context(|ctx| {
+                    ctx.load(&format!("package.loaded['reluax'] = {}", manifest))
+                        .exec()
+                        .unwrap();
+                    ctx.load("return reluax.env('RELUAX_TEST_ENV')")
+                        .eval()
+                        .unwrap()
+                })
+            };
+
+            assert_eq!(read(true, "{}"), Some("secret".to_string()));
+            assert_eq!(read(false, "{}"), None);
+            assert_eq!(read(false, "{ env = { 'OTHER' } }"), None);
+            assert_eq!(
+                read(false, "{ env = { 'OTHER', 'RELUAX_TEST_ENV' } }"),
+                Some("secret".to_string())
+            );
+
+            // changing the list later on doesn't allow more
+            let lua = crate::luax::prepare_lua(false).unwrap();
+            let appended: Option<String> = lua
+                .context(|ctx| {
+                    ctx.load("package.loaded['reluax'] = { env = {} }").exec()?;
+                    ctx.load("return reluax.env('RELUAX_TEST_ENV')")
+                        .eval::<Option<String>>()?;
+                    ctx.load(
+                        "table.insert(package.loaded.reluax.env, 'RELUAX_TEST_ENV')
+                        return reluax.env('RELUAX_TEST_ENV')",
+                    )
+                    .eval()
+                })
+                .unwrap();
+            assert_eq!(appended, None);
+
+            let lua = crate::luax::prepare_lua(true).unwrap();
+            let unset: Option<String> = lua
+                .context(|ctx| ctx.load("return reluax.env('RELUAX_TEST_UNSET')").eval())
+                .unwrap();
+            assert_eq!(unset, None);
+        }
+
+        #[test]
+        fn sandbox() {
+            let lua = crate::luax::prepare_sandboxed_lua(false).unwrap();
+
+            lua.context(|ctx| {
+                let missing = [
+                    "os.execute",
+                    "os.exit",
+                    "os.remove",
+                    "os.getenv",
+                    "io",
+                    "debug",
+                    "dofile",
+                    "loadfile",
+                    "package.loadlib",
+                    "package.loaded.io",
+                    "package.loaded.os.execute",
+                ];
+                for name in missing {
+                    let value: rlua::Value = ctx.load(&format!("return {}", name)).eval().unwrap();
+                    assert!(matches!(value, rlua::Value::Nil), "`{}` is available", name);
+                }
+                assert!(ctx.load("os.execute('true')").exec().is_err());
+
+                let kept: bool = ctx
+                    .load(
+                        "return type(os.time()) == 'number' and os.date('!%Y', 0) == '1970'
+                            and string.upper('a') == 'A' and table.concat({ 'a', 'b' }) == 'ab'
+                            and math.floor(1.5) == 1 and type(require) == 'function'
+                            and type(reluax.html) == 'function'",
+                    )
+                    .eval()
+                    .unwrap();
+                assert!(kept);
+            });
+
+            // the usual states have everything but `debug`
+            let lua = crate::luax::prepare_lua(false).unwrap();
+            let full: bool = lua
+                .context(|ctx| ctx.load("return os.execute ~= nil and io ~= nil").eval())
+                .unwrap();
+            assert!(full);
+        }
+
+        #[test]
+        fn merge_classes() {
+            let cases = vec![
+                (Some("a b"), Some("c d"), "a b c d"),
+                (Some("a b"), Some("b c"), "a b c"),
+                (Some("p-4 m-2"), Some("p-2"), "m-2 p-2"),
+                (Some("px-4 py-2"), Some("px-2"), "py-2 px-2"),
+                (
+                    Some("text-lg font-bold"),
+                    Some("text-sm"),
+                    "font-bold text-sm",
+                ),
+                (Some("p-4 hover:p-6"), Some("hover:p-8"), "p-4 hover:p-8"),
+                (Some("flex  p-4 "), None, "flex p-4"),
+                (Some("flex flex-col"), None, "flex flex-col"),
+                (Some("text-center text-lg"), None, "text-center text-lg"),
+                (None, Some("text-center text-lg"), "text-center text-lg"),
+                (
+                    Some("flex flex-col text-sm"),
+                    Some("text-center text-lg"),
+                    "flex flex-col text-center text-lg",
+                ),
+                (None, Some("p-4"), "p-4"),
+                (None, None, ""),
+            ];
+
+            let lua = Lua::new();
+
+            for (base, overrides, expected) in cases {
+                let res = lua
+                    .context(|ctx| {
+                        super::merge_classes(
+                            ctx,
+                            (base.map(str::to_string), overrides.map(str::to_string)),
+                        )
+                    })
+                    .unwrap();
+                assert_eq!(res, expected, "merging {:?} with {:?}", base, overrides);
+            }
+        }
     }
 }
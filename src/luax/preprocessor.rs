@@ -1,7 +1,7 @@
 use std::io::Write;
-use std::sync::OnceLock;
+use std::path::{Path, PathBuf};
 
-use crate::error::LuaXError;
+use crate::error::{LuaXError, PreprocessError};
 
 use crate::luax::lexer::Lexer;
 use crate::luax::tokens::Token;
@@ -73,33 +73,202 @@ macro_rules! require {
 pub struct Preprocessor<'s, W: Write> {
     lexer: Lexer<'s>,
     current: Token<'s>,
-    out_stream: W,
+    out_stream: Output<W>,
     first_token: bool,
+    // the input line the output has caught up to, to keep the line breaks
+    output_line: usize,
+    // HTML validation state
+    html_depth: usize,
+    page_ids: Vec<String>,
+    problems: Vec<LuaXError>,
+    // slot state, with the named slots of each component being emitted
+    slots: Vec<Vec<(String, Vec<u8>)>>,
+    slot_allowed: bool,
+    emitted_slot: bool,
+    // whitespace is kept as is inside of `<pre>`
+    pre_depth: usize,
+    // the elements whose children are being emitted, with where they start
+    open_tags: Vec<(String, (usize, usize))>,
+    // the file being preprocessed last, after the files which include it
+    includes: Vec<PathBuf>,
 }
 
+/// The output of the preprocessor, which can be captured into a buffer for a
+/// while, to be written out later in a different place
+struct Output<W: Write> {
+    stream: W,
+    captures: Vec<Vec<u8>>,
+    // source map state, with the input line of the token being written, and
+    // how many lines of it were written so far
+    source_map: SourceMap,
+    input_line: usize,
+    newlines: usize,
+    line_start: bool,
+}
+
+impl<W: Write> Output<W> {
+    fn set_input_line(&mut self, line: usize) {
+        self.input_line = line;
+        self.newlines = 0;
+    }
+
+    /// Map each output line to the input line of the first thing written on
+    /// it, counting the lines within tokens written as they are, like long
+    /// strings
+    fn track_lines(&mut self, buf: &[u8]) {
+        for &b in buf {
+            if b == b'\n' {
+                if self.line_start {
+                    self.source_map.lines.push(self.input_line + self.newlines);
+                }
+                self.newlines += 1;
+                self.line_start = true;
+            } else if self.line_start {
+                self.source_map.lines.push(self.input_line + self.newlines);
+                self.line_start = false;
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for Output<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.captures.last_mut() {
+            Some(capture) => capture.write(buf),
+            None => {
+                let written = self.stream.write(buf)?;
+                self.track_lines(&buf[..written]);
+                Ok(written)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// A mapping from the lines of preprocessed Lua back to the lines of the LuaX
+/// it came from, to make sense of the line numbers in Lua errors
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    // the input line of each output line, both counted from 1
+    lines: Vec<usize>,
+}
+
+impl SourceMap {
+    /// The line of the input which an output line came from
+    pub fn input_line(&self, output_line: usize) -> Option<usize> {
+        self.lines.get(output_line.checked_sub(1)?).copied()
+    }
+}
+
+/// Collapse runs of whitespace in HTML text into a single space, dropping the
+/// whitespace at the ends of the text entirely if it contains a line break
+fn collapse_whitespace(text: &str) -> String {
+    let is_whitespace = |c: char| matches!(c, ' ' | '\t' | '\n' | '\r');
+
+    let trimmed = text.trim_matches(is_whitespace);
+    if trimmed.is_empty() {
+        return if text.is_empty() || text.contains('\n') {
+            String::new()
+        } else {
+            " ".to_string()
+        };
+    }
+
+    let leading = &text[..text.len() - text.trim_start_matches(is_whitespace).len()];
+    let trailing = &text[text.trim_end_matches(is_whitespace).len()..];
+
+    let mut collapsed = String::with_capacity(text.len());
+    if !leading.is_empty() && !leading.contains('\n') {
+        collapsed.push(' ');
+    }
+
+    let mut last_was_whitespace = false;
+    for c in trimmed.chars() {
+        if is_whitespace(c) {
+            if !last_was_whitespace {
+                collapsed.push(' ');
+            }
+            last_was_whitespace = true;
+        } else {
+            collapsed.push(c);
+            last_was_whitespace = false;
+        }
+    }
+
+    if !trailing.is_empty() && !trailing.contains('\n') {
+        collapsed.push(' ');
+    }
+
+    collapsed
+}
+
+/// Elements which can never have children, and must not have a closing tag
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
 impl<'s, W: Write> Preprocessor<'s, W> {
-    pub fn new(template: &'s str, out_stream: W) -> Result<Self> {
+    /// Make a preprocessor for a template, read from `path` if it's a file, which
+    /// `<include src="...">`s are resolved relative to
+    pub fn new(template: &'s str, path: Option<&Path>, out_stream: W) -> Result<Self> {
         let mut lexer = Lexer::new(template);
         let current = lexer.next_token()?.unwrap();
+        let input_line = lexer.token_line();
         Ok(Preprocessor {
             lexer,
             current,
-            out_stream,
+            out_stream: Output {
+                stream: out_stream,
+                captures: Vec::new(),
+                source_map: SourceMap::default(),
+                input_line,
+                newlines: 0,
+                line_start: true,
+            },
             first_token: true,
+            output_line: 1,
+            html_depth: 0,
+            page_ids: Vec::new(),
+            problems: Vec::new(),
+            slots: Vec::new(),
+            slot_allowed: false,
+            emitted_slot: false,
+            pre_depth: 0,
+            open_tags: Vec::new(),
+            includes: path
+                .map(|path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf()))
+                .into_iter()
+                .collect(),
         })
     }
 
     fn next_token(&mut self) -> Result<()> {
         if self.current != Token::Eof {
-            if !self.first_token {
+            let line = self.out_stream.input_line;
+            if line > self.output_line {
+                // break lines where the input does, so the output mirrors it
+                for output_line in self.output_line..line {
+                    self.out_stream.set_input_line(output_line);
+                    writeln!(self.out_stream)?;
+                }
+                self.out_stream.set_input_line(line);
+            } else if !self.first_token {
                 write!(self.out_stream, " ")?;
             }
-            write!(self.out_stream, "{}", self.current)?;
+
+            let token = self.current.to_string();
+            write!(self.out_stream, "{}", token)?;
+            self.output_line = line + token.matches('\n').count();
             self.first_token = false;
         }
         match self.lexer.next_token()? {
             Some(token) => {
                 self.current = token;
+                self.out_stream.set_input_line(self.lexer.token_line());
             }
             None => return Err(LuaXError::InvalidStart.into()),
         }
@@ -128,6 +297,7 @@ impl<'s, W: Write> Preprocessor<'s, W> {
         match self.lexer.next_token()? {
             Some(token) => {
                 self.current = token;
+                self.out_stream.set_input_line(self.lexer.token_line());
             }
             None => return Err(LuaXError::InvalidStart.into()),
         }
@@ -152,8 +322,31 @@ impl<'s, W: Write> Preprocessor<'s, W> {
         }
     }
 
-    pub fn preprocess(mut self) -> Result<()> {
-        self.chunk()
+    /// Preprocess the whole template, returning the HTML validation problems
+    /// which could be found statically
+    pub fn preprocess(self) -> Result<Vec<LuaXError>> {
+        Ok(self.preprocess_mapped()?.0)
+    }
+
+    /// Preprocess the whole template, also returning the source map of the
+    /// output
+    ///
+    /// Errors are returned as a `PreprocessError` with the position of the
+    /// token the preprocessor stopped at, its `output` is left to the caller.
+    pub fn preprocess_mapped(mut self) -> Result<(Vec<LuaXError>, SourceMap)> {
+        if let Err(error) = self.chunk() {
+            let (line, column) = self.lexer.token_position();
+
+            return Err(PreprocessError {
+                error,
+                output: String::new(),
+                line,
+                column,
+            }
+            .into());
+        }
+
+        Ok((self.problems, self.out_stream.source_map))
     }
 
     fn chunk(&mut self) -> Result<()> {
@@ -512,6 +705,11 @@ impl<'s, W: Write> Preprocessor<'s, W> {
             return Err(LuaXError::InvalidStart.into());
         }
 
+        self.assignment_followup()
+    }
+
+    /// The rest of an assignment after its first variable, if there is one
+    fn assignment_followup(&mut self) -> Result<()> {
         if self.match_token(Token::Eq)? {
             require!(self.explist(), LuaXError::ExpectedExpression,);
         } else if self.match_token(Token::Comma)? {
@@ -724,6 +922,9 @@ impl<'s, W: Write> Preprocessor<'s, W> {
                 && !self.match_token(Token::And)?
                 && !self.match_token(Token::Or)?
             {
+                if self.current == Token::Bang {
+                    return Err(Self::not_lua_operator("!=", "~=").into());
+                }
                 break;
             }
 
@@ -734,6 +935,10 @@ impl<'s, W: Write> Preprocessor<'s, W> {
     }
 
     fn unary_expression(&mut self) -> Result<()> {
+        if self.current == Token::Bang {
+            return Err(Self::not_lua_operator("!", "not").into());
+        }
+
         if !self.match_token(Token::Not)?
             && !self.match_token(Token::Hash)?
             && !self.match_token(Token::Minus)?
@@ -747,6 +952,15 @@ impl<'s, W: Write> Preprocessor<'s, W> {
         Ok(())
     }
 
+    /// `!` is only lexed for `<!DOCTYPE>`, but people used to other languages
+    /// will write `!x` or `a != b`, so point them to the Lua way
+    fn not_lua_operator(found: &str, instead: &str) -> LuaXError {
+        LuaXError::NotLuaOperator {
+            found: found.to_string(),
+            instead: instead.to_string(),
+        }
+    }
+
     fn boolean(&mut self) -> Result<()> {
         if self.match_token(Token::True)? || self.match_token(Token::False)? {
             Ok(())
@@ -799,6 +1013,10 @@ impl<'s, W: Write> Preprocessor<'s, W> {
         }
     }
 
+    /// Parse a tag or attribute name, which is a sequence of identifiers joined
+    /// by `-`, like `my-element` or `data-id`, so it can contain the same
+    /// characters as Lua identifiers. Namespaced names like `xlink:href` are
+    /// joined by `:`. A leading `!` is allowed for `<!DOCTYPE>`.
     fn html_identifier(&mut self) -> Result<String> {
         if let Token::Bang = self.current {
             self.next_token_silent()?;
@@ -811,13 +1029,17 @@ impl<'s, W: Write> Preprocessor<'s, W> {
         } else if let Token::Identifier(s) = self.current {
             self.next_token_silent()?;
             let mut s = s.to_string();
-            // we might have - in the identifier, so we loop until we don't
+            // we might have - or : in the identifier, so we loop until we don't
             let mut last_was_id = true;
             loop {
                 if let Token::Minus = self.current {
                     s.push('-');
                     self.next_token_silent()?;
                     last_was_id = false;
+                } else if let Token::Colon = self.current {
+                    s.push(':');
+                    self.next_token_silent()?;
+                    last_was_id = false;
                 } else if let Token::Identifier(s2) = self.current {
                     if last_was_id {
                         break;
@@ -835,15 +1057,26 @@ impl<'s, W: Write> Preprocessor<'s, W> {
         }
     }
 
-    fn default_html_tag(tag: &str) -> bool {
-        static TAGS: OnceLock<Vec<&str>> = OnceLock::new();
+    /// Components are written like JSX components: their name starts with an
+    /// uppercase letter, and they are turned into a call to the Lua value of the
+    /// same name. Everything else is an element, and becomes a plain table.
+    fn is_component(tag: &str) -> bool {
+        tag.chars().next().map_or(false, |c| c.is_uppercase())
+    }
 
-        let tags = TAGS.get_or_init(|| include_str!("default_html_tags.txt").split('\n').collect());
+    fn html_template(&mut self) -> Result<()> {
+        self.html_depth += 1;
+        let res = self.html_element();
+        self.html_depth -= 1;
 
-        tags.contains(&tag)
+        res
     }
 
-    fn html_template(&mut self) -> Result<()> {
+    fn html_element(&mut self) -> Result<()> {
+        // only the direct children of a component can be its slots
+        let slot_allowed = std::mem::take(&mut self.slot_allowed);
+
+        let position = self.lexer.token_position();
         if !self.match_token_silent(Token::Lt)? {
             return Err(LuaXError::InvalidStart.into());
         }
@@ -853,10 +1086,26 @@ impl<'s, W: Write> Preprocessor<'s, W> {
             LuaXError::NeededToken("identifier".to_string())
         );
 
-        if Self::default_html_tag(&tag) {
-            write!(self.out_stream, " {{ tag=\"{}\", ", tag)?;
-        } else {
+        if slot_allowed && tag == "slot" {
+            return self.html_slot();
+        }
+
+        if tag == "include" {
+            return self.html_include();
+        }
+
+        // ids only need to be unique within a single page, which is the most we
+        // can know about statically
+        if self.html_depth == 1 {
+            self.page_ids.clear();
+        }
+
+        let component = Self::is_component(&tag);
+
+        if component {
             write!(self.out_stream, " {} ({{ ", tag)?;
+        } else {
+            write!(self.out_stream, " {{ tag=\"{}\", ", tag)?;
         }
 
         self.html_attributes()?;
@@ -864,10 +1113,10 @@ impl<'s, W: Write> Preprocessor<'s, W> {
         if self.match_token_silent(Token::Slash)? {
             self.consume_token_silent(Token::Gt, LuaXError::NeededToken(Token::Gt.to_string()))?;
             write!(self.out_stream, "children={{}}")?;
-            if Self::default_html_tag(&tag) {
-                write!(self.out_stream, " }}")?;
-            } else {
+            if component {
                 write!(self.out_stream, " }}) ")?;
+            } else {
+                write!(self.out_stream, " }}")?;
             }
             return Ok(());
         }
@@ -875,8 +1124,43 @@ impl<'s, W: Write> Preprocessor<'s, W> {
         // there might be an invalid token immediately after the tag name
         self.consume_token_silent(Token::Gt, LuaXError::NeededToken(Token::Gt.to_string()))?;
 
-        self.html_children()?;
+        if component {
+            self.slots.push(Vec::new());
+        }
 
+        write!(self.out_stream, "children=")?;
+        let pre = tag == "pre";
+        self.pre_depth += pre as usize;
+        self.open_tags.push((tag.clone(), position));
+        let children = self.html_children(component);
+        self.open_tags.pop();
+        self.pre_depth -= pre as usize;
+        let children = children?;
+
+        let slots = if component {
+            self.slots.pop().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+
+        if !slots.is_empty() {
+            write!(self.out_stream, ", slots={{")?;
+            for (name, content) in slots {
+                write!(self.out_stream, " [\"{}\"]=", name)?;
+                self.out_stream.write_all(&content)?;
+                write!(self.out_stream, ",")?;
+            }
+            write!(self.out_stream, " }}")?;
+        }
+
+        if children > 0 && VOID_ELEMENTS.contains(&tag.as_str()) {
+            self.problems.push(LuaXError::InvalidHtml(format!(
+                "<{}> is a void element and cannot have children",
+                tag
+            )));
+        }
+
+        let closing_position = self.lexer.token_position();
         self.consume_token_silent(
             Token::OpenClosingTag,
             LuaXError::NeededToken(Token::OpenClosingTag.to_string()),
@@ -888,15 +1172,20 @@ impl<'s, W: Write> Preprocessor<'s, W> {
         );
 
         if closing_tag != tag {
-            return Err(LuaXError::InvalidStart.into());
+            // closing an element further out means this one was left open
+            if self.open_tags.iter().any(|(open, _)| *open == closing_tag) {
+                return Err(Self::unclosed_tag(tag, position).into());
+            }
+
+            return Err(Self::mismatched_closing_tag(tag, closing_tag, closing_position).into());
         }
 
         self.consume_token_silent(Token::Gt, LuaXError::NeededToken(Token::Gt.to_string()))?;
 
-        if Self::default_html_tag(&tag) {
-            write!(self.out_stream, " }}")?;
-        } else {
+        if component {
             write!(self.out_stream, " }}) ")?;
+        } else {
+            write!(self.out_stream, " }}")?;
         }
 
         Ok(())
@@ -915,7 +1204,7 @@ impl<'s, W: Write> Preprocessor<'s, W> {
 
             self.consume_token_silent(Token::Eq, LuaXError::NeededToken(Token::Eq.to_string()))?;
 
-            if key.contains('-') {
+            if key.contains(['-', ':']) {
                 write!(self.out_stream, "[\"{}\"]=", key)?;
             } else {
                 write!(self.out_stream, "{}=", key)?;
@@ -927,12 +1216,30 @@ impl<'s, W: Write> Preprocessor<'s, W> {
                     Token::CloseBrace,
                     LuaXError::NeededToken(Token::CloseBrace.to_string()),
                 )?;
+            } else if self.match_token_silent(Token::LuaStart)? {
+                // interpolation works the same as in children, e.g. `x={$ y $}`
+                require!(self.expression(), LuaXError::ExpectedExpression);
+                self.consume_token_silent(
+                    Token::LuaEnd,
+                    LuaXError::NeededToken(Token::LuaEnd.to_string()),
+                )?;
             } else {
                 let value = require!(
                     self.html_string(),
                     LuaXError::NeededToken("string".to_string())
                 );
 
+                if key == "id" {
+                    if self.page_ids.contains(&value) {
+                        self.problems.push(LuaXError::InvalidHtml(format!(
+                            "duplicate id \"{}\"",
+                            value
+                        )));
+                    } else {
+                        self.page_ids.push(value.clone());
+                    }
+                }
+
                 write!(self.out_stream, "\"{}\"", value)?;
             }
 
@@ -943,22 +1250,196 @@ impl<'s, W: Write> Preprocessor<'s, W> {
         Ok(())
     }
 
-    fn html_children(&mut self) -> Result<()> {
-        write!(self.out_stream, "children={{")?;
+    /// Capture the content of a `<slot name="...">` directly inside a component,
+    /// which is passed to it in the `slots` table instead of `children`
+    fn html_slot(&mut self) -> Result<()> {
+        let attr = require!(
+            self.html_identifier(),
+            LuaXError::NeededToken("name".to_string())
+        );
+        if attr != "name" {
+            return Err(LuaXError::NeededToken("name".to_string()).into());
+        }
+        self.consume_token_silent(Token::Eq, LuaXError::NeededToken(Token::Eq.to_string()))?;
+        let name = require!(
+            self.html_string(),
+            LuaXError::NeededToken("string".to_string())
+        );
+
+        self.out_stream.captures.push(Vec::new());
+        let res = self.html_slot_content();
+        let content = self.out_stream.captures.pop().unwrap_or_default();
+        res?;
+
+        if let Some(slots) = self.slots.last_mut() {
+            slots.push((name, content));
+        }
+        self.emitted_slot = true;
+
+        Ok(())
+    }
+
+    /// Inline the element in the file of an `<include src="..." />`, which is
+    /// preprocessed as if it was written in its place
+    fn html_include(&mut self) -> Result<()> {
+        let attr = require!(
+            self.html_identifier(),
+            LuaXError::NeededToken("src".to_string())
+        );
+        if attr != "src" {
+            return Err(LuaXError::NeededToken("src".to_string()).into());
+        }
+        self.consume_token_silent(Token::Eq, LuaXError::NeededToken(Token::Eq.to_string()))?;
+        let src = require!(
+            self.html_string(),
+            LuaXError::NeededToken("string".to_string())
+        );
+        self.consume_token_silent(
+            Token::Slash,
+            LuaXError::NeededToken(Token::Slash.to_string()),
+        )?;
+        self.consume_token_silent(Token::Gt, LuaXError::NeededToken(Token::Gt.to_string()))?;
+
+        let path = match self.includes.last() {
+            Some(file) => file.parent().unwrap_or(Path::new("")).join(&src),
+            None => PathBuf::from(&src),
+        };
+        let (path, template) = match path
+            .canonicalize()
+            .and_then(|path| Ok((std::fs::read_to_string(&path)?, path)))
+        {
+            Ok((template, path)) => (path, template),
+            Err(e) => return Err(LuaXError::Include(format!("cannot read {}: {}", src, e)).into()),
+        };
+
+        if self.includes.contains(&path) {
+            let cycle = self
+                .includes
+                .iter()
+                .skip_while(|file| **file != path)
+                .chain(std::iter::once(&path))
+                .map(|file| file.display().to_string())
+                .collect::<Vec<_>>();
+            return Err(LuaXError::Include(format!("cycle {}", cycle.join(" -> "))).into());
+        }
+
+        let mut buf = Vec::new();
+        {
+            let mut included = Preprocessor::new(&template, Some(&path), &mut buf)?;
+            included.includes = self.includes.iter().cloned().chain([path]).collect();
+            // the included element is part of the same page
+            included.html_depth = self.html_depth;
+            included.pre_depth = self.pre_depth;
+            included.page_ids = std::mem::take(&mut self.page_ids);
+
+            if included.current != Token::Lt {
+                return Err(LuaXError::Include(format!("{} must contain an element", src)).into());
+            }
+            if let Err(e) = included.html_template() {
+                // errors in an include further down are already about their file
+                return Err(match e.downcast::<LuaXError>() {
+                    Ok(e @ LuaXError::Include(_)) => e.into(),
+                    Ok(e) => LuaXError::Include(format!("{}: {}", src, e)).into(),
+                    Err(e) => e,
+                });
+            }
+            if included.current != Token::Eof {
+                return Err(
+                    LuaXError::Include(format!("{} must contain a single element", src)).into(),
+                );
+            }
+
+            self.page_ids = included.page_ids;
+            self.problems.append(&mut included.problems);
+        }
+        self.out_stream.write_all(&buf)?;
+
+        Ok(())
+    }
+
+    fn html_slot_content(&mut self) -> Result<()> {
+        if self.match_token_silent(Token::Slash)? {
+            self.consume_token_silent(Token::Gt, LuaXError::NeededToken(Token::Gt.to_string()))?;
+            write!(self.out_stream, "{{}}")?;
+            return Ok(());
+        }
+
+        self.consume_token_silent(Token::Gt, LuaXError::NeededToken(Token::Gt.to_string()))?;
+
+        self.html_children(false)?;
+
+        let closing_position = self.lexer.token_position();
+        self.consume_token_silent(
+            Token::OpenClosingTag,
+            LuaXError::NeededToken(Token::OpenClosingTag.to_string()),
+        )?;
+
+        let closing_tag = require!(
+            self.html_identifier(),
+            LuaXError::NeededToken("identifier".to_string())
+        );
+
+        if closing_tag != "slot" {
+            return Err(Self::mismatched_closing_tag(
+                "slot".to_string(),
+                closing_tag,
+                closing_position,
+            )
+            .into());
+        }
+
+        self.consume_token_silent(Token::Gt, LuaXError::NeededToken(Token::Gt.to_string()))
+    }
+
+    /// Emit the children of an element as a table, returning how many there were
+    ///
+    /// Named slots among the children of a component are collected separately.
+    fn html_children(&mut self, component: bool) -> Result<usize> {
+        let mut children = 0;
+        let mut text = String::new();
+        write!(self.out_stream, "{{")?;
         loop {
+            // the lexer skips whitespace outside of text, but it's part of it
+            text.push_str(self.lexer.whitespace_before());
+
             if self.current == Token::OpenClosingTag {
                 break;
             }
+            if self.current == Token::Eof {
+                return Err(self.innermost_unclosed_tag().into());
+            }
+            if matches!(
+                self.current,
+                Token::LuaStart | Token::LuaStartRaw | Token::Lt
+            ) {
+                children += self.html_text(&std::mem::take(&mut text))?;
+            }
+
             if self.match_token_silent(Token::LuaStart)? {
-                require!(self.expression(), LuaXError::ExpectedExpression);
+                self.html_lua_block()?;
                 self.consume_token_silent(
                     Token::LuaEnd,
                     LuaXError::NeededToken(Token::LuaEnd.to_string()),
                 )?;
                 write!(self.out_stream, ",")?;
+                children += 1;
+                continue;
+            }
+
+            // `{$= ... $}` gives trusted HTML, wrapped in a raw node
+            if self.match_token_silent(Token::LuaStartRaw)? {
+                write!(self.out_stream, "{{ raw=")?;
+                self.html_lua_block()?;
+                self.consume_token_silent(
+                    Token::LuaEnd,
+                    LuaXError::NeededToken(Token::LuaEnd.to_string()),
+                )?;
+                write!(self.out_stream, " }},")?;
+                children += 1;
                 continue;
             }
 
+            self.slot_allowed = component;
             if match self.html_template() {
                 Ok(t) => Some(t),
                 Err(e) => match e.downcast_ref::<LuaXError>() {
@@ -968,20 +1449,24 @@ impl<'s, W: Write> Preprocessor<'s, W> {
             }
             .is_some()
             {
+                if std::mem::take(&mut self.emitted_slot) {
+                    continue;
+                }
                 write!(self.out_stream, ",")?;
+                children += 1;
                 continue;
             }
 
             if self.current == Token::Lt
                 || self.current == Token::OpenClosingTag
                 || self.current == Token::LuaStart
+                || self.current == Token::LuaStartRaw
             {
                 break;
             }
 
-            // handle plain HTML text, which can really be anything. Needs to become
-            // a string literal
-            write!(self.out_stream, " \"")?;
+            // handle plain HTML text, which can really be anything, collecting it
+            // until the next element, expression or closing tag
             self.lexer.enable_html_text_mode();
             loop {
                 if matches!(self.current, Token::Unknown(_)) {
@@ -989,19 +1474,160 @@ impl<'s, W: Write> Preprocessor<'s, W> {
                 }
                 if self.current == Token::Lt
                     || self.current == Token::LuaStart
+                    || self.current == Token::LuaStartRaw
                     || self.current == Token::OpenClosingTag
+                    || self.current == Token::Eof
                 {
                     break;
                 }
                 // all other tokens *should* be fine to just emit
-                write!(self.out_stream, "{}", self.current)?;
+                text.push_str(&self.current.to_string());
                 self.next_token_silent()?;
             }
             self.lexer.disable_html_text_mode();
-            write!(self.out_stream, "\",")?;
         }
+        children += self.html_text(&text)?;
         write!(self.out_stream, "}}")?;
 
+        Ok(children)
+    }
+
+    fn unclosed_tag(tag: String, (line, column): (usize, usize)) -> LuaXError {
+        LuaXError::UnclosedTag { tag, line, column }
+    }
+
+    /// A closing tag not matching its element is always an error, rather than
+    /// `InvalidStart`, so that it isn't swallowed while trying alternatives
+    fn mismatched_closing_tag(
+        expected: String,
+        found: String,
+        (line, column): (usize, usize),
+    ) -> LuaXError {
+        LuaXError::MismatchedClosingTag {
+            expected,
+            found,
+            line,
+            column,
+        }
+    }
+
+    /// The error for reaching the end of the file inside of an element
+    fn innermost_unclosed_tag(&self) -> LuaXError {
+        match self.open_tags.last() {
+            Some((tag, position)) => Self::unclosed_tag(tag.clone(), *position),
+            None => LuaXError::NeededToken(Token::OpenClosingTag.to_string()),
+        }
+    }
+
+    /// Emit the contents of a `{$ ... $}` block among the children of an element
+    ///
+    /// This is usually a single expression, but it can also hold statements,
+    /// e.g. a `for` loop building a list of elements. Those are wrapped in a
+    /// function which is called right away, returning the value of a trailing
+    /// expression or an explicit `return`.
+    fn html_lua_block(&mut self) -> Result<()> {
+        self.out_stream.captures.push(Vec::new());
+        let res = self.html_lua_block_contents();
+        let content = self.out_stream.captures.pop().unwrap_or_default();
+
+        if res? {
+            self.out_stream.write_all(&content)?;
+        } else {
+            write!(self.out_stream, " (function()")?;
+            self.out_stream.write_all(&content)?;
+            write!(self.out_stream, " end)()")?;
+        }
+
         Ok(())
     }
+
+    /// Emit the statements of a `{$ ... $}` block, returning whether it was
+    /// just a single expression
+    fn html_lua_block_contents(&mut self) -> Result<bool> {
+        if self.current == Token::LuaEnd {
+            return Err(LuaXError::ExpectedExpression.into());
+        }
+
+        let mut statements = 0;
+
+        while self.current != Token::LuaEnd {
+            if self.current == Token::Return {
+                self.return_statement()?;
+                break;
+            }
+
+            if !matches!(
+                self.current,
+                Token::Semicolon
+                    | Token::ColonColon
+                    | Token::Break
+                    | Token::Goto
+                    | Token::Do
+                    | Token::While
+                    | Token::Repeat
+                    | Token::If
+                    | Token::For
+                    | Token::Function
+                    | Token::Local
+            ) {
+                // anything else starts with an expression, which is the value of
+                // the block if nothing follows it
+                let start = self.out_stream.captures.last().map_or(0, Vec::len);
+                require!(self.expression(), LuaXError::ExpectedExpression);
+
+                if self.current == Token::LuaEnd {
+                    if statements == 0 {
+                        return Ok(true);
+                    }
+
+                    if let Some(capture) = self.out_stream.captures.last_mut() {
+                        capture.splice(start..start, *b" return");
+                    }
+                    break;
+                }
+
+                // otherwise it was the start of a call or assignment
+                self.assignment_followup()?;
+            } else {
+                require!(self.statement(), LuaXError::ExpectedExpression);
+            }
+
+            statements += 1;
+        }
+
+        Ok(false)
+    }
+
+    /// Emit a run of HTML text as a string literal child, returning how many
+    /// children that made (0 or 1)
+    ///
+    /// Outside of `<pre>`, runs of whitespace collapse into a single space, and
+    /// whitespace at either end of the text is dropped if it spans lines. This
+    /// way the indentation between elements doesn't end up in the output, while
+    /// `a <b>b</b> c` keeps its spaces.
+    fn html_text(&mut self, text: &str) -> Result<usize> {
+        let text = if self.pre_depth > 0 {
+            text.to_string()
+        } else {
+            collapse_whitespace(text)
+        };
+
+        if text.is_empty() {
+            return Ok(0);
+        }
+
+        write!(self.out_stream, " \"")?;
+        for c in text.chars() {
+            match c {
+                '"' => write!(self.out_stream, "\\\"")?,
+                '\\' => write!(self.out_stream, "\\\\")?,
+                '\n' => write!(self.out_stream, "\\n")?,
+                '\r' => write!(self.out_stream, "\\r")?,
+                c => write!(self.out_stream, "{}", c)?,
+            }
+        }
+        write!(self.out_stream, "\",")?;
+
+        Ok(1)
+    }
 }
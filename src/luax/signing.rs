@@ -0,0 +1,122 @@
+use std::io::Read;
+use std::sync::OnceLock;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+static SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// The key used to sign values handed out to clients
+///
+/// It can be set through the `RELUAX_SECRET` environment variable, or the
+/// project's config with `set_default_secret`, so that signed values survive
+/// restarts and are shared between instances. Otherwise a random key is
+/// generated for the lifetime of the process, which fails where there is no
+/// `/dev/urandom` to read it from.
+fn secret() -> rlua::Result<&'static [u8]> {
+    if let Some(secret) = SECRET.get() {
+        return Ok(secret);
+    }
+
+    let key = match env_secret() {
+        Some(secret) => secret,
+        None => {
+            let mut key = vec![0; 32];
+            std::fs::File::open("/dev/urandom")
+                .and_then(|mut f| f.read_exact(&mut key))
+                .map_err(|e| {
+                    rlua::Error::RuntimeError(format!(
+                        "failed to generate a secret key ({}), set RELUAX_SECRET or \
+                         `secret` in reluax.toml",
+                        e
+                    ))
+                })?;
+            key
+        }
+    };
+
+    // another thread may have set it in the meantime, in which case that one is kept
+    Ok(SECRET.get_or_init(|| key))
+}
+
+fn env_secret() -> Option<Vec<u8>> {
+    match std::env::var("RELUAX_SECRET") {
+        Ok(secret) if !secret.is_empty() => Some(secret.into_bytes()),
+        _ => None,
+    }
+}
+
+/// Use a secret from the config unless `RELUAX_SECRET` is set, which has to
+/// happen before anything is signed
+pub fn set_default_secret(secret: &str) {
+    let _ = SECRET.set(env_secret().unwrap_or_else(|| secret.as_bytes().to_vec()));
+}
+
+fn mac(key: &[u8], value: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(value.as_bytes());
+    mac
+}
+
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Compare two secrets in time depending only on their lengths, so that how
+/// much of a guess matches can't be timed
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Sign a value, producing `value.signature`
+pub fn sign(value: &str) -> rlua::Result<String> {
+    Ok(sign_with(secret()?, value))
+}
+
+/// Sign a value with the given key instead of the process-wide secret
+pub fn sign_with(key: &[u8], value: &str) -> String {
+    format!("{}.{}", value, signature_with(key, value))
+}
+
+/// The hex-encoded signature of a value, without the value itself
+pub fn signature_with(key: &[u8], value: &str) -> String {
+    hex_encode(&mac(key, value).finalize().into_bytes())
+}
+
+/// The process-wide secret, for signing with keys derived from it
+pub fn default_secret() -> rlua::Result<&'static [u8]> {
+    secret()
+}
+
+/// Check a value produced by `sign`, returning the original value if the
+/// signature matches
+pub fn verify(signed: &str) -> rlua::Result<Option<&str>> {
+    Ok(verify_with(secret()?, signed))
+}
+
+/// Check a value produced by `sign_with` with the same key
+pub fn verify_with<'a>(key: &[u8], signed: &'a str) -> Option<&'a str> {
+    let (value, signature) = signed.rsplit_once('.')?;
+    let signature = hex_decode(signature)?;
+
+    mac(key, value).verify_slice(&signature).ok()?;
+
+    Some(value)
+}
@@ -28,6 +28,71 @@ fn number() -> Result<()> {
     compare_tokens("123", vec![Token::Number("123"), Token::Eof])
 }
 
+#[test]
+fn floats() -> Result<()> {
+    compare_tokens(
+        ".5 3. 1.25 1e-3",
+        vec![
+            Token::Number(".5"),
+            Token::Number("3."),
+            Token::Number("1.25"),
+            Token::Number("1e-3"),
+            Token::Eof,
+        ],
+    )
+}
+
+#[test]
+fn dots_are_not_floats() -> Result<()> {
+    compare_tokens(
+        "a.b x..5 f(...) s:m()",
+        vec![
+            Token::Identifier("a"),
+            Token::Dot,
+            Token::Identifier("b"),
+            Token::Identifier("x"),
+            Token::DotDot,
+            Token::Number("5"),
+            Token::Identifier("f"),
+            Token::OpenParen,
+            Token::DotDotDot,
+            Token::CloseParen,
+            Token::Identifier("s"),
+            Token::Colon,
+            Token::Identifier("m"),
+            Token::OpenParen,
+            Token::CloseParen,
+            Token::Eof,
+        ],
+    )
+}
+
+#[test]
+fn shebang() -> Result<()> {
+    compare_tokens(
+        "#!/usr/bin/env lua\nprint(#t)",
+        vec![
+            Token::Identifier("print"),
+            Token::OpenParen,
+            Token::Hash,
+            Token::Identifier("t"),
+            Token::CloseParen,
+            Token::Eof,
+        ],
+    )?;
+    compare_tokens(
+        "x = 1 #!",
+        vec![
+            Token::Identifier("x"),
+            Token::Eq,
+            Token::Number("1"),
+            Token::Hash,
+            Token::Bang,
+            Token::Eof,
+        ],
+    )
+}
+
 #[test]
 fn string() -> Result<()> {
     compare_tokens(
@@ -57,6 +122,19 @@ fn all_string_types() -> Result<()> {
     )
 }
 
+#[test]
+fn escaped_string_ends() -> Result<()> {
+    compare_tokens(
+        r#""a\\"'\''"\"""#,
+        vec![
+            Token::String("a\\\\", StringType::Double),
+            Token::String("\\'", StringType::Single),
+            Token::String("\\\"", StringType::Double),
+            Token::Eof,
+        ],
+    )
+}
+
 #[test]
 fn table() -> Result<()> {
     compare_tokens(
@@ -136,6 +214,37 @@ fn operators() -> Result<()> {
     )
 }
 
+#[test]
+fn unicode_identifiers() -> Result<()> {
+    compare_tokens(
+        "café = 名前 + _x1",
+        vec![
+            Token::Identifier("café"),
+            Token::Eq,
+            Token::Identifier("名前"),
+            Token::Plus,
+            Token::Identifier("_x1"),
+            Token::Eof,
+        ],
+    )
+}
+
+#[test]
+fn identifier_cannot_start_with_digit() -> Result<()> {
+    compare_tokens(
+        "2fast",
+        vec![Token::Number("2"), Token::Identifier("fast"), Token::Eof],
+    )
+}
+
+#[test]
+fn emoji_is_not_an_identifier() -> Result<()> {
+    let mut lexer = Lexer::new("🦆");
+    assert_eq!(lexer.next_token()?, Some(Token::Unknown('🦆')));
+
+    Ok(())
+}
+
 #[test]
 fn keywords() -> Result<()> {
     compare_tokens(
@@ -195,3 +304,81 @@ fn html() -> Result<()> {
         ],
     )
 }
+
+#[test]
+fn lua_delimiters() -> Result<()> {
+    compare_tokens(
+        "{$ a $}",
+        vec![
+            Token::LuaStart,
+            Token::Identifier("a"),
+            Token::LuaEnd,
+            Token::Eof,
+        ],
+    )
+}
+
+#[test]
+fn raw_lua_delimiters() -> Result<()> {
+    compare_tokens(
+        "{$= a $} {$ = b $}",
+        vec![
+            Token::LuaStartRaw,
+            Token::Identifier("a"),
+            Token::LuaEnd,
+            Token::LuaStart,
+            Token::Eq,
+            Token::Identifier("b"),
+            Token::LuaEnd,
+            Token::Eof,
+        ],
+    )?;
+    compare_tokens(
+        "--! delimiters <% %>\n<%= a %>",
+        vec![
+            Token::LuaStartRaw,
+            Token::Identifier("a"),
+            Token::LuaEnd,
+            Token::Eof,
+        ],
+    )
+}
+
+#[test]
+fn template_comments() -> Result<()> {
+    compare_tokens(
+        "a {# note #} b {##}",
+        vec![Token::Identifier("a"), Token::Identifier("b"), Token::Eof],
+    )?;
+    // without whitespace or a closing `#}`, it's a table with a length
+    compare_tokens(
+        "{#t} {# t}",
+        vec![
+            Token::OpenBrace,
+            Token::Hash,
+            Token::Identifier("t"),
+            Token::CloseBrace,
+            Token::OpenBrace,
+            Token::Hash,
+            Token::Identifier("t"),
+            Token::CloseBrace,
+            Token::Eof,
+        ],
+    )
+}
+
+#[test]
+fn alternate_lua_delimiters() -> Result<()> {
+    compare_tokens(
+        "--! delimiters <% %>\n<% a %> { b }",
+        vec![
+            Token::LuaStart,
+            Token::Identifier("a"),
+            Token::LuaEnd,
+            Token::OpenBrace,
+            Token::Identifier("b"),
+            Token::CloseBrace,
+            Token::Eof,
+        ],
+    )
+}
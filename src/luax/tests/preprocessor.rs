@@ -1,3 +1,4 @@
+use crate::error::{LuaXError, PreprocessError, ReluaxError};
 use crate::luax::{lexer::Lexer, tokens::Token, *};
 use color_eyre::Result;
 
@@ -44,6 +45,22 @@ fn compare_output(input: &str, expected: &str) -> Result<()> {
     Ok(())
 }
 
+/// Run a template returning an element and render it to HTML
+fn render(input: &str) -> Result<String> {
+    render_with(input, Syntax::Html)
+}
+
+fn render_with(input: &str, syntax: Syntax) -> Result<String> {
+    let src = preprocess(input)?;
+
+    let lua = rlua::Lua::new();
+    lua.context(|ctx| -> Result<String> {
+        let mut html = Vec::new();
+        table_to_markup(ctx.load(&src).eval()?, &mut html, syntax)?;
+        Ok(String::from_utf8(html)?)
+    })
+}
+
 #[test]
 fn empty() -> Result<()> {
     compare_output("", "")
@@ -205,6 +222,46 @@ fn html_with_code() -> Result<()> {
     )
 }
 
+#[test]
+fn inline_whitespace() -> Result<()> {
+    compare_output(
+        "return <p>a <b>b</b> c</p>",
+        r#"return { tag="p", attrs={}, children={ "a ", { tag="b", attrs={}, children={ "b",} }, " c",} }"#,
+    )
+}
+
+#[test]
+fn whitespace_between_expressions() -> Result<()> {
+    compare_output(
+        "return <p>{$ a $} {$ b $}</p>",
+        r#"return { tag="p", attrs={}, children={ a, " ", b,} }"#,
+    )
+}
+
+#[test]
+fn whitespace_collapsed() -> Result<()> {
+    compare_output(
+        "return <p>\n    hello\n    world   again\n    <br />\n</p>",
+        r#"return { tag="p", attrs={}, children={ "hello world again", { tag="br", attrs={}, children={} },} }"#,
+    )
+}
+
+#[test]
+fn whitespace_in_pre() -> Result<()> {
+    compare_output(
+        "return <pre>  a\n    b <i>c</i>\n</pre>",
+        r#"return { tag="pre", attrs={}, children={ "  a\n    b ", { tag="i", attrs={}, children={ "c",} }, "\n",} }"#,
+    )
+}
+
+#[test]
+fn text_with_quotes() -> Result<()> {
+    compare_output(
+        r#"return <p>say "hi" \o/</p>"#,
+        r#"return { tag="p", attrs={}, children={ "say \"hi\" \\o/",} }"#,
+    )
+}
+
 #[test]
 fn html_with_code_and_text() -> Result<()> {
     compare_output(
@@ -261,6 +318,56 @@ fn component_with_children() -> Result<()> {
     )
 }
 
+#[test]
+fn self_closing_component_with_interpolated_attr() -> Result<()> {
+    compare_output(
+        "return <Hello x={$ y $} />",
+        "return Hello ({ attrs={x= y , }, children={} })",
+    )
+}
+
+#[test]
+fn self_closing_component_with_expression_attrs() -> Result<()> {
+    compare_output(
+        "return <Hello x={ y } z={$ f(1) $} name=\"world\" />",
+        "return Hello ({ attrs={x= y , z= f(1) , name=\"world\", }, children={} })",
+    )
+}
+
+#[test]
+fn element_with_interpolated_attr() -> Result<()> {
+    compare_output(
+        "return <a href={$ url $}>link</a>",
+        "return { tag=\"a\", attrs={href= url , }, children={ \"link\",} }",
+    )
+}
+
+#[test]
+fn component_with_slots() -> Result<()> {
+    compare_output(
+        "return <Layout><slot name=\"header\"><h1>Title</h1></slot><p>Body</p><slot name=\"footer\" /></Layout>",
+        "return Layout ({ attrs={}, children={ { tag=\"p\", attrs={}, children={ \"Body\",} },}, \
+         slots={ [\"header\"]={ { tag=\"h1\", attrs={}, children={ \"Title\",} },}, [\"footer\"]={}, } })",
+    )
+}
+
+#[test]
+fn slot_outside_component() -> Result<()> {
+    compare_output(
+        "return <div><slot name=\"header\"></slot></div>",
+        "return { tag=\"div\", attrs={}, children={ { tag=\"slot\", attrs={name=\"header\", }, children={} },} }",
+    )
+}
+
+#[test]
+fn nested_component_slots() -> Result<()> {
+    compare_output(
+        "return <Outer><slot name=\"a\"><Inner><slot name=\"b\">x</slot></Inner></slot></Outer>",
+        "return Outer ({ attrs={}, children={}, slots={ [\"a\"]={ Inner ({ attrs={}, children={}, \
+         slots={ [\"b\"]={ \"x\",}, } }),}, } })",
+    )
+}
+
 #[test]
 fn html_with_attrs_with_dash() -> Result<()> {
     compare_output(
@@ -276,3 +383,818 @@ fn weird_symbols_in_html() -> Result<()> {
         "return { tag=\"div\", attrs={}, children={ \"@everyone\",} }",
     )
 }
+
+#[test]
+fn custom_element() -> Result<()> {
+    compare_output(
+        "return <my-element></my-element>",
+        "return { tag=\"my-element\", attrs={}, children={} }",
+    )
+}
+
+#[test]
+fn svg_element() -> Result<()> {
+    compare_output(
+        "return <svg><circle r=\"4\" /></svg>",
+        "return { tag=\"svg\", attrs={}, children={ { tag=\"circle\", attrs={r=\"4\", }, children={} },} }",
+    )
+}
+
+#[test]
+fn component_inside_element() -> Result<()> {
+    compare_output(
+        "return <div><Hello /></div>",
+        "return { tag=\"div\", attrs={}, children={ Hello ({ attrs={}, children={} }),} }",
+    )
+}
+
+#[test]
+fn validate_valid_html() -> Result<()> {
+    let problems = validate("return <div id=\"a\"><img src=\"a.png\" /><br /></div>")?;
+
+    assert!(problems.is_empty(), "unexpected problems: {:?}", problems);
+
+    Ok(())
+}
+
+#[test]
+fn validate_void_element_with_children() -> Result<()> {
+    let problems = validate("return <div><br>hello</br></div>")?;
+
+    assert_eq!(
+        problems,
+        vec![LuaXError::InvalidHtml(
+            "<br> is a void element and cannot have children".to_string()
+        )]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn validate_duplicate_ids() -> Result<()> {
+    let problems = validate("return <div><p id=\"a\"></p><p id=\"a\"></p></div>")?;
+
+    assert_eq!(
+        problems,
+        vec![LuaXError::InvalidHtml("duplicate id \"a\"".to_string())]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn validate_ids_in_separate_pages() -> Result<()> {
+    let problems = validate("local a = <p id=\"a\"></p> local b = <p id=\"a\"></p>")?;
+
+    assert!(problems.is_empty(), "unexpected problems: {:?}", problems);
+
+    Ok(())
+}
+
+#[test]
+fn alternate_lua_delimiters() -> Result<()> {
+    compare_output(
+        "--! delimiters <% %>\nreturn <div>{$ hello $} <% world %></div>",
+        "return { tag=\"div\", attrs={}, children={ \"{$ hello $} \", world,} }",
+    )
+}
+
+#[test]
+fn shebang() -> Result<()> {
+    compare_output(
+        "#!/usr/bin/env lua\nlocal x = 1\nreturn <p>{$ x $}</p>",
+        "local x = 1 return { tag=\"p\", attrs={}, children={ x,} }",
+    )
+}
+
+#[test]
+fn numeric_literals() -> Result<()> {
+    let input = "n = { 1e10, 2.5E-3, .5, 100., 0xff, 0x1p4, 0xA.8P-1, 'x' .. 1 }";
+    let output = preprocess(input)?;
+
+    assert_eq!(tokenize(&output)?, tokenize(input)?);
+    for literal in ["1e10", "2.5E-3", ".5", "100.", "0xff", "0x1p4", "0xA.8P-1"] {
+        assert!(
+            tokenize(&output)?.contains(&Token::Number(literal)),
+            "{} didn't survive preprocessing: {}",
+            literal,
+            output
+        );
+    }
+
+    let lua = rlua::Lua::new();
+    let sum: f64 = lua.context(|ctx| {
+        ctx.load(&output).exec()?;
+        ctx.load("return n[3] + n[4] + n[5] + n[6] + n[7]").eval()
+    })?;
+    assert_eq!(sum, 0.5 + 100.0 + 255.0 + 16.0 + 5.25);
+
+    Ok(())
+}
+
+#[test]
+fn goto_round_trip() -> Result<()> {
+    let input = "
+        local out = ''
+        for i = 1, 4 do
+            if i % 2 == 0 then goto continue end
+            do
+                local j = i
+                out = out .. j
+            end
+            ::continue::
+        end
+        return out
+    ";
+
+    let output = preprocess(input)?;
+    assert_eq!(tokenize(&output)?, tokenize(input)?);
+
+    let lua = rlua::Lua::new();
+    let out: String = lua.context(|ctx| ctx.load(&output).eval())?;
+    assert_eq!(out, "13");
+
+    Ok(())
+}
+
+#[test]
+fn goto_around_elements() -> Result<()> {
+    let html = render(
+        "local items = {}
+        for i = 1, 3 do
+            if i == 2 then goto continue end
+            items[#items + 1] = <li>{$ i $}</li>
+            ::continue::
+        end
+        return <ul>{$ items $}</ul>",
+    )?;
+
+    assert_eq!(html, "<ul><li>1</li><li>3</li></ul>");
+
+    Ok(())
+}
+
+#[test]
+fn lua_block_expression() -> Result<()> {
+    compare_output(
+        "return <p>{$ greet(name) $}</p>",
+        "return { tag=\"p\", attrs={}, children={ greet ( name ),} }",
+    )
+}
+
+#[test]
+fn lua_block_statements() -> Result<()> {
+    compare_output(
+        "return <ul>{$ local items = {} for i = 1, 3 do items[i] = <li>{$ i $}</li> end items $}</ul>",
+        "return { tag=\"ul\", attrs={}, children={ (function() local items = { } for i = 1 , 3 do items [ i ] = { tag=\"li\", attrs={}, children={ i,} } end return items end)(),} }",
+    )?;
+    compare_output(
+        "return <p>{$ local x = 1 return x $}</p>",
+        "return { tag=\"p\", attrs={}, children={ (function() local x = 1 return x end)(),} }",
+    )
+}
+
+#[test]
+fn lua_block_renders_loop() -> Result<()> {
+    let html = render(
+        "return <ul>{$ local items = {} for i = 1, 3 do items[i] = <li>{$ i $}</li> end items $}</ul>",
+    )?;
+
+    assert_eq!(html, "<ul><li>1</li><li>2</li><li>3</li></ul>");
+
+    Ok(())
+}
+
+#[test]
+fn lua_block_raw() -> Result<()> {
+    compare_output(
+        "return <p>{$= html $}</p>",
+        "return { tag=\"p\", attrs={}, children={ { raw= html },} }",
+    )?;
+    compare_output(
+        "return <p>{$= local s = f() return s $}</p>",
+        "return { tag=\"p\", attrs={}, children={ { raw= (function() local s = f ( ) return s end)() },} }",
+    )
+}
+
+#[test]
+fn lua_block_raw_renders() -> Result<()> {
+    let html = render(
+        "local trusted = '<b>hi</b>' return <div>{$= trusted $} and {$ 'text' $}{$= 1 + 1 $}</div>",
+    )?;
+
+    assert_eq!(html, "<div><b>hi</b> and text2</div>");
+
+    Ok(())
+}
+
+#[test]
+fn template_comments() -> Result<()> {
+    let input = "return <div>
+        {# a comment
+           over lines #}
+        <p>a {# note #} b</p>
+        <b>x</b> {# note #}y<i>a{# note #}b</i>
+        <a {# note #} href=\"/\">link</a>
+    </div>";
+
+    assert!(!preprocess(input)?.contains("note"));
+    assert_eq!(
+        render(input)?,
+        "<div><p>a b</p><b>x</b> y<i>ab</i><a href=\"/\">link</a></div>"
+    );
+
+    // the length operator is still Lua
+    assert_eq!(
+        render("local t = {1, 2} local n = {#t} return <p>{$ n[1] $}</p>")?,
+        "<p>2</p>"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn optional_attributes() -> Result<()> {
+    let html = render(
+        "local url = nil return <a href={$ url $} title=\"Home\" hidden={$ false $}>Home</a>",
+    )?;
+    assert_eq!(html, "<a title=\"Home\">Home</a>");
+
+    let html = render("return <input disabled={$ true $} />")?;
+    assert_eq!(html, "<input disabled></input>");
+
+    Ok(())
+}
+
+#[test]
+fn numeric_and_boolean_attributes() -> Result<()> {
+    let cases = [
+        ("{width=300}", "<img width=\"300\"></img>"),
+        ("{opacity=0.5}", "<img opacity=\"0.5\"></img>"),
+        ("{hidden=true}", "<img hidden></img>"),
+        ("{hidden=false}", "<img></img>"),
+    ];
+
+    for (attrs, expected) in cases {
+        let html = render(&format!(
+            "return {{ tag=\"img\", attrs={}, children={{}} }}",
+            attrs
+        ))?;
+        assert_eq!(html, expected);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn invalid_tables_are_errors() {
+    assert!(render("return { tag=\"img\", attrs={src={}}, children={} }").is_err());
+    assert!(render("return { tag={}, attrs={}, children={} }").is_err());
+}
+
+#[test]
+fn class_list() -> Result<()> {
+    let html = render(
+        "local active = false return <button class={$ {\"btn\", active and \"btn-active\", \"big\"} $}>Go</button>",
+    )?;
+    assert_eq!(html, "<button class=\"btn big\">Go</button>");
+
+    let html = render("return <button class=\"btn\">Go</button>")?;
+    assert_eq!(html, "<button class=\"btn\">Go</button>");
+
+    Ok(())
+}
+
+#[test]
+fn class_map() -> Result<()> {
+    let html =
+        render("return <button class={$ {btn=true, disabled=false, active=1} $}>Go</button>")?;
+    assert_eq!(html, "<button class=\"active btn\">Go</button>");
+
+    Ok(())
+}
+
+#[test]
+fn style_table() -> Result<()> {
+    let html = render(
+        "return <p style={$ {[\"font-size\"]=\"12px\", color=\"red\", margin=nil, [\"z-index\"]=2} $}>Hi</p>",
+    )?;
+    assert_eq!(
+        html,
+        "<p style=\"color:red;font-size:12px;z-index:2\">Hi</p>"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn json_keys_are_sorted() -> Result<()> {
+    let lua = rlua::Lua::new();
+    let json = |src: &str| {
+        lua.context(|ctx| -> Result<String> {
+            let mut json = Vec::new();
+            table_to_json(ctx.load(src).eval()?, &mut json)?;
+            Ok(String::from_utf8(json)?)
+        })
+    };
+
+    let expected = "{\"a\":1,\"b\":{\"x\":true,\"y\":false},\"c\":\"three\",\"d\":4.5}";
+    for _ in 0..10 {
+        assert_eq!(
+            json("return { d = 4.5, c = \"three\", b = { y = false, x = true }, a = 1 }")?,
+            expected
+        );
+    }
+    assert_eq!(
+        json(
+            "local t = {} t.c = \"three\" t.a = 1 t.d = 4.5 t.b = { x = true, y = false } return t"
+        )?,
+        expected
+    );
+
+    Ok(())
+}
+
+#[test]
+fn json_sequences_are_arrays() -> Result<()> {
+    let lua = rlua::Lua::new();
+    let json = |src: &str| {
+        lua.context(|ctx| -> Result<String> {
+            let mut json = Vec::new();
+            table_to_json(ctx.load(src).eval()?, &mut json)?;
+            Ok(String::from_utf8(json)?)
+        })
+    };
+
+    // in index order, even past 9 where sorting the keys as strings would not be
+    assert_eq!(
+        json("return { 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12 }")?,
+        "[1,2,3,4,5,6,7,8,9,10,11,12]"
+    );
+    assert_eq!(
+        json("return { items = { \"a\", { x = 1 }, true }, count = 3 }")?,
+        "{\"count\":3,\"items\":[\"a\",{\"x\":1},true]}"
+    );
+    // tables with holes or other keys stay objects
+    assert_eq!(
+        json("return { [1] = \"a\", [3] = \"c\" }")?,
+        "{\"1\":\"a\",\"3\":\"c\"}"
+    );
+    assert_eq!(json("return { \"a\", b = 2 }")?, "{\"1\":\"a\",\"b\":2}");
+
+    Ok(())
+}
+
+#[test]
+fn attributes_are_sorted() -> Result<()> {
+    let input =
+        "return <a title=\"Home\" href=\"/\" id=\"home\" data-x=\"1\" class=\"nav\">Home</a>";
+    let expected = "<a class=\"nav\" data-x=\"1\" href=\"/\" id=\"home\" title=\"Home\">Home</a>";
+
+    for _ in 0..10 {
+        assert_eq!(render(input)?, expected);
+    }
+    assert_eq!(render_with(input, Syntax::Xml)?, expected);
+
+    Ok(())
+}
+
+#[test]
+fn lua_block_empty() {
+    assert!(preprocess("return <p>{$ $}</p>").is_err());
+}
+
+#[test]
+fn line_breaks_kept() -> Result<()> {
+    let output = preprocess("local a = 1\nlocal b = 2\nprint(a + b)")?;
+
+    assert_eq!(output, "local a = 1\nlocal b = 2\nprint ( a + b )");
+
+    Ok(())
+}
+
+#[test]
+fn line_breaks_after_templates() -> Result<()> {
+    let (output, source_map) = preprocess_with_source_map(
+        "-- a page\n\nlocal page = <div>\n  <p>hi</p>\n</div>\nreturn page",
+    )?;
+
+    assert_eq!(output.lines().count(), 6);
+    assert_eq!(output.lines().last(), Some("return page"));
+    assert_eq!(source_map.input_line(6), Some(6));
+
+    Ok(())
+}
+
+#[test]
+fn source_map() -> Result<()> {
+    let (output, source_map) =
+        preprocess_with_source_map("local a = 1\nlocal s = [[x\ny\n\nz]]\n\nerror(s)")?;
+
+    assert_eq!(output.lines().count(), 7);
+    for line in 1..=7 {
+        assert_eq!(source_map.input_line(line), Some(line));
+    }
+    assert_eq!(source_map.input_line(8), None);
+    assert_eq!(source_map.input_line(0), None);
+
+    Ok(())
+}
+
+#[test]
+fn lua_error_lines_point_into_luax() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let page = dir.path().join("page");
+    std::fs::write(
+        page.with_extension("luax"),
+        "local s = [[\n]] error('boom')",
+    )?;
+
+    let error = format!(
+        "{0}.lua:2: boom\nstack traceback:\n\t[C]: in function 'error'\n\t{0}.lua:2: in main chunk\n\tother.lua:7: in function 'f'",
+        page.display()
+    );
+
+    assert_eq!(
+        map_lua_error_lines(&error),
+        format!(
+            "{0}.luax:2: boom\nstack traceback:\n\t[C]: in function 'error'\n\t{0}.luax:2: in main chunk\n\tother.lua:7: in function 'f'",
+            page.display()
+        )
+    );
+
+    Ok(())
+}
+
+fn preprocess_error(input: &str) -> LuaXError {
+    let err = preprocess(input).unwrap_err();
+
+    match err
+        .downcast_ref::<PreprocessError>()
+        .and_then(|err| err.error.downcast_ref::<LuaXError>())
+    {
+        Some(err) => err.clone(),
+        None => panic!("expected a LuaX error, got {}", err),
+    }
+}
+
+#[test]
+fn error_keeps_partial_output() {
+    let err = preprocess("local a = 1\nreturn <div>\n  <p>hello</div>").unwrap_err();
+
+    match err.downcast_ref::<PreprocessError>() {
+        Some(err) => {
+            assert!(err.output.starts_with("local a = 1"));
+            assert!(err.output.contains("tag=\"div\""));
+            // stopped in the closing tag
+            assert_eq!(err.line, 3);
+            assert!(err.column > 11);
+            assert_eq!(err.to_string(), err.error.to_string());
+        }
+        None => panic!("expected a preprocessing error, got {}", err),
+    }
+}
+
+#[test]
+fn unclosed_tag() {
+    assert_eq!(
+        preprocess_error("return <div>\n  <p>hello"),
+        LuaXError::UnclosedTag {
+            tag: "p".to_string(),
+            line: 2,
+            column: 3
+        }
+    );
+    assert_eq!(
+        preprocess_error("local page = <main><p>hello</main>"),
+        LuaXError::UnclosedTag {
+            tag: "p".to_string(),
+            line: 1,
+            column: 20
+        }
+    );
+}
+
+#[test]
+fn mismatched_closing_tag() {
+    assert_eq!(
+        preprocess_error("return <div><p>hello</p></span>"),
+        LuaXError::MismatchedClosingTag {
+            expected: "div".to_string(),
+            found: "span".to_string(),
+            line: 1,
+            column: 25,
+        }
+    );
+}
+
+#[test]
+fn mismatched_closing_tag_is_not_swallowed() {
+    let expected = LuaXError::MismatchedClosingTag {
+        expected: "div".to_string(),
+        found: "span".to_string(),
+        line: 2,
+        column: 15,
+    };
+
+    assert_eq!(preprocess_error("return\n    f(x, <div></span>)"), expected);
+    assert_eq!(
+        preprocess_error("local t = {\n    aa = <div></span> }"),
+        expected
+    );
+    assert_eq!(
+        preprocess_error("local t = {\n    aa = <div></span> }").to_string(),
+        "Mismatched closing tag: expected </div>, found </span> at line 2, column 15"
+    );
+}
+
+#[test]
+fn bang_is_not_lua() {
+    assert_eq!(
+        preprocess_error("if a != b then end"),
+        LuaXError::NotLuaOperator {
+            found: "!=".to_string(),
+            instead: "~=".to_string(),
+        }
+    );
+    assert_eq!(
+        preprocess_error("local x = !y"),
+        LuaXError::NotLuaOperator {
+            found: "!".to_string(),
+            instead: "not".to_string(),
+        }
+    );
+}
+
+#[test]
+fn include() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    std::fs::create_dir_all(dir.path().join("partials"))?;
+    std::fs::write(
+        dir.path().join("partials/header.luax"),
+        "<header><h1>{$ title $}</h1><include src=\"nav.luax\" /></header>\n",
+    )?;
+    std::fs::write(
+        dir.path().join("partials/nav.luax"),
+        "<nav><a href=\"/\">Home</a></nav>",
+    )?;
+    let page = dir.path().join("page.luax");
+    let input =
+        "local title = \"Hi\"\nreturn <body><include src=\"partials/header.luax\" /><p>Text</p></body>";
+
+    let (src, _) = preprocess_at(input, Some(&page))?;
+    let lua = rlua::Lua::new();
+    let html = lua.context(|ctx| -> Result<String> {
+        let mut html = Vec::new();
+        table_to_html(ctx.load(&src).eval()?, &mut html)?;
+        Ok(String::from_utf8(html)?)
+    })?;
+
+    assert_eq!(
+        html,
+        "<body><header><h1>Hi</h1><nav><a href=\"/\">Home</a></nav></header><p>Text</p></body>"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn include_errors() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    std::fs::write(
+        dir.path().join("a.luax"),
+        "<div><include src=\"b.luax\" /></div>",
+    )?;
+    std::fs::write(
+        dir.path().join("b.luax"),
+        "<p><include src=\"a.luax\" /></p>",
+    )?;
+    std::fs::write(dir.path().join("two.luax"), "<p /><p />")?;
+    std::fs::write(dir.path().join("broken.luax"), "<p></div>")?;
+    let page = dir.path().join("page.luax");
+
+    let error = |src: &str| {
+        let input = format!("return <include src=\"{}\" />", src);
+        let e = preprocess_at(&input, Some(&page)).unwrap_err();
+        match e
+            .downcast::<PreprocessError>()
+            .map(|e| e.error.downcast::<LuaXError>())
+        {
+            Ok(Ok(LuaXError::Include(problem))) => problem,
+            e => panic!("expected an include error, got {:?}", e),
+        }
+    };
+
+    assert!(error("a.luax").starts_with("cycle "));
+    assert!(error("a.luax").ends_with("a.luax"));
+    assert!(error("missing.luax").starts_with("cannot read missing.luax"));
+    assert_eq!(error("two.luax"), "two.luax must contain a single element");
+    assert!(error("broken.luax").starts_with("broken.luax: Mismatched closing tag"));
+
+    Ok(())
+}
+
+#[test]
+fn preprocess_dir_nested() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let out = tempfile::tempdir()?;
+
+    std::fs::create_dir_all(dir.path().join("components/forms"))?;
+    std::fs::write(dir.path().join("reluax.luax"), "return <div />")?;
+    std::fs::write(dir.path().join("components/card.luax"), "return <p />")?;
+    std::fs::write(
+        dir.path().join("components/forms/input.luax"),
+        "return <input />",
+    )?;
+    std::fs::write(dir.path().join("style.css"), "p { color: red; }")?;
+
+    let preprocessed = crate::luax::preprocess_dir(dir.path(), out.path())?;
+
+    assert_eq!(preprocessed, 3);
+    assert!(out.path().join("reluax.lua").is_file());
+    assert!(out.path().join("components/card.lua").is_file());
+    assert!(out.path().join("components/forms/input.lua").is_file());
+    assert!(!out.path().join("style.css").exists());
+
+    Ok(())
+}
+
+#[test]
+fn preprocess_dir_skips_up_to_date() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let out = tempfile::tempdir()?;
+    // sources are dated an hour around the outputs, which are written now
+    let hour = std::time::Duration::from_secs(3600);
+    let touch = |name: &str, time: std::time::SystemTime| -> Result<()> {
+        std::fs::File::options()
+            .write(true)
+            .open(dir.path().join(name))?
+            .set_modified(time)?;
+        Ok(())
+    };
+
+    std::fs::write(dir.path().join("a.luax"), "return <div />")?;
+    std::fs::write(dir.path().join("b.luax"), "return <p />")?;
+    std::fs::write(dir.path().join("part.luax"), "<span />")?;
+    std::fs::write(
+        dir.path().join("c.luax"),
+        "return <div><include src=\"part.luax\" /></div>",
+    )?;
+    for name in ["a.luax", "b.luax", "part.luax", "c.luax"] {
+        touch(name, std::time::SystemTime::now() - hour)?;
+    }
+    let build =
+        |force| crate::luax::preprocess_dir_incremental(dir.path(), out.path(), force, false);
+
+    assert_eq!(
+        build(false)?,
+        Preprocessed {
+            processed: 4,
+            skipped: 0,
+            pruned: 0
+        }
+    );
+
+    // only the files with includes are preprocessed again
+    assert_eq!(
+        build(false)?,
+        Preprocessed {
+            processed: 1,
+            skipped: 3,
+            pruned: 0
+        }
+    );
+
+    std::fs::write(dir.path().join("b.luax"), "return <p>changed</p>")?;
+    touch("b.luax", std::time::SystemTime::now() + hour)?;
+    assert_eq!(
+        build(false)?,
+        Preprocessed {
+            processed: 2,
+            skipped: 2,
+            pruned: 0
+        }
+    );
+    assert!(std::fs::read_to_string(out.path().join("b.lua"))?.contains("changed"));
+
+    assert_eq!(
+        build(true)?,
+        Preprocessed {
+            processed: 4,
+            skipped: 0,
+            pruned: 0
+        }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn preprocess_dir_prunes_stale_outputs() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let out = tempfile::tempdir()?;
+
+    std::fs::create_dir(dir.path().join("pages"))?;
+    std::fs::write(dir.path().join("old.luax"), "return <div />")?;
+    std::fs::write(dir.path().join("pages/gone.luax"), "return <p />")?;
+    std::fs::write(dir.path().join("kept.luax"), "return <p />")?;
+    std::fs::write(out.path().join("handwritten.lua"), "return {}")?;
+
+    crate::luax::preprocess_dir_incremental(dir.path(), out.path(), false, true)?;
+
+    std::fs::rename(dir.path().join("old.luax"), dir.path().join("new.luax"))?;
+    std::fs::remove_file(dir.path().join("pages/gone.luax"))?;
+
+    // without pruning the stale outputs stay, but are still tracked
+    let built = crate::luax::preprocess_dir_incremental(dir.path(), out.path(), false, false)?;
+    assert_eq!(built.pruned, 0);
+    assert!(out.path().join("old.lua").is_file());
+
+    let built = crate::luax::preprocess_dir_incremental(dir.path(), out.path(), false, true)?;
+    assert_eq!(built.pruned, 2);
+    assert!(!out.path().join("old.lua").exists());
+    assert!(!out.path().join("pages/gone.lua").exists());
+    assert!(out.path().join("new.lua").is_file());
+    assert!(out.path().join("kept.lua").is_file());
+    assert!(out.path().join("handwritten.lua").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn preprocess_dir_reports_all_failures() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let out = tempfile::tempdir()?;
+
+    std::fs::write(dir.path().join("good.luax"), "return <div />")?;
+    std::fs::write(dir.path().join("bad.luax"), "return <div attr></div>")?;
+    std::fs::write(dir.path().join("worse.luax"), "return <p attr></p>")?;
+
+    let err = crate::luax::preprocess_dir(dir.path(), out.path()).unwrap_err();
+
+    match err.downcast_ref::<ReluaxError>() {
+        Some(ReluaxError::Preprocess(failures)) => {
+            let mut failed: Vec<_> = failures
+                .iter()
+                .map(|(path, _)| path.file_name().unwrap().to_owned())
+                .collect();
+            failed.sort();
+
+            assert_eq!(failed, vec!["bad.luax", "worse.luax"]);
+        }
+        _ => panic!("expected a preprocessing error, got {}", err),
+    }
+
+    assert!(out.path().join("good.lua").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn svg_fragment() -> Result<()> {
+    let input = r##"return <svg xmlns:xlink="http://www.w3.org/1999/xlink">
+        <use xlink:href="#icon"></use>
+        <g><circle r="4" /></g>
+        <text>Hi</text>
+    </svg>"##;
+
+    assert_eq!(
+        render_with(input, Syntax::Xml)?,
+        "<svg xmlns:xlink=\"http://www.w3.org/1999/xlink\"><use xlink:href=\"#icon\"/><g><circle r=\"4\"/></g><text>Hi</text></svg>"
+    );
+    assert_eq!(
+        render(input)?,
+        "<svg xmlns:xlink=\"http://www.w3.org/1999/xlink\"><use xlink:href=\"#icon\"></use><g><circle r=\"4\"></circle></g><text>Hi</text></svg>"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn rss_item() -> Result<()> {
+    let src = preprocess(
+        r#"return <item>
+            <title>Fish & "Chips"</title>
+            <link>https://example.com/?a=1&b=2</link>
+            <guid isPermaLink="false">42</guid>
+            <enclosure url={$ "https://example.com/a.mp3?x=<1>" $} />
+        </item>"#,
+    )?;
+
+    let lua = rlua::Lua::new();
+    let xml = lua.context(|ctx| -> Result<String> {
+        let mut xml = Vec::new();
+        table_to_xml(ctx.load(&src).eval()?, &mut xml)?;
+        Ok(String::from_utf8(xml)?)
+    })?;
+
+    assert_eq!(
+        xml,
+        "<item><title>Fish &amp; &quot;Chips&quot;</title><link>https://example.com/?a=1&amp;b=2</link><guid isPermaLink=\"false\">42</guid><enclosure url=\"https://example.com/a.mp3?x=&lt;1&gt;\"/></item>"
+    );
+
+    Ok(())
+}
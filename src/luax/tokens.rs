@@ -74,11 +74,13 @@ pub enum Token<'s> {
     Bang,
     OpenClosingTag,
     LuaStart,
+    /// `{$=`, starting a block whose value is trusted HTML
+    LuaStartRaw,
     LuaEnd,
     Eof,
     HtmlTextChar(char),
     Unknown(char),
-    Whitespace,
+    Whitespace(char),
 }
 
 impl std::fmt::Display for Token<'_> {
@@ -148,13 +150,14 @@ impl std::fmt::Display for Token<'_> {
             Token::Identifier(s) => write!(f, "{}", s),
             Token::OpenClosingTag => write!(f, "</"),
             Token::LuaStart => write!(f, "{{$"),
+            Token::LuaStartRaw => write!(f, "{{$="),
             Token::LuaEnd => write!(f, "$}}"),
 
             Token::Bang => write!(f, "!"),
             Token::Eof => write!(f, "EOF"),
             Token::HtmlTextChar(c) => write!(f, "{}", c),
             Token::Unknown(c) => write!(f, "{}", c),
-            Token::Whitespace => write!(f, " "),
+            Token::Whitespace(c) => write!(f, "{}", c),
         }
     }
 }
@@ -1,44 +1,77 @@
 use std::{
+    future::Future,
     io::Write,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
 use clap::Parser;
-use color_eyre::{owo_colors::OwoColorize, Result};
+use color_eyre::{eyre::WrapErr, owo_colors::OwoColorize, Result};
 
+mod cache;
+mod config;
 mod error;
+mod export;
+mod log;
 mod luax;
+mod rate_limit;
 mod server;
+mod watch;
+
+pub(crate) use log::{debug, error, info, warn};
+use rate_limit::RateLimit;
 
 #[derive(Debug, Clone, clap::Parser)]
 #[clap(about = "⛱️  A LuaX web framework")]
+struct Cli {
+    #[clap(
+        short = 'q',
+        long = "quiet",
+        global = true,
+        default_value = "false",
+        help = "Only print warnings and errors"
+    )]
+    quiet: bool,
+    #[clap(
+        long = "verbose",
+        global = true,
+        default_value = "false",
+        conflicts_with = "quiet",
+        help = "Also print debugging details"
+    )]
+    verbose: bool,
+    #[clap(subcommand)]
+    command: Args,
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
 enum Args {
     #[clap(
         name = "serve",
         about = "Serve a directory of LuaX files in production mode"
     )]
     Serve {
+        #[clap(flatten)]
+        serve_args: ServeArgs,
         #[clap(
-            short = 'C',
-            long = "change-dir",
-            default_value = ".",
-            help = "The directory to serve LuaX files from"
+            long = "sandbox",
+            default_value = "false",
+            help = "Run the Lua code without the io and debug libraries, and only the time functions of os"
         )]
-        change_dir: std::path::PathBuf,
+        sandbox: bool,
         #[clap(
-            short = 'p',
-            long = "port",
-            default_value = "4310",
-            help = "The port to serve on"
+            long = "memory-limit",
+            help = "The memory each Lua state can use in MiB, failing routes which need more with 500"
         )]
-        port: u16,
+        memory_limit: Option<usize>,
         #[clap(
-            short = 'l',
-            long = "local",
-            default_value = "false",
-            help = "Do not use a temporary directory for preprocessing"
+            long = "instruction-limit",
+            help = "The number of Lua instructions a route can run, failing it with 500 past that"
         )]
-        local: bool,
+        instruction_limit: Option<u64>,
     },
     #[clap(name = "build", about = "Build a directory of LuaX files")]
     Build {
@@ -56,99 +89,270 @@ enum Args {
             help = "The directory to output the built files to"
         )]
         output_dir: std::path::PathBuf,
+        #[clap(
+            short = 'P',
+            long = "public-dir",
+            help = "A directory of static files to copy into the output directory"
+        )]
+        public_dir: Option<std::path::PathBuf>,
+        #[clap(
+            long = "validate",
+            default_value = "false",
+            help = "Fail the build on statically detectable HTML problems"
+        )]
+        validate: bool,
+        #[clap(
+            long = "export",
+            default_value = "false",
+            help = "Render the pages listed by the manifest into static HTML files"
+        )]
+        export: bool,
+        #[clap(
+            long = "force",
+            default_value = "false",
+            help = "Preprocess every LuaX file, even the ones whose output is up to date"
+        )]
+        force: bool,
+        #[clap(
+            long = "no-prune",
+            default_value = "false",
+            help = "Keep the outputs of LuaX files which were renamed or deleted"
+        )]
+        no_prune: bool,
+        #[clap(
+            short = 'w',
+            long = "watch",
+            default_value = "false",
+            help = "Build again whenever the project changes, until Ctrl-C"
+        )]
+        watch: bool,
     },
     #[clap(
         name = "dev",
         about = "Serve a directory of LuaX files in development mode"
     )]
     Dev {
-        #[clap(
-            short = 'C',
-            long = "change-dir",
-            default_value = ".",
-            help = "The directory to serve LuaX files from"
-        )]
-        change_dir: std::path::PathBuf,
+        #[clap(flatten)]
+        serve_args: ServeArgs,
         #[clap(
             short = 'P',
             long = "public-dir",
-            default_value = ".",
-            help = "The static files directory to serve"
+            help = "The static files directory to serve [default: .]"
         )]
-        public_dir: std::path::PathBuf,
-        #[clap(
-            short = 'p',
-            long = "port",
-            default_value = "4310",
-            help = "The port to serve on"
-        )]
-        port: u16,
-        #[clap(
-            short = 'l',
-            long = "local",
-            default_value = "false",
-            help = "Do not use a temporary directory for preprocessing"
-        )]
-        local: bool,
+        public_dir: Option<std::path::PathBuf>,
     },
     #[clap(name = "new", about = "Create a new project")]
     New {
         #[clap(help = "The name of the project")]
         name: String,
+        #[clap(
+            short = 't',
+            long = "template",
+            value_enum,
+            default_value = "minimal",
+            help = "The starter template to use"
+        )]
+        template: Template,
     },
     #[clap(
         name = "init",
         about = "Initialize a new project in the current directory"
     )]
-    Init,
+    Init {
+        #[clap(
+            short = 't',
+            long = "template",
+            value_enum,
+            default_value = "minimal",
+            help = "The starter template to use"
+        )]
+        template: Template,
+    },
+}
+
+/// The flags shared by the `serve` and `dev` commands
+#[derive(Debug, Clone, clap::Args)]
+struct ServeArgs {
+    #[clap(
+        short = 'C',
+        long = "change-dir",
+        default_value = ".",
+        help = "The directory to serve LuaX files from"
+    )]
+    change_dir: std::path::PathBuf,
+    #[clap(
+        short = 'H',
+        long = "host",
+        alias = "bind",
+        help = "The IP address to bind to [default: 127.0.0.1]"
+    )]
+    host: Option<IpAddr>,
+    #[clap(
+        short = 'p',
+        long = "port",
+        help = "The port to serve on [default: 4310]"
+    )]
+    port: Option<u16>,
+    #[clap(
+        short = 'l',
+        long = "local",
+        default_value = "false",
+        help = "Do not use a temporary directory for preprocessing"
+    )]
+    local: bool,
+    #[clap(
+        short = 'w',
+        long = "workers",
+        help = "The number of Lua states handling requests in parallel [default: number of CPUs]"
+    )]
+    workers: Option<usize>,
+    #[clap(
+        long = "max-body-size",
+        help = "The maximum size of a request body in bytes [default: 2097152]"
+    )]
+    max_body_size: Option<usize>,
+    #[clap(
+        short = 'v',
+        long = "log",
+        default_value = "false",
+        help = "Log every request with its status and timing"
+    )]
+    log: bool,
+    #[clap(
+        long = "trust-proxy",
+        default_value = "false",
+        help = "Take the client address from X-Forwarded-For, when behind a reverse proxy"
+    )]
+    trust_proxy: bool,
+    #[clap(
+        long = "route-timeout",
+        help = "Answer with 503 and interrupt routes running longer than this many seconds"
+    )]
+    route_timeout: Option<u64>,
+    #[clap(
+        long = "max-connections",
+        help = "The number of connections served at once, with the rest waiting to be accepted [default: 1024]"
+    )]
+    max_connections: Option<NonZeroUsize>,
+    #[clap(
+        long = "no-keep-alive",
+        default_value = "false",
+        help = "Close HTTP/1.1 connections after every response"
+    )]
+    no_keep_alive: bool,
+    #[clap(
+        long = "rate-limit",
+        help = "The number of requests a second allowed from each client IP, answering others with 429"
+    )]
+    rate_limit: Option<u32>,
+    #[clap(
+        long = "rate-burst",
+        help = "The number of requests a client can make at once under the rate limit [default: the rate limit]"
+    )]
+    rate_burst: Option<u32>,
+}
+
+/// The starter projects `new` and `init` can create
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Template {
+    /// A single page with some styling
+    Minimal,
+    /// A Markdown blog with a post list and a shared layout
+    Blog,
+    /// A JSON API managing a list of items
+    Api,
+}
+
+impl Template {
+    /// The files of the template, as paths relative to the project root and
+    /// their contents, in which `PROJECT_NAME` is replaced with the project name
+    fn files(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Template::Minimal => &[
+                (
+                    "reluax.luax",
+                    include_str!("../templates/minimal/reluax.luax"),
+                ),
+                (
+                    "index.luax",
+                    include_str!("../templates/minimal/index.luax"),
+                ),
+                ("style.css", include_str!("../templates/minimal/style.css")),
+            ],
+            Template::Blog => &[
+                ("reluax.luax", include_str!("../templates/blog/reluax.luax")),
+                ("layout.luax", include_str!("../templates/blog/layout.luax")),
+                ("posts.lua", include_str!("../templates/blog/posts.lua")),
+                ("style.css", include_str!("../templates/blog/style.css")),
+            ],
+            Template::Api => &[("reluax.luax", include_str!("../templates/api/reluax.luax"))],
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
     color_eyre::install().unwrap();
-    let args = Args::parse();
+    let cli = Cli::parse();
 
-    match args {
+    if cli.quiet {
+        log::set_level(log::Level::Warn);
+    } else if cli.verbose {
+        log::set_level(log::Level::Debug);
+    }
+
+    match cli.command {
         Args::Serve {
-            change_dir,
-            port,
-            local,
+            serve_args,
+            sandbox,
+            memory_limit,
+            instruction_limit,
         } => {
-            if !change_dir.is_dir() {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    format!("{} is not a directory", change_dir.display()),
-                )
-                .into());
-            }
+            let config = load_config(&serve_args.change_dir)?;
 
-            println!("🌴 Project root: {}", change_dir.display().bright_yellow());
+            let options = ServeOptions {
+                sandbox: sandbox || config.sandbox.unwrap_or(false),
+                memory_limit: memory_limit
+                    .or(config.memory_limit)
+                    .map(|mib| mib * 1024 * 1024),
+                instruction_limit: instruction_limit.or(config.instruction_limit),
+                ..serve_options(&serve_args, &config, false)?
+            };
 
-            if local {
-                serve_locally(change_dir, false, port, None).await
+            if serve_args.local {
+                serve_locally(serve_args.change_dir, options).await
             } else {
-                serve_from_temp(change_dir, false, port, None).await
+                serve_from_temp(serve_args.change_dir, options).await
             }
         }
         Args::Build {
             change_dir,
             output_dir,
-        } => build(change_dir, output_dir),
+            public_dir,
+            validate,
+            export,
+            force,
+            no_prune,
+            watch,
+        } => {
+            let options = BuildOptions {
+                validate,
+                export,
+                force,
+                prune: !no_prune,
+            };
+
+            build(change_dir, output_dir, public_dir, options, watch).await
+        }
         Args::Dev {
-            change_dir,
+            serve_args,
             public_dir,
-            port,
-            local,
         } => {
-            if !change_dir.is_dir() {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    format!("{} is not a directory", change_dir.display()),
-                )
-                .into());
-            }
+            let config = load_config(&serve_args.change_dir)?;
 
-            println!("🌴 Project root: {}", change_dir.display().bright_yellow());
+            let public_dir = public_dir
+                .or_else(|| config.public_dir.clone())
+                .unwrap_or_else(|| PathBuf::from("."));
 
             if !public_dir.is_dir() {
                 return Err(std::io::Error::new(
@@ -158,85 +362,178 @@ async fn main() -> color_eyre::Result<()> {
                 .into());
             }
 
-            println!(
+            info!(
                 "🌴 Public directory: {}",
                 public_dir.display().bright_yellow()
             );
-            let public_dir = Some(public_dir.canonicalize()?);
 
-            if local {
-                serve_locally(change_dir, true, port, public_dir).await
+            let options = ServeOptions {
+                public_dir: Some(public_dir.canonicalize()?),
+                ..serve_options(&serve_args, &config, true)?
+            };
+
+            if serve_args.local {
+                serve_locally(serve_args.change_dir, options).await
             } else {
-                serve_from_temp(change_dir, true, port, public_dir).await
+                serve_from_temp(serve_args.change_dir, options).await
             }
         }
-        Args::New { name } => create_project(&name),
-        Args::Init => init_project(),
+        Args::New { name, template } => create_project(&name, template),
+        Args::Init { template } => init_project(template),
     }
 }
 
-async fn serve_locally(
-    change_dir: PathBuf,
+/// Options shared by the `serve` and `dev` commands
+struct ServeOptions {
     dev_mode: bool,
-    port: u16,
+    source_dir: PathBuf,
+    addr: SocketAddr,
     public_dir: Option<PathBuf>,
-) -> Result<()> {
-    println!("🌴 Running in local mode");
-    std::env::set_current_dir(&change_dir)?;
-    preprocess_current_dir().await?;
-
-    ensure_entry_point().await?;
-
-    serve(dev_mode, port, public_dir).await
+    workers: usize,
+    max_body_size: usize,
+    log: bool,
+    trust_proxy: bool,
+    route_timeout: Option<Duration>,
+    max_connections: usize,
+    keep_alive: bool,
+    rate_limit: Option<RateLimit>,
+    sandbox: bool,
+    /// In bytes, for each Lua state
+    memory_limit: Option<usize>,
+    instruction_limit: Option<u64>,
 }
 
-async fn serve_from_temp(
-    change_dir: PathBuf,
-    dev_mode: bool,
-    port: u16,
-    public_dir: Option<PathBuf>,
-) -> Result<()> {
-    // Create a /tmp/reluax-XXXXXX directory for the server to pre-process files in.
-    let tmp_dir = tempfile::Builder::new()
-        .prefix("reluax-")
-        .tempdir()
-        .unwrap();
-
-    if !tmp_dir.path().is_dir() {
+/// Check the project directory of `serve` or `dev` and load its config
+fn load_config(change_dir: &Path) -> Result<config::Config> {
+    if !change_dir.is_dir() {
         return Err(std::io::Error::new(
             std::io::ErrorKind::NotFound,
-            format!("{} is not a directory", tmp_dir.path().display()),
+            format!("{} is not a directory", change_dir.display()),
         )
         .into());
     }
-    println!(
-        "⏲️  Created temporary directory {}",
-        tmp_dir.path().display().bright_blue()
-    );
 
-    println!(
-        "⏲️  Will serve Lua from {}",
-        tmp_dir.path().display().bright_blue()
-    );
+    info!("🌴 Project root: {}", change_dir.display().bright_yellow());
+
+    let config = config::Config::load(change_dir)?;
+    if let Some(secret) = &config.secret {
+        luax::set_default_secret(secret);
+    }
 
-    let copied = recurse_copy_lua(&change_dir, tmp_dir.path())?;
+    Ok(config)
+}
+
+/// The options of `serve` or `dev` from their shared flags, falling back to
+/// the config, without a public directory, sandboxing or limits
+fn serve_options(
+    args: &ServeArgs,
+    config: &config::Config,
+    dev_mode: bool,
+) -> Result<ServeOptions> {
+    Ok(ServeOptions {
+        dev_mode,
+        source_dir: args.change_dir.canonicalize()?,
+        addr: SocketAddr::new(
+            args.host.or(config.host).unwrap_or(config::DEFAULT_HOST),
+            args.port.or(config.port).unwrap_or(config::DEFAULT_PORT),
+        ),
+        public_dir: None,
+        workers: workers_or_default(args.workers.or(config.workers)),
+        max_body_size: args
+            .max_body_size
+            .or(config.body_limit)
+            .unwrap_or(config::DEFAULT_MAX_BODY_SIZE),
+        log: args.log,
+        trust_proxy: args.trust_proxy || config.trust_proxy.unwrap_or(false),
+        route_timeout: args
+            .route_timeout
+            .or(config.route_timeout)
+            .map(Duration::from_secs),
+        max_connections: args
+            .max_connections
+            .or(config.max_connections)
+            .map_or(config::DEFAULT_MAX_CONNECTIONS, NonZeroUsize::get),
+        keep_alive: !args.no_keep_alive && config.keep_alive.unwrap_or(true),
+        rate_limit: args.rate_limit.or(config.rate_limit).map(|rate| RateLimit {
+            rate,
+            burst: args.rate_burst.or(config.rate_burst).unwrap_or(rate),
+        }),
+        sandbox: false,
+        memory_limit: None,
+        instruction_limit: None,
+    })
+}
 
-    println!("⏲️  {} files copied", copied.bright_green());
+/// Options of the `build` command which apply to every build in watch mode
+#[derive(Clone, Copy)]
+struct BuildOptions {
+    validate: bool,
+    export: bool,
+    force: bool,
+    prune: bool,
+}
 
-    std::env::set_current_dir(tmp_dir.path())?;
+fn workers_or_default(workers: Option<usize>) -> usize {
+    workers
+        .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1)
+        .max(1)
+}
 
+async fn serve_locally(change_dir: PathBuf, options: ServeOptions) -> Result<()> {
+    info!("🌴 Running in local mode");
+    std::env::set_current_dir(&change_dir)?;
     preprocess_current_dir().await?;
 
     ensure_entry_point().await?;
 
-    serve(dev_mode, port, public_dir).await
+    serve(options).await
+}
+
+async fn serve_from_temp(change_dir: PathBuf, options: ServeOptions) -> Result<()> {
+    let original_dir = std::env::current_dir()?;
+
+    // the server pre-processes and serves a copy of the project in a
+    // /tmp/reluax-XXXXXX directory, which lives exactly as long as it does
+    in_temp_dir(|tmp_dir| async move {
+        info!(
+            "⏲️  Created temporary directory {}",
+            tmp_dir.display().bright_blue()
+        );
+
+        info!(
+            "⏲️  Will serve Lua from {}",
+            tmp_dir.display().bright_blue()
+        );
+
+        let copied = recurse_copy_lua(&change_dir, &tmp_dir)?;
+
+        info!("⏲️  {} files copied", copied.bright_green());
+
+        std::env::set_current_dir(&tmp_dir)?;
+
+        let res = async {
+            preprocess_current_dir().await?;
+
+            ensure_entry_point().await?;
+
+            serve(options).await
+        }
+        .await;
+
+        // leave the directory so that it can be removed
+        std::env::set_current_dir(&original_dir)?;
+
+        res
+    })
+    .await
 }
 
 async fn preprocess_current_dir() -> Result<()> {
     let current_dir = std::env::current_dir()?;
     let preprocessed = luax::preprocess_dir(current_dir.as_path(), current_dir.as_path())?;
 
-    println!(
+    info!(
         "⛱️  {} Reluax files preprocessed!",
         preprocessed.bright_green()
     );
@@ -258,23 +555,121 @@ async fn ensure_entry_point() -> Result<()> {
     Ok(())
 }
 
-async fn serve(dev_mode: bool, port: u16, public_dir: Option<PathBuf>) -> Result<()> {
-    println!("📦 Building Lua state...");
-    let lua = luax::prepare_lua(dev_mode)?;
-    lua.context(|ctx| -> Result<()> {
+async fn serve(options: ServeOptions) -> Result<()> {
+    info!("📦 Building {} Lua states...", options.workers);
+    if options.sandbox {
+        info!("🔒 Sandboxing Lua without io, debug and most of os");
+    }
+    let states = (0..options.workers)
+        .map(|_| -> Result<rlua::Lua> {
+            let lua = if options.sandbox {
+                luax::prepare_sandboxed_lua(options.dev_mode)?
+            } else {
+                luax::prepare_lua(options.dev_mode)?
+            };
+            Ok(lua)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    states[0].context(|ctx| -> Result<()> {
         let entry_table: rlua::Table = ctx.load("require('reluax')").eval()?;
         let project_name: Option<String> = entry_table.get("name")?;
 
         if let Some(name) = project_name {
-            println!("🌴 App name: {}", name.bright_yellow());
+            info!("🌴 App name: {}", name.bright_yellow());
+        }
+
+        // without this, a missing `route` only shows up as the first request failing
+        let has_routes = entry_table
+            .get::<_, Option<rlua::Table>>("routes")?
+            .is_some();
+        match entry_table.get::<_, rlua::Value>("route")? {
+            rlua::Value::Function(_) => {}
+            rlua::Value::Nil if has_routes => {}
+            rlua::Value::Nil => {
+                return Err(error::ReluaxError::Server(
+                    "reluax.luax must return a table with a `route` function, e.g. \
+                     `return { route = function(path, method) ... end }`"
+                        .to_string(),
+                )
+                .into())
+            }
+            route => {
+                return Err(error::ReluaxError::Server(format!(
+                    "`route` in reluax.luax must be a function, not a {}",
+                    route.type_name()
+                ))
+                .into())
+            }
         }
 
         Ok(())
     })?;
-    println!("🛫 Starting server on port {}...", port);
-    server::Server::serve(lua, port, public_dir).await
+    let lua = Arc::new(server::LuaPool::new(states));
+
+    // kept alive for as long as the server runs
+    let _watcher = if options.dev_mode {
+        Some(watch::watch_for_changes(
+            options.source_dir,
+            std::env::current_dir()?,
+            lua.clone(),
+        )?)
+    } else {
+        None
+    };
+
+    info!("🛫 Starting server on {}...", options.addr);
+    server::Server::serve_until(
+        lua,
+        options.addr,
+        server::Options {
+            dev_mode: options.dev_mode,
+            public_dir: options.public_dir,
+            max_body_size: options.max_body_size,
+            log: options.log,
+            trust_proxy: options.trust_proxy,
+            route_timeout: options.route_timeout,
+            memory_limit: options.memory_limit,
+            instruction_limit: options.instruction_limit,
+            max_connections: options.max_connections,
+            keep_alive: options.keep_alive,
+            rate_limit: options.rate_limit,
+        },
+        shutdown_signal(),
+    )
+    .await
 }
 
+/// Resolve on Ctrl-C, so that the server stops and cleans up after itself
+/// instead of the process being killed
+async fn shutdown_signal() {
+    if tokio::signal::ctrl_c().await.is_err() {
+        // without a handler, Ctrl-C still kills the process as usual
+        std::future::pending::<()>().await;
+    }
+
+    info!("👋 Shutting down...");
+}
+
+/// Run `f` in a new `reluax-XXXXXX` temporary directory, which is removed once
+/// the future it returns is done, whether it succeeded or not
+async fn in_temp_dir<T, F, Fut>(f: F) -> Result<T>
+where
+    F: FnOnce(PathBuf) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let tmp_dir = tempfile::Builder::new().prefix("reluax-").tempdir()?;
+
+    let res = f(tmp_dir.path().to_path_buf()).await;
+    // unlike dropping it, closing it reports if it couldn't be removed
+    let closed = tmp_dir.close();
+
+    let value = res?;
+    closed?;
+
+    Ok(value)
+}
+
+/// Copy a project to another directory, e.g. a temporary one to preprocess it in
 fn recurse_copy_lua(from: &Path, to: &Path) -> Result<usize> {
     let mut copied = 0;
     for entry in std::fs::read_dir(from)? {
@@ -284,7 +679,9 @@ fn recurse_copy_lua(from: &Path, to: &Path) -> Result<usize> {
         let to = to.join(file_name);
 
         if path.is_dir() {
-            std::fs::create_dir(&to)?;
+            if !to.is_dir() {
+                std::fs::create_dir(&to)?;
+            }
             copied += recurse_copy_lua(&path, &to)?;
         } else {
             std::fs::copy(&path, &to)?;
@@ -295,23 +692,56 @@ fn recurse_copy_lua(from: &Path, to: &Path) -> Result<usize> {
     Ok(copied)
 }
 
-fn create_project(name: &str) -> Result<()> {
+/// Copy a directory for a build, leaving out the LuaX files, which get
+/// preprocessed instead, and the output directory if it is inside `from`
+fn recurse_copy_static(from: &Path, to: &Path, output_dir: &Path) -> Result<usize> {
+    let mut copied = 0;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_name = path.file_name().unwrap();
+        let to = to.join(file_name);
+
+        if path == output_dir {
+            continue;
+        }
+
+        if path.is_dir() {
+            if !to.is_dir() {
+                std::fs::create_dir(&to)?;
+            }
+            copied += recurse_copy_static(&path, &to, output_dir)?;
+        } else if path.extension().unwrap_or_default() != "luax" {
+            std::fs::copy(&path, &to)?;
+            copied += 1;
+        }
+    }
+
+    Ok(copied)
+}
+
+fn create_project(name: &str, template: Template) -> Result<()> {
     let dir = PathBuf::from(name);
 
-    if dir.is_dir() {
-        println!("🛑 Directory {} already exists", dir.display().bright_red());
-        return Ok(());
+    if dir.exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} already exists", dir.display()),
+        )
+        .into());
     }
 
     std::fs::create_dir(&dir)?;
 
-    std::env::set_current_dir(&dir)?;
-
-    write_templates(name)?;
+    // don't leave a half-created project behind
+    if let Err(e) = write_templates(&dir, name, template) {
+        std::fs::remove_dir_all(&dir)?;
+        return Err(e);
+    }
 
-    println!("🌴 Created project {}", name.bright_yellow());
+    info!("🌴 Created project {}", name.bright_yellow());
 
-    println!(
+    info!(
         "🛠️  To start a development server, change to the {} directory and run {}.",
         name.bright_yellow(),
         "reluax dev".bright_green()
@@ -320,21 +750,21 @@ fn create_project(name: &str) -> Result<()> {
     Ok(())
 }
 
-fn init_project() -> Result<()> {
+fn init_project(template: Template) -> Result<()> {
     let dir = std::env::current_dir()?;
 
     if !dir.is_dir() {
-        println!("🛑 Directory {} does not exist", dir.display().bright_red());
+        error!("🛑 Directory {} does not exist", dir.display().bright_red());
         return Ok(());
     }
 
     let name = dir.file_name().unwrap().to_str().unwrap();
 
-    write_templates(name)?;
+    write_templates(&dir, name, template)?;
 
-    println!("🌴 Initialized project {}", name.bright_yellow());
+    info!("🌴 Initialized project {}", name.bright_yellow());
 
-    println!(
+    info!(
         "🛠️  To start a development server, run {}.",
         "reluax dev".bright_green()
     );
@@ -342,28 +772,32 @@ fn init_project() -> Result<()> {
     Ok(())
 }
 
-fn write_templates(name: &str) -> Result<()> {
-    let mut file = std::fs::File::create("reluax.luax")?;
-    file.write_all(
-        include_str!("../templates/reluax.luax")
-            .replace("PROJECT_NAME", name)
-            .as_bytes(),
-    )?;
+fn write_templates(dir: &Path, name: &str, template: Template) -> Result<()> {
+    for (path, contents) in template.files() {
+        let path = dir.join(path);
+
+        let write = || -> std::io::Result<()> {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
 
-    let mut file = std::fs::File::create("index.luax")?;
-    file.write_all(
-        include_str!("../templates/index.luax")
-            .replace("PROJECT_NAME", name)
-            .as_bytes(),
-    )?;
+            let mut file = std::fs::File::create(&path)?;
+            file.write_all(contents.replace("PROJECT_NAME", name).as_bytes())
+        };
 
-    let mut file = std::fs::File::create("style.css")?;
-    file.write_all(include_str!("../templates/style.css").as_bytes())?;
+        write().wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+    }
 
     Ok(())
 }
 
-fn build(change_dir: PathBuf, output_dir: PathBuf) -> Result<()> {
+async fn build(
+    change_dir: PathBuf,
+    output_dir: PathBuf,
+    public_dir: Option<PathBuf>,
+    options: BuildOptions,
+    watch: bool,
+) -> Result<()> {
     if !change_dir.is_dir() {
         return Err(std::io::Error::new(
             std::io::ErrorKind::NotFound,
@@ -372,24 +806,268 @@ fn build(change_dir: PathBuf, output_dir: PathBuf) -> Result<()> {
         .into());
     }
 
-    println!("🌴 Project root: {}", change_dir.display().bright_yellow());
+    info!("🌴 Project root: {}", change_dir.display().bright_yellow());
 
     if !output_dir.is_dir() {
         std::fs::create_dir(&output_dir)?;
     }
 
-    println!(
+    info!(
         "🌴 Output directory: {}",
         output_dir.display().bright_yellow()
     );
 
-    std::env::set_current_dir(&change_dir)?;
+    let config = config::Config::load(&change_dir)?;
+    let public_dir = public_dir.or(config.public_dir);
 
-    println!("📦 Preprocessing LuaX files...");
+    if let Some(public_dir) = &public_dir {
+        if !public_dir.is_dir() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} is not a directory", public_dir.display()),
+            )
+            .into());
+        }
+
+        info!(
+            "🌴 Public directory: {}",
+            public_dir.display().bright_yellow()
+        );
+    }
 
-    let built = luax::preprocess_dir(&change_dir, &output_dir)?;
+    let change_dir = change_dir.canonicalize()?;
+    let output_dir = output_dir.canonicalize()?;
+    let public_dir = public_dir.map(|dir| dir.canonicalize()).transpose()?;
 
-    println!("📦 {} LuaX files preprocessed!", built.bright_green());
+    if !watch {
+        return build_once(&change_dir, &output_dir, public_dir.as_deref(), options).await;
+    }
+
+    // rebuilding on the changes of a build would never end
+    if change_dir.starts_with(&output_dir) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--watch needs an output directory outside of the project root",
+        )
+        .into());
+    }
+
+    if let Err(e) = build_once(&change_dir, &output_dir, public_dir.as_deref(), options).await {
+        error!("🛑 Build failed: {}", e.bright_red());
+    }
+
+    let mut dirs = vec![change_dir.clone()];
+    if let Some(public_dir) = &public_dir {
+        if !public_dir.starts_with(&change_dir) {
+            dirs.push(public_dir.clone());
+        }
+    }
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    // kept alive until Ctrl-C
+    let _watcher = watch::watch(dirs, vec![output_dir.clone()], move || {
+        let _ = tx.send(());
+    })?;
+
+    // only the first build is forced, the next ones only redo what changed
+    let options = BuildOptions {
+        force: false,
+        ..options
+    };
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            changed = rx.recv() => {
+                if changed.is_none() {
+                    break;
+                }
+
+                let started = std::time::Instant::now();
+                match build_once(&change_dir, &output_dir, public_dir.as_deref(), options).await {
+                    Ok(()) => info!(
+                        "🔄 Rebuilt in {} ms",
+                        started.elapsed().as_millis().bright_green()
+                    ),
+                    Err(e) => error!("🛑 Build failed: {}", e.bright_red()),
+                }
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy the public directory into the output directory, unless it was already
+/// copied along with the project or would be copied onto itself, returning
+/// how many files were copied
+fn copy_public_dir(
+    change_dir: &Path,
+    output_dir: &Path,
+    public_dir: &Path,
+) -> Result<Option<usize>> {
+    if public_dir.starts_with(change_dir) {
+        return Ok(None);
+    }
+
+    let name = public_dir.file_name().unwrap_or("public".as_ref());
+    let to = output_dir.join(name);
+
+    // copying a file onto itself truncates it
+    if to.canonicalize().map_or(false, |to| to == public_dir) {
+        return Ok(None);
+    }
+
+    if !to.is_dir() {
+        std::fs::create_dir(&to)?;
+    }
+
+    recurse_copy_static(public_dir, &to, output_dir).map(Some)
+}
+
+/// Build the project once into the output directory, with canonical paths
+async fn build_once(
+    change_dir: &Path,
+    output_dir: &Path,
+    public_dir: Option<&Path>,
+    options: BuildOptions,
+) -> Result<()> {
+    let BuildOptions {
+        validate,
+        export,
+        force,
+        prune,
+    } = options;
+
+    if validate {
+        info!("🔍 Validating HTML...");
+
+        let problems = luax::validate_dir(change_dir)?;
+
+        if !problems.is_empty() {
+            for problem in problems.iter() {
+                error!("🛑 {}", problem.bright_red());
+            }
+
+            return Err(error::ReluaxError::Validation(problems.len()).into());
+        }
+    }
+
+    std::env::set_current_dir(change_dir)?;
+
+    if change_dir != output_dir {
+        info!("📦 Copying static files...");
+
+        let copied = recurse_copy_static(change_dir, output_dir, output_dir)?;
+
+        info!("📦 {} files copied", copied.bright_green());
+    }
+
+    if let Some(public_dir) = public_dir {
+        if let Some(copied) = copy_public_dir(change_dir, output_dir, public_dir)? {
+            info!("📦 {} public files copied", copied.bright_green());
+        }
+    }
+
+    info!("📦 Preprocessing LuaX files...");
+
+    let built = luax::preprocess_dir_incremental(change_dir, output_dir, force, prune)?;
+
+    info!(
+        "📦 {} LuaX files preprocessed, {} up to date!",
+        built.processed.bright_green(),
+        built.skipped.bright_green()
+    );
+    if built.pruned > 0 {
+        info!("📦 {} stale outputs removed!", built.pruned.bright_green());
+    }
+
+    if export {
+        // the preprocessed modules are required from the output directory
+        std::env::set_current_dir(output_dir)?;
+
+        info!("📦 Exporting pages...");
+
+        let lua = Arc::new(server::LuaPool::new(vec![luax::prepare_lua(false)?]));
+        let exported = export::export(lua, output_dir).await?;
+
+        info!("📦 {} pages exported!", exported.bright_green());
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{copy_public_dir, in_temp_dir};
+
+    #[test]
+    fn public_dir_is_not_copied_onto_itself() {
+        let project = tempfile::tempdir().unwrap();
+        let project = project.path().canonicalize().unwrap();
+        let public_dir = project.join("public");
+        std::fs::create_dir(&public_dir).unwrap();
+        std::fs::write(public_dir.join("style.css"), "body {}").unwrap();
+
+        // `-o .` with the public directory inside the project
+        assert_eq!(
+            copy_public_dir(&project, &project, &public_dir).unwrap(),
+            None
+        );
+        assert_eq!(
+            std::fs::read_to_string(public_dir.join("style.css")).unwrap(),
+            "body {}"
+        );
+
+        // a public directory elsewhere, output next to it
+        let elsewhere = tempfile::tempdir().unwrap();
+        let elsewhere = elsewhere.path().canonicalize().unwrap();
+        assert_eq!(
+            copy_public_dir(&elsewhere, &project, &public_dir).unwrap(),
+            None
+        );
+        assert_eq!(
+            std::fs::read_to_string(public_dir.join("style.css")).unwrap(),
+            "body {}"
+        );
+
+        let output = tempfile::tempdir().unwrap();
+        let output = output.path().canonicalize().unwrap();
+        assert_eq!(
+            copy_public_dir(&elsewhere, &output, &public_dir).unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            std::fs::read_to_string(output.join("public/style.css")).unwrap(),
+            "body {}"
+        );
+    }
+
+    #[tokio::test]
+    async fn temp_dir_is_removed() {
+        let dir = in_temp_dir(|dir| async move {
+            std::fs::write(dir.join("reluax.lua"), "return {}")?;
+            assert!(dir.join("reluax.lua").is_file());
+            Ok(dir)
+        })
+        .await
+        .unwrap();
+        assert!(!dir.exists());
+
+        // also when whatever ran in it failed
+        let mut used = None;
+        let res: color_eyre::Result<()> = in_temp_dir(|dir| {
+            used = Some(dir.clone());
+            async move {
+                std::fs::create_dir(dir.join("nested"))?;
+                Err(std::io::Error::new(std::io::ErrorKind::Other, "server failed").into())
+            }
+        })
+        .await;
+        assert!(res.is_err());
+        assert!(!used.unwrap().exists());
+    }
+}
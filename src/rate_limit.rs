@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_CLIENTS: usize = 10_000;
+
+/// How many requests a client may make: `rate` a second on average, with up
+/// to `burst` of them at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub rate: u32,
+    pub burst: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket rate limiter keyed by client IP
+///
+/// At most `max_clients` buckets are kept. When there are too many, the full
+/// ones are dropped first, since a new bucket is full anyway, then the ones
+/// used the longest time ago, a quarter of them at once so that the scan is
+/// only paid for once every so many new clients.
+pub struct RateLimiter {
+    limit: RateLimit,
+    max_clients: usize,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit, max_clients: usize) -> Self {
+        Self {
+            limit,
+            max_clients,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Take a token for a request from the client, or get how long it has to
+    /// wait for the next one
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let rate = self.limit.rate as f64;
+        let burst = self.limit.burst.max(1) as f64;
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= self.max_clients && !buckets.contains_key(&ip) {
+            buckets.retain(|_, bucket| Self::refill(bucket, rate, burst, now) < burst);
+
+            if buckets.len() >= self.max_clients {
+                Self::evict_least_recent(&mut buckets, self.max_clients);
+            }
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        let tokens = Self::refill(bucket, rate, burst, now);
+        bucket.updated = now;
+
+        if tokens >= 1.0 {
+            bucket.tokens = tokens - 1.0;
+            Ok(())
+        } else if rate == 0.0 {
+            bucket.tokens = tokens;
+            Err(Duration::MAX)
+        } else {
+            bucket.tokens = tokens;
+            Err(Duration::from_secs_f64((1.0 - tokens) / rate))
+        }
+    }
+
+    /// Drop the least recently used buckets, leaving room for a quarter of
+    /// `max_clients` new ones
+    fn evict_least_recent(buckets: &mut HashMap<IpAddr, Bucket>, max_clients: usize) {
+        let keep = (max_clients - max_clients / 4).min(max_clients.saturating_sub(1));
+        let mut by_use: Vec<(Instant, IpAddr)> = buckets
+            .iter()
+            .map(|(ip, bucket)| (bucket.updated, *ip))
+            .collect();
+
+        let evicted = by_use.len().saturating_sub(keep);
+        if evicted < by_use.len() {
+            by_use.select_nth_unstable(evicted);
+        }
+
+        for (_, ip) in &by_use[..evicted] {
+            buckets.remove(ip);
+        }
+    }
+
+    /// The tokens in a bucket at `now`, counting the ones added since it was
+    /// last used
+    fn refill(bucket: &Bucket, rate: f64, burst: f64, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+
+        (bucket.tokens + elapsed * rate).min(burst)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{Duration, Instant};
+
+    use super::{RateLimit, RateLimiter};
+
+    fn ip(n: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, n))
+    }
+
+    #[test]
+    fn burst_then_rate() {
+        let limiter = RateLimiter::new(RateLimit { rate: 2, burst: 3 }, 10);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(ip(1), now).is_ok());
+        }
+        assert_eq!(
+            limiter.check_at(ip(1), now),
+            Err(Duration::from_millis(500))
+        );
+
+        // other clients have their own buckets
+        assert!(limiter.check_at(ip(2), now).is_ok());
+
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.check_at(ip(1), later).is_ok());
+        assert!(limiter.check_at(ip(1), later).is_err());
+    }
+
+    #[test]
+    fn bounded() {
+        let limiter = RateLimiter::new(RateLimit { rate: 1, burst: 1 }, 2);
+        let now = Instant::now();
+        let at = |millis| now + Duration::from_millis(millis);
+
+        assert!(limiter.check_at(ip(1), at(0)).is_ok());
+        assert!(limiter.check_at(ip(2), at(100)).is_ok());
+        assert!(limiter.check_at(ip(3), at(200)).is_ok());
+        assert_eq!(limiter.len(), 2);
+
+        // the least recently used client was forgotten, so it starts afresh
+        assert!(limiter.check_at(ip(1), at(200)).is_ok());
+        assert_eq!(limiter.len(), 2);
+
+        // full buckets are dropped before anything else
+        let later = now + Duration::from_secs(5);
+        assert!(limiter.check_at(ip(4), later).is_ok());
+        assert!(limiter.check_at(ip(5), later).is_ok());
+        assert_eq!(limiter.len(), 2);
+    }
+
+    #[test]
+    fn evicts_in_batches() {
+        let limiter = RateLimiter::new(RateLimit { rate: 1, burst: 1 }, 8);
+        let now = Instant::now();
+
+        for n in 0..8 {
+            assert!(limiter.check_at(ip(n), now).is_ok());
+        }
+        assert_eq!(limiter.len(), 8);
+
+        // a quarter of the clients make room at once for the next ones
+        assert!(limiter.check_at(ip(8), now).is_ok());
+        assert_eq!(limiter.len(), 7);
+        assert!(limiter.check_at(ip(9), now).is_ok());
+        assert_eq!(limiter.len(), 8);
+    }
+}
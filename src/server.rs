@@ -1,97 +1,958 @@
+use std::collections::HashMap;
 use std::future::Future;
 use std::io::Write;
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Component, Path, PathBuf};
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use http_body_util::{BodyExt, Collected, Full};
-use hyper::body::{Bytes, Incoming};
-use hyper::server::conn::http1;
+use http_body_util::{BodyExt, Collected, Either, Full, LengthLimitError, Limited};
+use hyper::body::{Body, Bytes, Frame, Incoming};
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD,
+    ALLOW, CONTENT_LENGTH, RETRY_AFTER, SET_COOKIE,
+};
 use hyper::service::Service;
 use hyper::{Method, Request, Response, StatusCode};
-use hyper_util::rt::TokioIo;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
 use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch, Semaphore};
 
+use crate::cache::{CachePolicy, ResponseCache};
 use crate::error::ReluaxError;
-use crate::luax::{table_to_html, table_to_json};
-use color_eyre::Result;
+use crate::luax::utils::{url_extract, url_matches};
+use crate::luax::{self, table_to_html, table_to_json, table_to_markup, table_to_xml, Syntax};
+use crate::rate_limit::{RateLimit, RateLimiter, DEFAULT_MAX_CLIENTS};
+use crate::{debug, error, info, warn};
+use color_eyre::{eyre::eyre, owo_colors::OwoColorize, Result};
 use rlua::Lua;
 
 pub struct Server {
-    port: u16,
+    addr: SocketAddr,
     state: State,
+    connections: ConnectionOptions,
 }
 
+/// How the server handles requests
+pub struct Options {
+    pub dev_mode: bool,
+    pub public_dir: Option<PathBuf>,
+    pub max_body_size: usize,
+    pub log: bool,
+    pub trust_proxy: bool,
+    pub route_timeout: Option<Duration>,
+    /// In bytes, for each Lua state, as counted by `collectgarbage("count")`
+    pub memory_limit: Option<usize>,
+    /// The number of Lua instructions a route can run before it fails
+    pub instruction_limit: Option<u64>,
+    /// Connections beyond this wait to be accepted until others close
+    pub max_connections: usize,
+    pub keep_alive: bool,
+    /// Requests from a client beyond this are answered with `429`
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// How connections are accepted and kept open
+#[derive(Debug, Clone, Copy)]
+struct ConnectionOptions {
+    max_connections: usize,
+    keep_alive: bool,
+    /// Connections without a request for this long are closed
+    idle_timeout: Duration,
+}
+
+/// How long a client has to send the headers of a request once it started
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a connection is kept open without requests, so that idle ones
+/// don't hold on to their slot under `max_connections`
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long open connections get to finish their requests on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Clone)]
 struct State {
-    lua: Arc<Mutex<Lua>>,
+    lua: Arc<LuaPool>,
+    dev_mode: bool,
     public_dir: Option<PathBuf>,
+    max_body_size: usize,
+    log: bool,
+    // set for each connection
+    remote_addr: Option<SocketAddr>,
+    trust_proxy: bool,
+    route_timeout: Option<Duration>,
+    memory_limit: Option<usize>,
+    instruction_limit: Option<u64>,
+    cache: Arc<ResponseCache>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+/// A fixed set of prepared Lua states, so that requests can be handled in parallel
+pub struct LuaPool {
+    states: Vec<Mutex<Lua>>,
+    next: AtomicUsize,
+    // bumped on every reload
+    generation: watch::Sender<u64>,
+}
+
+impl LuaPool {
+    pub fn new(states: Vec<Lua>) -> Self {
+        assert!(!states.is_empty(), "a Lua pool needs at least one state");
+
+        for lua in states.iter() {
+            resolve_manifest(lua);
+        }
+
+        Self {
+            states: states.into_iter().map(Mutex::new).collect(),
+            next: AtomicUsize::new(0),
+            generation: watch::channel(0).0,
+        }
+    }
+
+    /// Get a free Lua state, waiting for one if they are all busy
+    pub fn get(&self) -> MutexGuard<'_, Lua> {
+        self.get_indexed().1
+    }
+
+    /// Get a free Lua state along with its index in the pool, so that the same
+    /// state can be used again later
+    fn get_indexed(&self) -> (usize, MutexGuard<'_, Lua>) {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+
+        for i in 0..self.states.len() {
+            let index = (start + i) % self.states.len();
+            if let Ok(lua) = self.states[index].try_lock() {
+                return (index, lua);
+            }
+        }
+
+        let index = start % self.states.len();
+        debug!(
+            "All {} Lua states are busy, waiting for state {}",
+            self.states.len(),
+            index
+        );
+        (index, self.states[index].lock().unwrap())
+    }
+
+    /// Get the state at `index`, waiting for it if it is busy
+    fn get_state(&self, index: usize) -> MutexGuard<'_, Lua> {
+        self.states[index].lock().unwrap()
+    }
+
+    /// Replace every state with a freshly prepared one, waiting for the
+    /// requests using them to finish
+    pub fn reload(&self, prepare: impl Fn() -> Result<Lua>) -> Result<()> {
+        for state in self.states.iter() {
+            let lua = prepare()?;
+            resolve_manifest(&lua);
+            *state.lock().unwrap() = lua;
+        }
+
+        self.generation.send_modify(|generation| *generation += 1);
+        debug!(
+            "{} Lua states reloaded, generation {}",
+            self.states.len(),
+            self.generation()
+        );
+
+        Ok(())
+    }
+
+    /// The number of times the states were reloaded
+    pub fn generation(&self) -> u64 {
+        *self.generation.borrow()
+    }
+
+    /// Get notified when the states are reloaded
+    fn subscribe(&self) -> watch::Receiver<u64> {
+        self.generation.subscribe()
+    }
+}
+
+/// The key of the manifest in the registry of a Lua state
+const MANIFEST_KEY: &str = "reluax.manifest";
+
+/// Get the table returned by `require('reluax')`, which is only required the
+/// first time for each state, and kept in its registry after that
+///
+/// The table itself is kept, rather than what's in it, so changes made to it
+/// while handling a request are still seen by the next one.
+fn manifest(ctx: rlua::Context<'_>) -> rlua::Result<rlua::Table<'_>> {
+    if let Some(manifest) = ctx.named_registry_value::<_, Option<rlua::Table>>(MANIFEST_KEY)? {
+        return Ok(manifest);
+    }
+
+    let manifest: rlua::Table = ctx.load("require('reluax')").eval()?;
+    ctx.set_named_registry_value(MANIFEST_KEY, manifest.clone())?;
+    luax::utils::snapshot_env_allowlist(ctx, &manifest)?;
+
+    Ok(manifest)
+}
+
+/// Require the manifest of a freshly prepared state up front, rather than
+/// while handling its first request
+fn resolve_manifest(lua: &Lua) {
+    // errors are left for the requests to report, like in dev mode, where the
+    // page shows them
+    let _ = lua.context(|ctx| manifest(ctx).map(|_| ()));
 }
 
 impl Server {
-    pub async fn serve(lua: Lua, port: u16, public_dir: Option<PathBuf>) -> Result<()> {
+    pub async fn serve(lua: Arc<LuaPool>, addr: SocketAddr, options: Options) -> Result<()> {
+        Self::serve_until(lua, addr, options, std::future::pending()).await
+    }
+
+    /// Serve until `shutdown` resolves, then stop accepting connections and
+    /// return once the ones already open finished the requests in progress
+    pub async fn serve_until(
+        lua: Arc<LuaPool>,
+        addr: SocketAddr,
+        options: Options,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
         let state = State {
-            lua: Arc::new(Mutex::new(lua)),
-            public_dir,
+            lua,
+            dev_mode: options.dev_mode,
+            public_dir: options.public_dir,
+            max_body_size: options.max_body_size,
+            log: options.log,
+            remote_addr: None,
+            trust_proxy: options.trust_proxy,
+            route_timeout: options.route_timeout,
+            memory_limit: options.memory_limit,
+            instruction_limit: options.instruction_limit,
+            cache: Arc::default(),
+            rate_limiter: options
+                .rate_limit
+                .map(|limit| Arc::new(RateLimiter::new(limit, DEFAULT_MAX_CLIENTS))),
+        };
+        let connections = ConnectionOptions {
+            max_connections: options.max_connections,
+            keep_alive: options.keep_alive,
+            idle_timeout: IDLE_TIMEOUT,
         };
-        let server = Self { port, state };
-        server.start().await
+        let server = Self {
+            addr,
+            state,
+            connections,
+        };
+        server.start(shutdown).await
     }
 
-    async fn start(self) -> Result<()> {
-        let addr = SocketAddr::from(([127, 0, 0, 1], self.port));
-        let listener = TcpListener::bind(addr).await?;
+    async fn start(self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        let listener = TcpListener::bind(self.addr).await?;
+
+        Self::accept(listener, self.state, self.connections, shutdown).await
+    }
 
-        let state = self.state;
+    /// Serve the connections to a listener, over HTTP/1.1, or HTTP/2 for clients
+    /// which open with its connection preface
+    async fn accept(
+        listener: TcpListener,
+        state: State,
+        connections: ConnectionOptions,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        // every slot is taken back on shutdown, which counts in `u32`
+        let slots = connections
+            .max_connections
+            .min(Semaphore::MAX_PERMITS)
+            .min(u32::MAX as usize);
+        let open = Arc::new(Semaphore::new(slots));
+        let (stop, _) = watch::channel(());
+        tokio::pin!(shutdown);
 
         loop {
-            let (stream, _) = listener.accept().await?;
+            // over the limit, new connections wait in the listener's backlog
+            // until one of the open ones closes
+            let next = async {
+                let permit = open.clone().acquire_owned().await?;
+                let accepted = listener.accept().await?;
+                Ok::<_, color_eyre::Report>((permit, accepted))
+            };
+            let (permit, (stream, remote_addr)) = tokio::select! {
+                next = next => next?,
+                _ = &mut shutdown => break,
+            };
             let io = TokioIo::new(stream);
-            let http = http1::Builder::new();
-            let state = state.clone();
+            let mut http = auto::Builder::new(TokioExecutor::new());
+            http.http1()
+                .keep_alive(connections.keep_alive)
+                .timer(TokioTimer::new())
+                .header_read_timeout(HEADER_READ_TIMEOUT);
+            let state = State {
+                remote_addr: Some(remote_addr),
+                ..state.clone()
+            };
+            let mut stopping = stop.subscribe();
 
             tokio::task::spawn(async move {
-                if let Err(err) = http.serve_connection(io, state).await {
-                    println!("Failed to serve connection: {:?}", err);
+                // marks every request, so that the connection can be closed
+                // once it went without any for too long
+                let (activity, mut active) = watch::channel(());
+                let service = hyper::service::service_fn(move |req| {
+                    activity.send_replace(());
+                    state.call(req)
+                });
+
+                let conn = http.serve_connection(io, service);
+                tokio::pin!(conn);
+                let mut closing = false;
+                loop {
+                    tokio::select! {
+                        res = conn.as_mut() => {
+                            if let Err(err) = res {
+                                warn!("Failed to serve connection: {:?}", err);
+                            }
+                            break;
+                        }
+                        idle = tokio::time::timeout(connections.idle_timeout, active.changed()),
+                            if !closing =>
+                        {
+                            // a request in progress is still answered
+                            if idle.is_err() {
+                                conn.as_mut().graceful_shutdown();
+                                closing = true;
+                            }
+                        }
+                        _ = stopping.changed(), if !closing => {
+                            conn.as_mut().graceful_shutdown();
+                            closing = true;
+                        }
+                    }
                 }
+                drop(permit);
             });
         }
+
+        // new connections are refused while the open ones finish, which is
+        // over once all of their slots are free again
+        drop(listener);
+        stop.send_replace(());
+        let drained = tokio::time::timeout(SHUTDOWN_TIMEOUT, open.acquire_many(slots as u32));
+        if drained.await.is_err() {
+            warn!(
+                "Connections still open after {:?}, closing anyway",
+                SHUTDOWN_TIMEOUT
+            );
+        }
+
+        Ok(())
     }
 }
 
-fn mk_response(status: StatusCode, s: String) -> Result<Response<Full<Bytes>>> {
-    Ok(Response::builder()
-        .status(status)
-        .body(Full::new(Bytes::from(s)))?)
+/// Route a `GET` request for a path without a listener, e.g. to export pages
+pub fn render(lua: Arc<LuaPool>, path: &str) -> Result<Response<ResponseBody>> {
+    let state = State {
+        lua,
+        dev_mode: false,
+        public_dir: None,
+        max_body_size: usize::MAX,
+        log: false,
+        remote_addr: None,
+        trust_proxy: false,
+        route_timeout: None,
+        memory_limit: None,
+        instruction_limit: None,
+        cache: Arc::default(),
+        rate_limiter: None,
+    };
+
+    state.serve(path.to_string(), Method::GET, Collected::default(), vec![])
 }
 
-fn mk_file_response(path: PathBuf) -> Result<Response<Full<Bytes>>> {
-    let ext = path.extension().unwrap().to_str().unwrap();
+/// The body of a response, either fully built up front or streamed from a
+/// Lua producer
+pub type ResponseBody = Either<Full<Bytes>, LuaStream>;
 
-    let mime = match ext {
-        "css" => "text/css",
-        "js" => "text/javascript",
-        "html" => "text/html",
-        "png" => "image/png",
-        "jpg" => "image/jpeg",
-        "jpeg" => "image/jpeg",
+pub(crate) fn full(bytes: impl Into<Bytes>) -> ResponseBody {
+    Either::Left(Full::new(bytes.into()))
+}
+
+/// How many chunks of a streamed response can be produced ahead of the client
+const STREAM_BUFFER: usize = 16;
+
+/// A response body streamed from the chunks returned by a Lua producer
+pub struct LuaStream {
+    chunks: mpsc::Receiver<Result<Bytes>>,
+}
+
+impl Body for LuaStream {
+    type Data = Bytes;
+    type Error = color_eyre::Report;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Bytes>, Self::Error>>> {
+        self.chunks
+            .poll_recv(cx)
+            .map(|chunk| chunk.map(|chunk| chunk.map(Frame::data)))
+    }
+}
+
+/// Call the producer of a streamed response until it runs out of chunks or
+/// the client goes away, locking its Lua state only for each call
+fn pump_stream(
+    lua: &LuaPool,
+    index: usize,
+    producer: rlua::RegistryKey,
+    tx: mpsc::Sender<Result<Bytes>>,
+) {
+    loop {
+        let chunk = lua
+            .get_state(index)
+            .context(|ctx| -> Result<Option<Bytes>> {
+                let producer: rlua::Function = ctx.registry_value(&producer)?;
+
+                match producer.call::<_, rlua::Value>(())? {
+                    rlua::Value::Nil => Ok(None),
+                    rlua::Value::String(s) => Ok(Some(Bytes::copy_from_slice(s.as_bytes()))),
+                    rlua::Value::Table(t) => {
+                        let mut buf = Vec::new();
+                        table_to_html(t, &mut buf)?;
+                        Ok(Some(Bytes::from(buf)))
+                    }
+                    _ => Err(ReluaxError::Server(
+                        "Stream producer returned invalid type".to_string(),
+                    )
+                    .into()),
+                }
+            });
+
+        match chunk {
+            Ok(Some(chunk)) => {
+                // the receiver is gone once the client disconnects
+                if tx.blocking_send(Ok(chunk)).is_err() {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                error!("Internal lua error: {}", e);
+                let _ = tx.blocking_send(Err(e));
+                break;
+            }
+        }
+    }
+
+    // fails if the state was reloaded in the meantime, which drops the key anyway
+    let _ = lua
+        .get_state(index)
+        .context(|ctx| ctx.remove_registry_value(producer));
+}
+
+/// Build a plain text response, as used for bare strings returned by routes and
+/// for the server's own errors
+fn mk_response(status: StatusCode, body: impl Into<Bytes>) -> Result<Response<ResponseBody>> {
+    Ok(with_content_length(
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(full(body))?,
+    ))
+}
+
+/// Answer with a `500` when handling a request failed outside of Lua, instead
+/// of dropping the connection
+fn mk_internal_error_response(e: color_eyre::Report) -> Result<Response<ResponseBody>> {
+    error!("Internal server error: {}", e);
+
+    mk_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Internal server error".to_string(),
+    )
+}
+
+/// Set the `Content-Length` of a whole response explicitly, instead of leaving it
+/// to hyper, replacing any set by the route
+fn with_content_length(mut res: Response<ResponseBody>) -> Response<ResponseBody> {
+    // streamed bodies have no known length
+    if let Some(len) = res.body().size_hint().exact() {
+        res.headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(len));
+    }
+
+    res
+}
+
+/// Describe a Lua error along with its stack traceback, looking through the
+/// errors raised in Rust callbacks to the one which caused them
+fn describe_lua_error(e: &rlua::Error) -> String {
+    match e {
+        rlua::Error::CallbackError { traceback, cause } => {
+            format!("{}\n{}", describe_lua_error(cause), traceback)
+        }
+        e => e.to_string(),
+    }
+}
+
+/// Answer a request whose route failed with a `500`. In dev mode the page
+/// shows the error and its traceback, pointing into the LuaX sources, while in
+/// production only `message` is sent, so that nothing about the code leaks.
+fn mk_lua_error_response(
+    e: &rlua::Error,
+    message: &str,
+    dev_mode: bool,
+) -> Result<Response<ResponseBody>> {
+    if !dev_mode {
+        return mk_response(StatusCode::INTERNAL_SERVER_ERROR, message.to_string());
+    }
+
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html><head><title>{0}</title></head><body><h1>{0}</h1><pre>{1}</pre></body></html>",
+        luax::escape_html(message),
+        luax::escape_html(&luax::map_lua_error_lines(&describe_lua_error(e)))
+    )
+    .into_bytes();
+    // reload once the error is fixed
+    inject_live_reload(&mut page);
+
+    Ok(with_content_length(
+        Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(full(page))?,
+    ))
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// Build an entity tag for a static file from its modification time and size
+fn file_etag(modified: SystemTime, len: u64) -> String {
+    let secs = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    format!("\"{:x}-{:x}\"", secs, len)
+}
+
+/// Check the conditional request headers against the file's validators.
+/// `If-None-Match` takes precedence over `If-Modified-Since`, as per RFC 9110.
+fn is_not_modified(headers: &[(String, String)], etag: &str, modified: SystemTime) -> bool {
+    if let Some(if_none_match) = find_header(headers, "if-none-match") {
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+
+    if let Some(if_modified_since) = find_header(headers, "if-modified-since") {
+        if let Ok(since) = httpdate::parse_http_date(if_modified_since) {
+            // HTTP dates only have second precision
+            let modified_secs = modified
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let since_secs = since
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+
+            return modified_secs <= since_secs;
+        }
+    }
+
+    false
+}
+
+/// Guess the MIME type of a static file from its (lowercase) extension
+fn default_mime_type(ext: &str) -> &'static str {
+    match ext {
+        // text, which is always sent as UTF-8
+        "css" => "text/css; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "htm" | "html" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        // data
+        "json" => "application/json",
+        "map" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        // images
+        "avif" => "image/avif",
+        "bmp" => "image/bmp",
         "gif" => "image/gif",
-        "svg" => "image/svg+xml",
-        _ => "text/plain",
+        "ico" => "image/x-icon",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "svg" => "image/svg+xml; charset=utf-8",
+        "webp" => "image/webp",
+        // fonts
+        "otf" => "font/otf",
+        "ttf" => "font/ttf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        // audio and video
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Precompressed siblings of static files that can be served in their place,
+/// in order of preference, as the `Content-Encoding` and the file extension
+const PRECOMPRESSED: &[(&str, &str)] = &[("br", "br"), ("gzip", "gz")];
+
+/// Check if the client accepts an encoding, going by `Accept-Encoding`
+fn accepts_encoding(headers: &[(String, String)], encoding: &str) -> bool {
+    let accept_encoding = match find_header(headers, "Accept-Encoding") {
+        Some(accept_encoding) => accept_encoding,
+        None => return false,
+    };
+
+    accept_encoding.split(',').any(|coding| {
+        let mut params = coding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+
+        let rejected = params
+            .any(|param| param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+
+        (name.eq_ignore_ascii_case(encoding) || name == "*") && !rejected
+    })
+}
+
+/// Find a precompressed sibling of a file (e.g. `app.js.br` for `app.js`) the
+/// client accepts, returning its `Content-Encoding` and path
+fn find_precompressed(
+    path: &Path,
+    headers: &[(String, String)],
+) -> Option<(&'static str, PathBuf)> {
+    PRECOMPRESSED.iter().find_map(|(encoding, ext)| {
+        let mut sibling = path.as_os_str().to_owned();
+        sibling.push(".");
+        sibling.push(ext);
+        let sibling = PathBuf::from(sibling);
+
+        (accepts_encoding(headers, encoding) && sibling.is_file()).then_some((*encoding, sibling))
+    })
+}
+
+/// Find the file in the public directory to serve for a path, which is the
+/// `index.html` of a directory, or `page.html` for `/page` if there is no file
+/// with the exact name
+///
+/// Paths which could leave the public directory, like `/../secret`, are never
+/// resolved, and neither are files which end up outside of it once symlinks
+/// are followed.
+fn static_file_path(public_dir: &Path, path: &str) -> Option<PathBuf> {
+    let file = find_static_file(public_dir, path)?;
+
+    let canonical = file.canonicalize().ok()?;
+    if !canonical.starts_with(public_dir.canonicalize().ok()?) {
+        return None;
+    }
+
+    Some(file)
+}
+
+fn find_static_file(public_dir: &Path, path: &str) -> Option<PathBuf> {
+    let relative = Path::new(path.trim_start_matches('/'));
+
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return None;
+    }
+
+    let file = public_dir.join(relative);
+
+    if file.is_dir() {
+        let index = file.join("index.html");
+        return index.is_file().then_some(index);
+    }
+
+    if file.is_file() {
+        return Some(file);
+    }
+
+    if relative.extension().is_none() && !path.ends_with('/') {
+        let mut html = file.into_os_string();
+        html.push(".html");
+        let html = PathBuf::from(html);
+        return html.is_file().then_some(html);
+    }
+
+    None
+}
+
+fn mk_file_response(
+    path: PathBuf,
+    headers: &[(String, String)],
+    mime_types: &HashMap<String, String>,
+) -> Result<Response<ResponseBody>> {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    // extensions are matched case-insensitively, also in the project's table
+    let mime = match mime_types
+        .iter()
+        .find(|(known, _)| known.to_lowercase() == ext)
+    {
+        Some((_, mime)) => mime.as_str(),
+        None => default_mime_type(&ext),
+    };
+
+    let (encoding, path) = match find_precompressed(&path, headers) {
+        Some((encoding, sibling)) => (Some(encoding), sibling),
+        None => (None, path),
     };
 
+    let metadata = std::fs::metadata(&path)?;
+    let modified = metadata.modified()?;
+    let etag = file_etag(modified, metadata.len());
+    let last_modified = httpdate::fmt_http_date(modified);
+
+    let mut res = Response::builder()
+        .header("ETag", etag.as_str())
+        .header("Last-Modified", last_modified)
+        .header("Cache-Control", "no-cache")
+        .header("Vary", "Accept-Encoding");
+
+    if is_not_modified(headers, &etag, modified) {
+        return Ok(res
+            .status(StatusCode::NOT_MODIFIED)
+            .body(full(Bytes::new()))?);
+    }
+
+    if let Some(encoding) = encoding {
+        res = res.header("Content-Encoding", encoding);
+    }
+
     let bytes = std::fs::read(path)?;
 
-    Ok(Response::builder()
-        .header("Content-Type", mime)
-        .body(Full::new(Bytes::from(bytes)))?)
+    Ok(with_content_length(
+        res.header("Content-Type", mime)
+            .body(full(Bytes::from(bytes)))?,
+    ))
+}
+
+/// Build an `Allow` header from the methods a route allows, adding `HEAD`
+/// wherever `GET` is allowed, since it is answered the same way
+fn allow_header(allowed: &[String]) -> String {
+    let mut methods: Vec<String> = Vec::new();
+
+    for method in allowed {
+        let method = method.to_uppercase();
+        if methods.contains(&method) {
+            continue;
+        }
+        methods.push(method.clone());
+
+        if method == "GET" && !allowed.iter().any(|m| m.eq_ignore_ascii_case("HEAD")) {
+            methods.push("HEAD".to_string());
+        }
+    }
+
+    methods.join(", ")
+}
+
+/// Answer an `OPTIONS` request with the methods the manifest allows, and with
+/// the CORS headers too if it is a preflight and the manifest has an origin
+fn mk_options_response(
+    allowed: &[String],
+    cors_origin: Option<&str>,
+    headers: &[(String, String)],
+) -> Result<Response<ResponseBody>> {
+    let mut allowed = allowed.to_vec();
+    if !allowed.iter().any(|m| m.eq_ignore_ascii_case("OPTIONS")) {
+        allowed.push("OPTIONS".to_string());
+    }
+    let methods = allow_header(&allowed);
+
+    let mut res = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(ALLOW, &methods);
+
+    let preflight = find_header(headers, ACCESS_CONTROL_REQUEST_METHOD.as_str()).is_some();
+    if let (true, Some(origin)) = (preflight, cors_origin) {
+        res = res
+            .header(ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+            .header(ACCESS_CONTROL_ALLOW_METHODS, &methods);
+
+        if let Some(requested) = find_header(headers, ACCESS_CONTROL_REQUEST_HEADERS.as_str()) {
+            res = res.header(ACCESS_CONTROL_ALLOW_HEADERS, requested);
+        }
+    }
+
+    Ok(res.body(full(Bytes::new()))?)
+}
+
+/// Drop the body of a response to a `HEAD` request, keeping the `Content-Length`
+/// it would have had
+fn strip_body(res: Response<ResponseBody>) -> Result<Response<ResponseBody>> {
+    let (mut parts, body) = res.into_parts();
+
+    if parts.status != StatusCode::NOT_MODIFIED {
+        // streamed bodies have no known length
+        if let Some(len) = body.size_hint().exact() {
+            parts
+                .headers
+                .entry(CONTENT_LENGTH)
+                .or_insert(HeaderValue::from(len));
+        }
+    }
+
+    Ok(Response::from_parts(parts, full(Bytes::new())))
+}
+
+/// Find the first of the manifest's `routes` whose pattern matches the path,
+/// returning its handler with the parameters captured from the path
+fn match_route<'lua>(
+    ctx: rlua::Context<'lua>,
+    manifest: &rlua::Table<'lua>,
+    path: &str,
+) -> Result<Option<(rlua::Function<'lua>, rlua::Table<'lua>)>> {
+    let routes: rlua::Table = match manifest.get("routes")? {
+        Some(routes) => routes,
+        None => return Ok(None),
+    };
+
+    for route in routes.sequence_values::<rlua::Table>() {
+        let route = route?;
+        let pattern: String = route.get(1)?;
+
+        if url_matches(ctx, (pattern.clone(), path.to_string(), None))? {
+            let params = url_extract(ctx, (pattern, path.to_string()))?;
+            return Ok(Some((route.get(2)?, params)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// How many VM instructions run between checks of a route's deadline
+const LIMIT_CHECK_INTERVAL: u32 = 1000;
+
+/// Interrupt the Lua code running in a state once the deadline passes,
+/// flagging that it timed out, once it ran more than `max_instructions`, or
+/// once the state uses more than `max_memory` bytes
+///
+/// The limits are checked every `LIMIT_CHECK_INTERVAL` instructions. LuaJIT
+/// doesn't run hooks in code compiled before the hook was set, so a hot loop
+/// may not be interrupted. The memory is read with `collectgarbage("count")`,
+/// since the allocator doesn't keep track of it under LuaJIT, so the state
+/// can go past the limit by what those instructions allocate.
+fn set_limits(
+    lua: &Lua,
+    deadline: Option<Instant>,
+    max_instructions: Option<u64>,
+    max_memory: Option<usize>,
+    timed_out: Arc<AtomicBool>,
+) {
+    let triggers = rlua::HookTriggers {
+        every_nth_instruction: Some(LIMIT_CHECK_INTERVAL),
+        ..Default::default()
+    };
+
+    let mut instructions: u64 = 0;
+    lua.set_hook(triggers, move |ctx, _| {
+        instructions += LIMIT_CHECK_INTERVAL as u64;
+        if let Some(max) = max_instructions {
+            if instructions > max {
+                return Err(rlua::Error::RuntimeError(format!(
+                    "route ran more than {} instructions",
+                    max
+                )));
+            }
+        }
+
+        if let Some(max) = max_memory {
+            // not the global, which the route could replace
+            let collectgarbage: rlua::Function =
+                ctx.named_registry_value(luax::COLLECTGARBAGE_KEY)?;
+            let kilobytes: f64 = collectgarbage.call("count")?;
+            if kilobytes * 1024.0 > max as f64 {
+                return Err(rlua::Error::MemoryError(format!(
+                    "route used more than {} bytes of memory",
+                    max
+                )));
+            }
+        }
+
+        match deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                timed_out.store(true, Ordering::Relaxed);
+                Err(rlua::Error::RuntimeError("route timed out".to_string()))
+            }
+            _ => Ok(()),
+        }
+    });
+}
+
+/// The path of the endpoint notifying pages served in dev mode about reloads
+const LIVE_RELOAD_PATH: &str = "/__reluax/reload";
+
+/// Add a script reloading the page on template changes before the closing
+/// `</body>` tag of a page, or at its end if there is none
+fn inject_live_reload(page: &mut Vec<u8>) {
+    let script = format!(
+        "<script>new EventSource(\"{}\").onmessage = () => location.reload();</script>",
+        LIVE_RELOAD_PATH
+    );
+
+    let closing_body = page.windows(7).rposition(|w| w == b"</body>");
+    let at = closing_body.unwrap_or(page.len());
+
+    page.splice(at..at, script.into_bytes());
 }
 
-fn decode_luax_response(status: StatusCode, t: rlua::Table) -> Result<Response<Full<Bytes>>> {
+fn decode_luax_response(
+    status: StatusCode,
+    t: rlua::Table,
+    dev_mode: bool,
+) -> Result<Response<ResponseBody>> {
     let lua_headers: Option<rlua::Table> = t.get("headers")?;
 
-    let (response_body, mime_type)  = if t.contains_key("type")? {
+    if t.get::<_, Option<String>>("type")?.as_deref() == Some("redirect") {
+        let location: String = t.get("location")?;
+
+        // a route's own status only replaces the redirect's if it is one too,
+        // e.g. `return 200, reluax.redirect(...)` still redirects
+        let status = if status.is_redirection() {
+            status
+        } else {
+            let own: u16 = t.get("status")?;
+            StatusCode::from_u16(own).unwrap_or(StatusCode::FOUND)
+        };
+
+        let response_builder = Response::builder()
+            .status(status)
+            .header("Location", location);
+
+        return Ok(with_content_length(
+            with_lua_headers(response_builder, lua_headers)?.body(full(Bytes::new()))?,
+        ));
+    }
+
+    let (response_body, mime_type) = if t.contains_key("type")? {
         let ty: String = t.get("type")?;
         let mime_type: Option<String> = t.get("mime_type")?;
 
@@ -99,18 +960,54 @@ fn decode_luax_response(status: StatusCode, t: rlua::Table) -> Result<Response<F
             "html" => {
                 let mut buf = Vec::new();
                 table_to_html(t, &mut buf)?;
-                (buf, mime_type.unwrap_or("text/html".to_string()))
+                (
+                    buf,
+                    mime_type.unwrap_or("text/html; charset=utf-8".to_string()),
+                )
+            }
+            "xhtml" => {
+                let mut buf = Vec::new();
+                table_to_markup(t, &mut buf, Syntax::Xml)?;
+                (
+                    buf,
+                    mime_type.unwrap_or("application/xhtml+xml".to_string()),
+                )
+            }
+            "xml" => {
+                let mut buf = Vec::new();
+                writeln!(&mut buf, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+                table_to_xml(t, &mut buf)?;
+                (buf, mime_type.unwrap_or("application/xml".to_string()))
+            }
+            "text" => {
+                let text: rlua::String = t.get("text")?;
+                (
+                    text.as_bytes().to_vec(),
+                    mime_type.unwrap_or("text/plain; charset=utf-8".to_string()),
+                )
             }
             "json" => {
                 let mut buf = Vec::new();
-                table_to_json(t, &mut buf)?;
+                table_to_json(t.get("value")?, &mut buf)?;
                 (buf, mime_type.unwrap_or("application/json".to_string()))
             }
             "html-page" => {
+                let doctype: Option<String> = t.get("doctype")?;
+
                 let mut buf = Vec::new();
-                writeln!(&mut buf, "<!DOCTYPE html>")?;
+                writeln!(
+                    &mut buf,
+                    "<!DOCTYPE {}>",
+                    doctype.as_deref().unwrap_or("html")
+                )?;
                 table_to_html(t, &mut buf)?;
-                (buf, mime_type.unwrap_or("text/html".to_string()))
+                if dev_mode {
+                    inject_live_reload(&mut buf);
+                }
+                (
+                    buf,
+                    mime_type.unwrap_or("text/html; charset=utf-8".to_string()),
+                )
             }
             _ => return Err(ReluaxError::Server("Unknown response type".to_string()).into()),
         }
@@ -118,13 +1015,27 @@ fn decode_luax_response(status: StatusCode, t: rlua::Table) -> Result<Response<F
         let mut buf = Vec::new();
         writeln!(&mut buf, "<!DOCTYPE html>")?;
         table_to_html(t, &mut buf)?;
-        (buf, "text/html".to_string())
+        if dev_mode {
+            inject_live_reload(&mut buf);
+        }
+        (buf, "text/html; charset=utf-8".to_string())
     };
 
-    let mut response_builder = Response::builder()
+    let response_builder = Response::builder()
         .status(status)
         .header("Content-Type", mime_type);
 
+    let response =
+        with_lua_headers(response_builder, lua_headers)?.body(full(Bytes::from(response_body)))?;
+
+    Ok(with_content_length(response))
+}
+
+/// Add the headers set with `reluax.headers` to a response
+fn with_lua_headers(
+    mut response_builder: hyper::http::response::Builder,
+    lua_headers: Option<rlua::Table>,
+) -> Result<hyper::http::response::Builder> {
     if let Some(lua_headers) = lua_headers {
         for r in lua_headers.pairs::<String, String>() {
             let (k, v) = r?;
@@ -132,109 +1043,1985 @@ fn decode_luax_response(status: StatusCode, t: rlua::Table) -> Result<Response<F
         }
     }
 
-    let response = response_builder.body(Full::new(Bytes::from(response_body)))?;
-
-    Ok(response)
+    Ok(response_builder)
 }
 
 impl Service<Request<Incoming>> for State {
-    type Response = Response<Full<Bytes>>;
+    type Response = Response<ResponseBody>;
     type Error = color_eyre::Report;
     type Future =
         Pin<Box<dyn Future<Output = std::result::Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&self, req: Request<Incoming>) -> Self::Future {
+        let state = self.clone();
+
+        Box::pin(async move {
+            if !state.log {
+                return state.handle(req).await.or_else(mk_internal_error_response);
+            }
+
+            let method = req.method().clone();
+            let path = req.uri().path().to_string();
+            let start = Instant::now();
+
+            let res = state.handle(req).await.or_else(mk_internal_error_response);
+
+            log_request(&method, &path, &res, start.elapsed());
+
+            res
+        })
+    }
+}
+
+/// Print a line about a handled request to the access log
+fn log_request(
+    method: &Method,
+    path: &str,
+    res: &Result<Response<ResponseBody>>,
+    elapsed: Duration,
+) {
+    let status = match res {
+        Ok(res) => res.status(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    let status = if status.is_server_error() {
+        status.as_u16().bright_red().to_string()
+    } else if status.is_client_error() {
+        status.as_u16().bright_yellow().to_string()
+    } else {
+        status.as_u16().bright_green().to_string()
+    };
+
+    info!(
+        "📨 {} {} {} {:.2?}",
+        method.bright_blue(),
+        path,
+        status,
+        elapsed
+    );
+}
+
+impl State {
+    async fn handle<B>(self, req: Request<B>) -> Result<Response<ResponseBody>>
+    where
+        B: Body<Data = Bytes>,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
         let path = req.uri().path().to_string();
         let method = req.method().clone();
-        let lua = self.lua.clone();
-        let public_dir = self.public_dir.clone();
-        let headers = req
+        let headers: Vec<(String, String)> = req
             .headers()
             .iter()
             .map(|(k, v)| (k.to_string(), v.to_str().unwrap().to_string()))
             .collect();
-        Box::pin(async {
-            let body = req.into_body().collect().await?;
 
-            Self::serve(lua, public_dir, path, method, body, headers)
-        })
-    }
-}
+        if let Some(rate_limiter) = &self.rate_limiter {
+            if let Some(ip) = self.client_ip(&headers) {
+                if let Err(wait) = rate_limiter.check(ip) {
+                    let mut res = mk_response(StatusCode::TOO_MANY_REQUESTS, "Too many requests")?;
+                    let seconds = wait.as_secs_f64().ceil() as u64;
+                    res.headers_mut()
+                        .insert(RETRY_AFTER, HeaderValue::from(seconds));
+                    return Ok(res);
+                }
+            }
+        }
 
-impl State {
-    fn serve(
-        lua: Arc<Mutex<Lua>>,
-        public_dir: Option<PathBuf>,
-        path: String,
-        method: Method,
-        body: Collected<Bytes>,
-        headers: Vec<(String, String)>,
-    ) -> Result<Response<Full<Bytes>>> {
-        let lua = lua.lock().unwrap();
+        // hyper answers `Expect: 100-continue` by itself once the body is read,
+        // but other expectations can't be met
+        if let Some(expect) = find_header(&headers, "expect") {
+            if !expect.eq_ignore_ascii_case("100-continue") {
+                return mk_response(
+                    StatusCode::EXPECTATION_FAILED,
+                    "Expectation failed".to_string(),
+                );
+            }
+        }
 
-        let res = lua.context(|ctx| -> Result<Response<Full<Bytes>>> {
-            let manifest: rlua::Result<rlua::Table> = ctx.load("require('reluax')").eval();
+        // reject bodies which are declared too large without reading them, so
+        // that clients waiting for a 100 Continue don't send them at all
+        let declared_len = find_header(&headers, "content-length").and_then(|l| l.parse().ok());
+        if declared_len.map_or(false, |len: usize| len > self.max_body_size) {
+            return mk_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Payload too large".to_string(),
+            );
+        }
 
-            let manifest = match manifest {
-                Ok(m) => m,
-                Err(e) => {
-                    eprintln!("Internal lua error: {}", e);
+        let body = match Limited::new(req.into_body(), self.max_body_size)
+            .collect()
+            .await
+        {
+            Ok(body) => body,
+            Err(e) if e.is::<LengthLimitError>() => {
+                return mk_response(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "Payload too large".to_string(),
+                );
+            }
+            Err(e) => return Err(eyre!(e)),
+        };
 
-                    return mk_response(
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        "Internal lua error".to_string(),
-                    );
+        if self.dev_mode && path == LIVE_RELOAD_PATH {
+            return self.live_reload().await;
+        }
+
+        // `HEAD` requests are answered like a `GET`, so they share its entry
+        let generation = self.lua.generation();
+        if method == Method::GET || method == Method::HEAD {
+            if let Some(cached) = self.cache.get(&path, generation) {
+                return match method {
+                    Method::HEAD => strip_body(cached),
+                    _ => Ok(cached),
+                };
+            }
+        }
+
+        // running Lua blocks, so keep it off the async workers
+        let state = self.clone();
+        let cache_path = path.clone();
+        let is_get = method == Method::GET;
+        let res =
+            tokio::task::spawn_blocking(move || state.serve(path, method, body, headers)).await??;
+
+        match res.extensions().get::<CachePolicy>().copied() {
+            Some(policy) if is_get => self.cache.store(&cache_path, generation, policy, res).await,
+            _ => Ok(res),
+        }
+    }
+
+    /// Wait for the next reload, then send a single server-sent event about it
+    async fn live_reload(&self) -> Result<Response<ResponseBody>> {
+        let mut generation = self.lua.subscribe();
+        generation.changed().await?;
+
+        Ok(Response::builder()
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-cache")
+            .body(full(Bytes::from("data: reload\n\n")))?)
+    }
+
+    /// Respond with the file in the public directory matching the path, if any
+    fn static_response(
+        &self,
+        manifest: &rlua::Table,
+        path: &str,
+        headers: &[(String, String)],
+    ) -> Result<Option<Response<ResponseBody>>> {
+        let file = match self
+            .public_dir
+            .as_deref()
+            .and_then(|public_dir| static_file_path(public_dir, path))
+        {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        // the manifest can override or extend the MIME types by extension
+        let mime_types: Option<HashMap<String, String>> = manifest.get("mime_types")?;
+
+        mk_file_response(file, headers, &mime_types.unwrap_or_default()).map(Some)
+    }
+
+    /// Render the manifest's page for an error status, if `error_pages` has
+    /// one, either as the page itself or as a function of the path and status
+    /// returning it
+    ///
+    /// An error page which fails is logged and left out, so that the plain
+    /// response is sent instead.
+    fn error_page(
+        &self,
+        manifest: &rlua::Table,
+        status: StatusCode,
+        path: &str,
+    ) -> Result<Option<Response<ResponseBody>>> {
+        let pages: rlua::Table = match manifest.get("error_pages")? {
+            Some(pages) => pages,
+            None => return Ok(None),
+        };
+
+        let page = match pages.get::<_, rlua::Value>(status.as_u16())? {
+            rlua::Value::Function(f) => match f.call::<_, rlua::Value>((path, status.as_u16())) {
+                Ok(page) => page,
+                Err(e) => {
+                    error!("Error page for {} failed: {}", status.as_u16(), e);
+                    return Ok(None);
+                }
+            },
+            page => page,
+        };
+
+        match page {
+            rlua::Value::String(s) => mk_response(status, s.as_bytes().to_vec()).map(Some),
+            rlua::Value::Table(t) => match decode_luax_response(status, t, self.dev_mode) {
+                Ok(response) => Ok(Some(response)),
+                Err(e) => {
+                    error!("Error page for {} failed: {}", status.as_u16(), e);
+                    Ok(None)
+                }
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// The manifest's page for a `500`, which is left out in dev mode, where
+    /// errors are shown as they are
+    fn internal_error_page(
+        &self,
+        manifest: &rlua::Table,
+        path: &str,
+    ) -> Result<Option<Response<ResponseBody>>> {
+        if self.dev_mode {
+            return Ok(None);
+        }
+
+        self.error_page(manifest, StatusCode::INTERNAL_SERVER_ERROR, path)
+    }
+
+    fn serve(
+        &self,
+        path: String,
+        method: Method,
+        body: Collected<Bytes>,
+        headers: Vec<(String, String)>,
+    ) -> Result<Response<ResponseBody>> {
+        if method == Method::HEAD {
+            // route it like a GET to get the same headers and status
+            let res = self.serve(path, Method::GET, body, headers)?;
+            return strip_body(res);
+        }
+
+        let (lua_index, lua) = self.lua.get_indexed();
+
+        let limited = self.route_timeout.is_some()
+            || self.instruction_limit.is_some()
+            || self.memory_limit.is_some();
+        let timed_out = Arc::new(AtomicBool::new(false));
+        if limited {
+            let deadline = self.route_timeout.map(|timeout| Instant::now() + timeout);
+            set_limits(
+                &lua,
+                deadline,
+                self.instruction_limit,
+                self.memory_limit,
+                timed_out.clone(),
+            );
+        }
+        let mut out_of_memory = false;
+
+        let res = lua.context(|ctx| -> Result<Response<ResponseBody>> {
+            let manifest = match manifest(ctx) {
+                Ok(m) => m,
+                Err(e) => {
+                    error!("Internal lua error: {}", e);
+
+                    return mk_lua_error_response(&e, "Internal lua error", self.dev_mode);
                 }
             };
 
-            let route: rlua::Function = manifest.get("route")?;
+            // with `static_first`, files in the public directory shadow the routes
+            if manifest
+                .get::<_, Option<bool>>("static_first")?
+                .unwrap_or(false)
+            {
+                if let Some(response) = self.static_response(&manifest, &path, &headers)? {
+                    return Ok(response);
+                }
+            }
+
+            // with `allowed_methods`, `OPTIONS` requests don't reach the routes
+            let cors_origin: Option<String> = manifest.get("cors_origin")?;
+            if method == Method::OPTIONS {
+                if let Some(allowed) = manifest.get::<_, Option<Vec<String>>>("allowed_methods")? {
+                    return mk_options_response(&allowed, cors_origin.as_deref(), &headers);
+                }
+            }
 
             let method = method.as_str();
-            let body: rlua::String = ctx.create_string(&body.to_bytes().to_vec())?;
+            // raw bytes, which don't have to be valid UTF-8
+            let body: rlua::String = ctx.create_string(&body.to_bytes())?;
             let lua_headers: rlua::Table = ctx.create_table()?;
-            for (k, v) in headers {
-                lua_headers.set(k, v)?;
+            for (k, v) in headers.iter() {
+                lua_headers.set(k.as_str(), v.as_str())?;
             }
+            let client_ip = self.client_ip(&headers).map(|ip| ip.to_string());
 
-            let res: rlua::Result<(rlua::Integer, rlua::Value)> =
-                route.call((path.clone(), method, lua_headers, body));
+            // an optional third value lists the methods allowed for the path
+            let res: rlua::Result<(rlua::Value, rlua::Value, Option<Vec<String>>)> =
+                match match_route(ctx, &manifest, &path)? {
+                    Some((handler, params)) => {
+                        handler.call((params, method, lua_headers, body, client_ip))
+                    }
+                    None => match manifest.get::<_, Option<rlua::Function>>("route")? {
+                        Some(route) => {
+                            route.call((path.clone(), method, lua_headers, body, client_ip))
+                        }
+                        None => Ok((
+                            rlua::Value::Integer(404),
+                            rlua::Value::String(ctx.create_string("Not found")?),
+                            None,
+                        )),
+                    },
+                };
 
             let res = match res {
                 Ok(r) => r,
-                Err(e) => {
-                    eprintln!("Internal lua error: {}", e);
+                Err(_) if timed_out.load(Ordering::Relaxed) => {
+                    warn!("Route timed out: {}", path);
 
                     return mk_response(
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        "Internal server error".to_string(),
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "Route timed out".to_string(),
                     );
                 }
+                Err(e) => {
+                    error!("Internal lua error: {}", e);
+                    out_of_memory = matches!(e, rlua::Error::MemoryError(_));
+
+                    if let Some(page) = self.internal_error_page(&manifest, &path)? {
+                        return Ok(page);
+                    }
+                    return mk_lua_error_response(&e, "Internal server error", self.dev_mode);
+                }
+            };
+
+            // the status is decided once here, and every kind of response is
+            // sent with it
+            let (status, body, allowed) = match res {
+                // responses carrying their own status, like `reluax.redirect(...)`,
+                // can be returned on their own
+                (rlua::Value::Table(t), rlua::Value::Nil, allowed) => {
+                    let status = match t.get::<_, Option<u16>>("status")? {
+                        Some(status) => StatusCode::from_u16(status)
+                            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+                        None => StatusCode::OK,
+                    };
+                    (status, rlua::Value::Table(t), allowed)
+                }
+                (status, body, allowed) => {
+                    let status: rlua::Integer = ctx.unpack(status)?;
+                    let status = StatusCode::from_u16(status as u16)
+                        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                    (status, body, allowed)
+                }
             };
 
-            let status =
-                StatusCode::from_u16(res.0 as u16).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            // `reluax.static()` delegates to the public directory, keeping the route's status
+            if let rlua::Value::Table(t) = &body {
+                if is_static(t) {
+                    let static_path: Option<String> = t.get("path")?;
+                    let static_path = static_path.as_deref().unwrap_or(&path);
 
-            if status == StatusCode::NOT_FOUND && public_dir.is_some() {
+                    return match self.static_response(&manifest, static_path, &headers)? {
+                        Some(mut response) => {
+                            if response.status() == StatusCode::OK {
+                                *response.status_mut() = status;
+                            }
+                            Ok(response)
+                        }
+                        None => match self.error_page(&manifest, StatusCode::NOT_FOUND, &path)? {
+                            Some(page) => Ok(page),
+                            None => mk_response(StatusCode::NOT_FOUND, "Not found"),
+                        },
+                    };
+                }
+            }
+
+            if status == StatusCode::NOT_FOUND {
                 // try to serve a static file
-                let path = public_dir
-                    .clone()
-                    .unwrap()
-                    .join(path.trim_start_matches('/'));
+                if let Some(response) = self.static_response(&manifest, &path, &headers)? {
+                    return Ok(response);
+                }
+            }
+
+            // the manifest's page for a 404, unless the route rendered one itself
+            let not_found_page =
+                if status == StatusCode::NOT_FOUND && !matches!(body, rlua::Value::Table(_)) {
+                    self.error_page(&manifest, status, &path)?
+                } else {
+                    None
+                };
 
-                if path.is_file() {
-                    return mk_file_response(path);
+            let response = match (not_found_page, body) {
+                (Some(page), _) => Ok(page),
+                // sent as is, since it may not be text
+                (None, rlua::Value::String(s)) => mk_response(status, s.as_bytes().to_vec()),
+                (None, rlua::Value::Table(t)) if is_stream(&t) => {
+                    self.stream_response(ctx, lua_index, status, t)
                 }
+                (None, rlua::Value::Table(t)) => decode_luax_response(status, t, self.dev_mode),
+                (None, rlua::Value::Nil) => {
+                    Err(ReluaxError::Server("No route found".to_string()).into())
+                }
+                (None, rlua::Value::Error(e)) => Err(ReluaxError::Lua(e).into()),
+                (None, _) => {
+                    Err(ReluaxError::Server("Route returned invalid type".to_string()).into())
+                }
+            };
+            let mut response = match response {
+                Ok(response) => response,
+                Err(e) => match self.internal_error_page(&manifest, &path)? {
+                    Some(page) => {
+                        error!("Internal server error: {}", e);
+                        page
+                    }
+                    None => return Err(e),
+                },
+            };
+
+            if let Some(allowed) = allowed {
+                response
+                    .headers_mut()
+                    .insert(ALLOW, HeaderValue::from_str(&allow_header(&allowed))?);
             }
+            if let Some(origin) = cors_origin {
+                // a route can still answer with an origin of its own
+                if !response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN) {
+                    response
+                        .headers_mut()
+                        .insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_str(&origin)?);
+                }
+            }
+
+            // only whole, successful responses which don't set cookies are cached
+            if status == StatusCode::OK
+                && matches!(response.body(), Either::Left(_))
+                && !response.headers().contains_key(SET_COOKIE)
+            {
+                if let Some(policy) = CachePolicy::from_manifest(ctx, &manifest, &path)? {
+                    response.extensions_mut().insert(policy);
+                }
+            }
+
+            Ok(response)
+        });
+
+        if limited {
+            lua.remove_hook();
+        }
+        // free what the route left behind right away, so that the state isn't
+        // stuck at its memory limit
+        if out_of_memory {
+            let _ = lua.gc_collect();
+        }
+
+        res
+    }
+
+    /// The address of the client, taken from the last address in
+    /// `X-Forwarded-For` when behind a trusted proxy
+    ///
+    /// The proxy appends the address it got the request from, while anything
+    /// before it comes from the client and can't be trusted.
+    fn client_ip(&self, headers: &[(String, String)]) -> Option<IpAddr> {
+        if self.trust_proxy {
+            let forwarded = find_header(headers, "x-forwarded-for")
+                .and_then(|forwarded| forwarded.rsplit(',').next())
+                .and_then(|ip| ip.trim().parse().ok());
 
-            match res.1 {
-                rlua::Value::String(s) => mk_response(status, s.to_str()?.to_string()),
-                rlua::Value::Table(t) => decode_luax_response(status, t),
-                rlua::Value::Nil => Err(ReluaxError::Server("No route found".to_string()).into()),
-                rlua::Value::Error(e) => Err(ReluaxError::Lua(e).into()),
-                _ => Err(ReluaxError::Server("Route returned invalid type".to_string()).into()),
+            if forwarded.is_some() {
+                return forwarded;
             }
-        })?;
+        }
+
+        self.remote_addr.map(|addr| addr.ip())
+    }
+
+    /// Start streaming the chunks of a `reluax.stream` response from another
+    /// thread, which calls its producer once the current request lets go of
+    /// the Lua state
+    fn stream_response<'lua>(
+        &self,
+        ctx: rlua::Context<'lua>,
+        lua_index: usize,
+        status: StatusCode,
+        t: rlua::Table<'lua>,
+    ) -> Result<Response<ResponseBody>> {
+        let lua_headers: Option<rlua::Table> = t.get("headers")?;
+        let mime_type: Option<String> = t.get("mime_type")?;
+        let producer: rlua::Function = t.get("producer")?;
+        let producer = ctx.create_registry_value(producer)?;
+
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let lua = self.lua.clone();
+        std::thread::spawn(move || pump_stream(&lua, lua_index, producer, tx));
+
+        let response_builder = Response::builder()
+            .status(status)
+            .header(
+                "Content-Type",
+                mime_type.unwrap_or("text/html; charset=utf-8".to_string()),
+            )
+            .header("Cache-Control", "no-cache");
+
+        Ok(with_lua_headers(response_builder, lua_headers)?
+            .body(Either::Right(LuaStream { chunks: rx }))?)
+    }
+}
+
+fn is_stream(t: &rlua::Table) -> bool {
+    t.get::<_, rlua::String>("type")
+        .map_or(false, |ty| ty.as_bytes() == b"stream")
+}
+
+fn is_static(t: &rlua::Table) -> bool {
+    t.get::<_, rlua::String>("type")
+        .map_or(false, |ty| ty.as_bytes() == b"static")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use http_body_util::{BodyExt, Collected, Full};
+    use hyper::body::{Body, Bytes};
+    use hyper::{Method, Request, Response, StatusCode};
+    use rlua::Lua;
+
+    use super::{LuaPool, ResponseBody};
+
+    /// Prepare a Lua state where `require('reluax')` returns the given LuaX manifest
+    fn lua_with_manifest(manifest: &str) -> Arc<LuaPool> {
+        let lua = crate::luax::prepare_lua(false).unwrap();
+        let src = crate::luax::preprocess(&format!(
+            "package.preload['reluax'] = function() return {} end",
+            manifest
+        ))
+        .unwrap();
+        lua.context(|ctx| ctx.load(&src).exec()).unwrap();
+
+        Arc::new(LuaPool::new(vec![lua]))
+    }
+
+    fn state(lua: &Arc<LuaPool>, max_body_size: usize) -> super::State {
+        super::State {
+            lua: lua.clone(),
+            dev_mode: false,
+            public_dir: None,
+            max_body_size,
+            log: false,
+            remote_addr: None,
+            trust_proxy: false,
+            route_timeout: None,
+            memory_limit: None,
+            instruction_limit: None,
+            cache: Arc::default(),
+            rate_limiter: None,
+        }
+    }
+
+    fn connections(max_connections: usize, keep_alive: bool) -> super::ConnectionOptions {
+        super::ConnectionOptions {
+            max_connections,
+            keep_alive,
+            idle_timeout: super::IDLE_TIMEOUT,
+        }
+    }
+
+    fn request(lua: &Arc<LuaPool>, method: Method, path: &str) -> Response<ResponseBody> {
+        state(lua, usize::MAX)
+            .serve(path.to_string(), method, Collected::default(), vec![])
+            .unwrap()
+    }
+
+    async fn body_bytes(res: Response<ResponseBody>) -> Bytes {
+        res.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    fn temp_file(contents: &str) -> tempfile::TempPath {
+        temp_file_with_suffix(contents, ".css")
+    }
+
+    fn temp_file_with_suffix(contents: &str, suffix: &str) -> tempfile::TempPath {
+        let mut file = tempfile::Builder::new().suffix(suffix).tempfile().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file.into_temp_path()
+    }
+
+    #[test]
+    fn file_response_has_validators() {
+        let path = temp_file("body { color: red; }");
+        let res = super::mk_file_response(path.to_path_buf(), &[], &HashMap::new()).unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().contains_key("etag"));
+        assert!(res.headers().contains_key("last-modified"));
+        assert!(res.headers().contains_key("cache-control"));
+    }
+
+    #[test]
+    fn file_response_not_modified_etag() {
+        let path = temp_file("body { color: red; }");
+        let res = super::mk_file_response(path.to_path_buf(), &[], &HashMap::new()).unwrap();
+        let etag = res.headers()["etag"].to_str().unwrap().to_string();
+
+        let headers = vec![("if-none-match".to_string(), etag)];
+        let res = super::mk_file_response(path.to_path_buf(), &headers, &HashMap::new()).unwrap();
+
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[test]
+    fn file_response_not_modified_since() {
+        let path = temp_file("body { color: red; }");
+        let res = super::mk_file_response(path.to_path_buf(), &[], &HashMap::new()).unwrap();
+        let last_modified = res.headers()["last-modified"].to_str().unwrap().to_string();
+
+        let headers = vec![("if-modified-since".to_string(), last_modified)];
+        let res = super::mk_file_response(path.to_path_buf(), &headers, &HashMap::new()).unwrap();
+
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[test]
+    fn file_response_modified_etag() {
+        let path = temp_file("body { color: red; }");
+
+        let headers = vec![("if-none-match".to_string(), "\"stale\"".to_string())];
+        let res = super::mk_file_response(path.to_path_buf(), &headers, &HashMap::new()).unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn file_response_mime_type() {
+        let path = temp_file_with_suffix("", ".woff2");
+        let res = super::mk_file_response(path.to_path_buf(), &[], &HashMap::new()).unwrap();
+
+        assert_eq!(res.headers()["content-type"], "font/woff2");
+    }
+
+    #[test]
+    fn file_response_mime_type_override() {
+        let path = temp_file_with_suffix("", ".woff2");
+        let mime_types = HashMap::from([("woff2".to_string(), "application/x-font".to_string())]);
+        let res = super::mk_file_response(path.to_path_buf(), &[], &mime_types).unwrap();
+
+        assert_eq!(res.headers()["content-type"], "application/x-font");
+    }
+
+    #[test]
+    fn file_response_mime_type_any_case() {
+        let path = temp_file_with_suffix("", ".PNG");
+        let res = super::mk_file_response(path.to_path_buf(), &[], &HashMap::new()).unwrap();
+
+        assert_eq!(res.headers()["content-type"], "image/png");
+
+        let path = temp_file_with_suffix("", ".WOFF2");
+        let mime_types = HashMap::from([("Woff2".to_string(), "application/x-font".to_string())]);
+        let res = super::mk_file_response(path.to_path_buf(), &[], &mime_types).unwrap();
+
+        assert_eq!(res.headers()["content-type"], "application/x-font");
+    }
+
+    #[tokio::test]
+    async fn file_response_precompressed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.js");
+        std::fs::write(&path, "console.log('plain');").unwrap();
+        std::fs::write(dir.path().join("app.js.br"), "brotli").unwrap();
+        std::fs::write(dir.path().join("app.js.gz"), "gzip").unwrap();
+
+        let headers = vec![("accept-encoding".to_string(), "gzip, br".to_string())];
+        let res = super::mk_file_response(path.clone(), &headers, &HashMap::new()).unwrap();
+
+        assert_eq!(res.headers()["content-encoding"], "br");
+        assert_eq!(
+            res.headers()["content-type"],
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(res.headers()["vary"], "Accept-Encoding");
+        assert_eq!(body_bytes(res).await, "brotli");
+
+        let headers = vec![("accept-encoding".to_string(), "gzip, br;q=0".to_string())];
+        let res = super::mk_file_response(path.clone(), &headers, &HashMap::new()).unwrap();
+
+        assert_eq!(res.headers()["content-encoding"], "gzip");
+        assert_eq!(body_bytes(res).await, "gzip");
+
+        let res = super::mk_file_response(path, &[], &HashMap::new()).unwrap();
+
+        assert!(!res.headers().contains_key("content-encoding"));
+        assert_eq!(
+            res.headers()["content-type"],
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(body_bytes(res).await, "console.log('plain');");
+    }
+
+    #[tokio::test]
+    async fn manifest_is_required_once() {
+        let lua = lua_with_manifest(
+            r#"(function()
+                loads = (loads or 0) + 1
+                local manifest = {}
+                manifest.route = function(path)
+                    -- the module can't be required again after this
+                    package.loaded['reluax'] = nil
+                    package.preload['reluax'] = nil
+                    if path == '/swap' then
+                        manifest.route = function() return 200, 'swapped' end
+                    end
+                    return 200, tostring(loads)
+                end
+                return manifest
+            end)()"#,
+        );
+        let state = state(&lua, 1024);
+        let get =
+            |path: &str| state.serve(path.to_string(), Method::GET, Collected::default(), vec![]);
+
+        assert_eq!(body_bytes(get("/").unwrap()).await, "1");
+        assert_eq!(body_bytes(get("/").unwrap()).await, "1");
+
+        // changes to the manifest are still seen by later requests
+        assert_eq!(body_bytes(get("/swap").unwrap()).await, "1");
+        assert_eq!(body_bytes(get("/").unwrap()).await, "swapped");
+    }
+
+    #[test]
+    fn lua_pool_hands_out_free_states() {
+        let pool = LuaPool::new(vec![Lua::new(), Lua::new()]);
+
+        let a = pool.get();
+        let b = pool.get();
+
+        assert!(!std::ptr::eq(&*a, &*b));
+    }
+
+    #[tokio::test]
+    async fn error_pages() {
+        let lua = lua_with_manifest(
+            "{
+                error_pages = {
+                    [404] = function(path) return <h1>{$ path $} is missing</h1> end,
+                    [500] = <h1>Something went wrong</h1>,
+                },
+                route = function(path)
+                    if path == '/broken' then error('oops') end
+                    if path == '/custom' then return 404, <p>custom</p> end
+                    return 404, 'Not found'
+                end,
+            }",
+        );
+
+        let res = request(&lua, Method::GET, "/missing");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+        let body = body_bytes(res).await;
+        assert!(String::from_utf8_lossy(&body).contains("<h1>/missing is missing</h1>"));
+
+        // a route rendering its own page keeps it
+        let res = request(&lua, Method::GET, "/custom");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(String::from_utf8_lossy(&body_bytes(res).await).contains("<p>custom</p>"));
+
+        let res = request(&lua, Method::GET, "/broken");
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(String::from_utf8_lossy(&body_bytes(res).await)
+            .contains("<h1>Something went wrong</h1>"));
+    }
+
+    #[tokio::test]
+    async fn head_request() {
+        let lua = lua_with_manifest(
+            "{ route = function(path, method) if method == 'GET' then return 200, 'hello' end return 405, 'no' end }",
+        );
+
+        let res = request(&lua, Method::GET, "/");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body_bytes(res).await, "hello");
+
+        let res = request(&lua, Method::HEAD, "/");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-length"], "5");
+        assert_eq!(res.body().size_hint().exact(), Some(0));
+        assert_eq!(body_bytes(res).await, "");
+    }
+
+    #[tokio::test]
+    async fn content_length() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("app.js"), "console.log(1)").unwrap();
+
+        let lua = lua_with_manifest(
+            "{ route = function(path)
+                if path == '/text' then return 200, 'hello' end
+                if path == '/page' then return 200, <p>hi</p> end
+                if path == '/json' then return 200, reluax.json({ a = 1 }) end
+                if path == '/lying' then
+                    return 200, reluax.headers(<p>hi</p>, { ['Content-Length'] = '99' })
+                end
+                return 404, 'Not found'
+            end }",
+        );
+        let state = static_state(&lua, dir.path());
+
+        for path in ["/text", "/page", "/json", "/lying", "/app.js", "/missing"] {
+            let res = get(&state, path);
+            let len: usize = res.headers()["content-length"]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert_eq!(len, body_bytes(res).await.len(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn method_not_allowed() {
+        let lua = lua_with_manifest(
+            "{ route = function(path, method) if method == 'GET' then return 200, 'hello' end return 405, 'no', { 'GET', 'post' } end }",
+        );
+
+        let res = request(&lua, Method::GET, "/");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res.headers().contains_key("allow"));
+
+        let res = request(&lua, Method::DELETE, "/");
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()["allow"], "GET, HEAD, POST");
+    }
+
+    #[test]
+    fn router_method_not_allowed() {
+        let lua = lua_with_manifest(
+            r#"{
+                route = function(path, method, headers, body)
+                    local app = reluax.router()
+                        :get("/users/{id}", function() return 200, "user" end)
+                        :delete("/users/{id}", function() return 204, "" end)
+                    return app:match(path, method, headers, body)
+                end
+            }"#,
+        );
+
+        let res = request(&lua, Method::POST, "/users/1");
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()["allow"], "GET, HEAD, DELETE");
+    }
+
+    #[tokio::test]
+    async fn methods_with_bodies() {
+        let lua = lua_with_manifest(
+            "{ route = function(path, method, headers, body) return 200, method .. ' ' .. body end }",
+        );
+
+        for method in [Method::PUT, Method::PATCH, Method::DELETE, Method::POST] {
+            let req = Request::builder()
+                .method(method.clone())
+                .uri("/items/1")
+                .body(Full::new(Bytes::from("payload")))
+                .unwrap();
+
+            let res = state(&lua, usize::MAX).handle(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                body_bytes(res).await,
+                format!("{} payload", method.as_str())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn options_reach_the_route() {
+        let lua = lua_with_manifest("{ route = function(path, method) return 200, method end }");
+
+        let res = request(&lua, Method::OPTIONS, "/");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body_bytes(res).await, "OPTIONS");
+    }
+
+    #[tokio::test]
+    async fn options_response() {
+        let lua = lua_with_manifest(
+            "{
+                allowed_methods = { 'GET', 'put', 'DELETE' },
+                cors_origin = 'https://example.com',
+                route = function(path, method) return 200, method end,
+            }",
+        );
+        let options = |headers: Vec<(String, String)>| {
+            state(&lua, usize::MAX)
+                .serve(
+                    "/items/1".to_string(),
+                    Method::OPTIONS,
+                    Collected::default(),
+                    headers,
+                )
+                .unwrap()
+        };
+
+        let res = options(vec![]);
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers()["allow"], "GET, HEAD, PUT, DELETE, OPTIONS");
+        assert!(!res.headers().contains_key("access-control-allow-origin"));
+        assert_eq!(body_bytes(res).await, "");
+
+        let res = options(vec![
+            ("Origin".to_string(), "https://example.com".to_string()),
+            (
+                "Access-Control-Request-Method".to_string(),
+                "PUT".to_string(),
+            ),
+            (
+                "Access-Control-Request-Headers".to_string(),
+                "content-type".to_string(),
+            ),
+        ]);
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.headers()["access-control-allow-origin"],
+            "https://example.com"
+        );
+        assert_eq!(
+            res.headers()["access-control-allow-methods"],
+            "GET, HEAD, PUT, DELETE, OPTIONS"
+        );
+        assert_eq!(
+            res.headers()["access-control-allow-headers"],
+            "content-type"
+        );
 
-        Ok(res)
+        // the other methods still go to the route, with the origin allowed
+        let res = request(&lua, Method::PUT, "/items/1");
+        assert_eq!(
+            res.headers()["access-control-allow-origin"],
+            "https://example.com"
+        );
+        assert_eq!(body_bytes(res).await, "PUT");
+    }
+
+    #[tokio::test]
+    async fn streamed_response() {
+        let lua = lua_with_manifest(
+            r#"{
+                route = function(path)
+                    local items = { "one", "two", "three" }
+                    return 200, reluax.headers(reluax.stream(coroutine.wrap(function()
+                        coroutine.yield("<ul>")
+                        for _, item in ipairs(items) do
+                            coroutine.yield(<li>{item}</li>)
+                        end
+                        coroutine.yield("</ul>")
+                    end), "text/html"), { ["X-Streamed"] = "yes" })
+                end
+            }"#,
+        );
+
+        let res = request(&lua, Method::GET, "/");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "text/html");
+        assert_eq!(res.headers()["x-streamed"], "yes");
+        assert_eq!(res.body().size_hint().exact(), None);
+        assert_eq!(
+            body_bytes(res).await,
+            "<ul><li>one</li><li>two</li><li>three</li></ul>"
+        );
+
+        let res = request(&lua, Method::HEAD, "/");
+        assert!(!res.headers().contains_key("content-length"));
+        assert_eq!(body_bytes(res).await, "");
+    }
+
+    #[tokio::test]
+    async fn binary_upload_round_trips() {
+        let lua = lua_with_manifest(
+            r#"{
+                route = function(path, method, headers, body)
+                    local form = reluax.parse_multipart(body, headers["content-type"])
+                    return 200, form.image.data
+                end
+            }"#,
+        );
+
+        let png: &[u8] = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR\xff\xfe\x80";
+        let mut body = Vec::new();
+        body.extend_from_slice(b"--b\r\nContent-Disposition: form-data; name=\"image\"; ");
+        body.extend_from_slice(b"filename=\"a.png\"\r\nContent-Type: image/png\r\n\r\n");
+        body.extend_from_slice(png);
+        body.extend_from_slice(b"\r\n--b--\r\n");
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/upload")
+            .header("Content-Type", "multipart/form-data; boundary=b")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap();
+
+        let res = state(&lua, usize::MAX).handle(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body_bytes(res).await, png);
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        use crate::rate_limit::{RateLimit, RateLimiter};
+
+        let lua = lua_with_manifest("{ route = function() return 200, 'hello' end }");
+        let limited = super::State {
+            remote_addr: Some("192.0.2.1:54321".parse().unwrap()),
+            rate_limiter: Some(Arc::new(RateLimiter::new(
+                RateLimit { rate: 1, burst: 5 },
+                16,
+            ))),
+            ..state(&lua, usize::MAX)
+        };
+        let get = |state: &super::State| {
+            let req = Request::get("/").body(Full::new(Bytes::new())).unwrap();
+            state.clone().handle(req)
+        };
+
+        let mut statuses = Vec::new();
+        for _ in 0..8 {
+            statuses.push(get(&limited).await.unwrap().status());
+        }
+        assert_eq!(&statuses[..5], &[StatusCode::OK; 5]);
+        assert_eq!(&statuses[5..], &[StatusCode::TOO_MANY_REQUESTS; 3]);
+
+        let res = get(&limited).await.unwrap();
+        assert_eq!(res.headers()["retry-after"], "1");
+
+        // other clients aren't affected
+        let other = super::State {
+            remote_addr: Some("192.0.2.2:54321".parse().unwrap()),
+            ..limited.clone()
+        };
+        assert_eq!(get(&other).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn remote_addr() {
+        let lua = lua_with_manifest(
+            r#"{
+                route = function(path, method, headers, body, remote_addr)
+                    return 200, remote_addr or "unknown"
+                end
+            }"#,
+        );
+
+        let forwarded = vec![(
+            "X-Forwarded-For".to_string(),
+            "1.2.3.4, 10.0.0.9".to_string(),
+        )];
+        let request_from = |trust_proxy, headers: &[(String, String)]| {
+            let state = super::State {
+                remote_addr: Some("192.0.2.1:54321".parse().unwrap()),
+                trust_proxy,
+                ..state(&lua, usize::MAX)
+            };
+            state
+                .serve(
+                    "/".to_string(),
+                    Method::GET,
+                    Collected::default(),
+                    headers.to_vec(),
+                )
+                .unwrap()
+        };
+
+        assert_eq!(body_bytes(request_from(false, &[])).await, "192.0.2.1");
+        assert_eq!(
+            body_bytes(request_from(false, &forwarded)).await,
+            "192.0.2.1"
+        );
+        // the client can claim any address before the proxy's
+        assert_eq!(body_bytes(request_from(true, &forwarded)).await, "10.0.0.9");
+        assert_eq!(body_bytes(request_from(true, &[])).await, "192.0.2.1");
+        assert_eq!(body_bytes(request(&lua, Method::GET, "/")).await, "unknown");
+    }
+
+    #[tokio::test]
+    async fn cached_responses() {
+        let lua = lua_with_manifest(
+            r#"{
+                cache = { paths = { "/cached/*" }, max_age = 60 },
+                route = function(path)
+                    renders = (renders or 0) + 1
+                    if path == "/cached/fail" then
+                        return 500, tostring(renders)
+                    end
+                    return 200, tostring(renders)
+                end
+            }"#,
+        );
+        let state = state(&lua, usize::MAX);
+
+        let get = |method: Method, path: &str| {
+            let req = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Full::new(Bytes::new()))
+                .unwrap();
+            state.clone().handle(req)
+        };
+
+        assert_eq!(
+            body_bytes(get(Method::GET, "/cached/a").await.unwrap()).await,
+            "1"
+        );
+        assert_eq!(
+            body_bytes(get(Method::GET, "/cached/a").await.unwrap()).await,
+            "1"
+        );
+        let res = get(Method::HEAD, "/cached/a").await.unwrap();
+        assert_eq!(res.headers()["content-length"], "1");
+        assert_eq!(body_bytes(res).await, "");
+
+        assert_eq!(
+            body_bytes(get(Method::GET, "/other").await.unwrap()).await,
+            "2"
+        );
+        assert_eq!(
+            body_bytes(get(Method::GET, "/other").await.unwrap()).await,
+            "3"
+        );
+        assert_eq!(
+            body_bytes(get(Method::GET, "/cached/fail").await.unwrap()).await,
+            "4"
+        );
+        assert_eq!(
+            body_bytes(get(Method::GET, "/cached/fail").await.unwrap()).await,
+            "5"
+        );
+
+        // a reload drops everything cached before it
+        lua.reload(|| {
+            let lua = crate::luax::prepare_lua(false)?;
+            lua.context(|ctx| {
+                ctx.load("package.preload['reluax'] = function() return { route = function() return 200, 'reloaded' end } end")
+                    .exec()
+            })?;
+            Ok(lua)
+        })
+        .unwrap();
+        assert_eq!(
+            body_bytes(get(Method::GET, "/cached/a").await.unwrap()).await,
+            "reloaded"
+        );
+    }
+
+    #[tokio::test]
+    async fn route_timeout() {
+        let lua = lua_with_manifest(
+            r#"{
+                route = function(path)
+                    if path == "/slow" then
+                        while true do end
+                    end
+                    return 200, "fast"
+                end
+            }"#,
+        );
+        let state = super::State {
+            route_timeout: Some(std::time::Duration::from_millis(50)),
+            ..state(&lua, usize::MAX)
+        };
+        let get = |path: &str| {
+            state
+                .serve(path.to_string(), Method::GET, Collected::default(), vec![])
+                .unwrap()
+        };
+
+        let res = get("/slow");
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // the state is still usable afterwards
+        let res = get("/fast");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body_bytes(res).await, "fast");
+    }
+
+    #[tokio::test]
+    async fn instruction_limit() {
+        let lua = lua_with_manifest(
+            r#"{
+                route = function(path)
+                    if path == "/loop" then
+                        while true do end
+                    end
+                    local n = 0
+                    for i = 1, 100 do n = n + i end
+                    return 200, tostring(n)
+                end
+            }"#,
+        );
+        let state = super::State {
+            instruction_limit: Some(100_000),
+            ..state(&lua, usize::MAX)
+        };
+        let get = |path: &str| {
+            state
+                .serve(path.to_string(), Method::GET, Collected::default(), vec![])
+                .unwrap()
+        };
+
+        assert_eq!(get("/loop").status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // the count starts over for every request
+        for _ in 0..3 {
+            let res = get("/sum");
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(body_bytes(res).await, "5050");
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_limit() {
+        let lua = lua_with_manifest(
+            r#"{
+                route = function(path)
+                    if path == "/grow" then
+                        local t = {}
+                        for i = 1, 1e6 do t[i] = ("x"):rep(64) .. i end
+                    elseif path == "/cheat" then
+                        collectgarbage = function() return 0 end
+                        local t = {}
+                        for i = 1, 1e6 do t[i] = ("x"):rep(64) .. i end
+                    end
+                    return 200, "small"
+                end
+            }"#,
+        );
+        let used: f64 = lua
+            .get()
+            .context(|ctx| ctx.load("collectgarbage('count')").eval())
+            .unwrap();
+        let state = super::State {
+            memory_limit: Some(used as usize * 1024 + 16 * 1024 * 1024),
+            ..state(&lua, usize::MAX)
+        };
+        let get = |path: &str| {
+            state
+                .serve(path.to_string(), Method::GET, Collected::default(), vec![])
+                .unwrap()
+        };
+
+        assert_eq!(get("/grow").status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // the state is still usable afterwards
+        let res = get("/");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body_bytes(res).await, "small");
+
+        // replacing `collectgarbage` doesn't hide the memory from the limit
+        assert_eq!(get("/cheat").status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn lua_error_details_in_dev_mode() {
+        let lua = lua_with_manifest(
+            r#"{
+                route = function(path)
+                    local function fail()
+                        error("<boom> in " .. path)
+                    end
+                    fail()
+                end
+            }"#,
+        );
+
+        let res = request(&lua, Method::GET, "/page");
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body_bytes(res).await, "Internal server error");
+
+        let dev = super::State {
+            dev_mode: true,
+            ..state(&lua, usize::MAX)
+        };
+        let res = dev
+            .serve(
+                "/page".to_string(),
+                Method::GET,
+                Collected::default(),
+                vec![],
+            )
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+
+        let body = body_bytes(res).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("&lt;boom&gt; in /page"), "{}", body);
+        assert!(body.contains("stack traceback"), "{}", body);
+        assert!(body.contains("'fail'"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn manifest_routes() {
+        let lua = lua_with_manifest(
+            r#"{
+                routes = {
+                    { "/users/{id}", function(params, method)
+                        return 200, method .. " user " .. params.id
+                    end },
+                    { "/files/*", function(params)
+                        return 200, "file " .. params.splat
+                    end },
+                },
+                route = function(path)
+                    return 404, "fallback " .. path
+                end
+            }"#,
+        );
+
+        let res = request(&lua, Method::GET, "/users/42");
+        assert_eq!(body_bytes(res).await, "GET user 42");
+        let res = request(&lua, Method::GET, "/files/a/b.txt");
+        assert_eq!(body_bytes(res).await, "file a/b.txt");
+
+        let res = request(&lua, Method::GET, "/missing");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body_bytes(res).await, "fallback /missing");
+
+        let lua = lua_with_manifest(
+            r#"{
+                routes = { { "/", function() return 200, "home" end } },
+            }"#,
+        );
+        assert_eq!(body_bytes(request(&lua, Method::GET, "/")).await, "home");
+        assert_eq!(
+            request(&lua, Method::GET, "/missing").status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn redirect() {
+        let lua = lua_with_manifest(
+            r#"{
+                route = function(path)
+                    if path == "/old" then
+                        return reluax.redirect("/new", 301)
+                    elseif path == "/login" then
+                        return 302, reluax.headers(reluax.redirect("/"), { ["Set-Cookie"] = "a=b" })
+                    elseif path == "/moved" then
+                        return 200, reluax.redirect("/x", 301)
+                    end
+                    return reluax.redirect("/login")
+                end
+            }"#,
+        );
+
+        let res = request(&lua, Method::GET, "/old");
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(res.headers()["location"], "/new");
+        assert_eq!(body_bytes(res).await, "");
+
+        let res = request(&lua, Method::GET, "/login");
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(res.headers()["location"], "/");
+        assert_eq!(res.headers()["set-cookie"], "a=b");
+
+        let res = request(&lua, Method::GET, "/admin");
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(res.headers()["location"], "/login");
+
+        let res = request(&lua, Method::GET, "/moved");
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(res.headers()["location"], "/x");
+    }
+
+    #[tokio::test]
+    async fn body_size_limit() {
+        let lua = lua_with_manifest(
+            "{ route = function(path, method, headers, body) return 200, body end }",
+        );
+
+        let req = Request::post("/")
+            .body(Full::new(Bytes::from("small")))
+            .unwrap();
+        let res = state(&lua, 16).handle(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body_bytes(res).await, "small");
+
+        let req = Request::post("/")
+            .body(Full::new(Bytes::from("this body is way too large")))
+            .unwrap();
+        let res = state(&lua, 16).handle(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn declared_body_size_limit() {
+        let lua = lua_with_manifest(
+            "{ route = function(path, method, headers, body) return 200, body end }",
+        );
+
+        let req = Request::post("/")
+            .header("Content-Length", "1000")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let res = state(&lua, 16).handle(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn expect_continue() {
+        let lua = lua_with_manifest(
+            "{ route = function(path, method, headers, body) return 200, body end }",
+        );
+
+        let req = Request::post("/")
+            .header("Expect", "100-continue")
+            .header("Content-Length", "5")
+            .body(Full::new(Bytes::from("small")))
+            .unwrap();
+        let res = state(&lua, 16).handle(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = Request::post("/")
+            .header("Expect", "100-continue")
+            .header("Content-Length", "1000000")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let res = state(&lua, 16).handle(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let req = Request::post("/")
+            .header("Expect", "something-else")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let res = state(&lua, 16).handle(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::EXPECTATION_FAILED);
+    }
+
+    #[test]
+    fn inject_live_reload() {
+        let mut page = b"<html><body><p>hi</p></body></html>".to_vec();
+        super::inject_live_reload(&mut page);
+        let page = String::from_utf8(page).unwrap();
+        assert!(page.starts_with("<html><body><p>hi</p><script>"));
+        assert!(page.ends_with("</script></body></html>"));
+
+        let mut page = b"<p>hi</p>".to_vec();
+        super::inject_live_reload(&mut page);
+        let page = String::from_utf8(page).unwrap();
+        assert!(page.starts_with("<p>hi</p><script>"));
+    }
+
+    #[tokio::test]
+    async fn html_page_lang_and_doctype() {
+        let lua = lua_with_manifest(
+            "{ route = function(path)
+                if path == '/legacy' then
+                    return 200, reluax.html_page(<body>hi</body>, { doctype = 'html SYSTEM \\'about:legacy-compat\\'' })
+                end
+                return 200, reluax.html_page(<body>hi</body>, { lang = 'pl' })
+            end }",
+        );
+
+        let res = request(&lua, Method::GET, "/");
+        assert_eq!(
+            body_bytes(res).await,
+            "<!DOCTYPE html>\n<html lang=\"pl\"><body>hi</body></html>"
+        );
+
+        let res = request(&lua, Method::GET, "/legacy");
+        assert_eq!(
+            body_bytes(res).await,
+            "<!DOCTYPE html SYSTEM 'about:legacy-compat'>\n<body>hi</body>"
+        );
+    }
+
+    #[tokio::test]
+    async fn xhtml_response() {
+        let lua = lua_with_manifest(
+            "{ route = function(path)
+                if path == '/icon.svg' then return 200, reluax.xhtml(<svg><path d=\"M0 0\" /></svg>, 'image/svg+xml') end
+                return 200, reluax.xhtml(<p>a<br />b</p>)
+            end }",
+        );
+
+        let res = request(&lua, Method::GET, "/");
+        assert_eq!(res.headers()["content-type"], "application/xhtml+xml");
+        assert_eq!(body_bytes(res).await, "<p>a<br/>b</p>");
+
+        let res = request(&lua, Method::GET, "/icon.svg");
+        assert_eq!(res.headers()["content-type"], "image/svg+xml");
+        assert_eq!(body_bytes(res).await, "<svg><path d=\"M0 0\"/></svg>");
+    }
+
+    #[tokio::test]
+    async fn xml_response() {
+        let lua = lua_with_manifest(
+            "{ route = function() return 200, reluax.xml(<rss><channel><title>A & B</title></channel></rss>) end }",
+        );
+
+        let res = request(&lua, Method::GET, "/feed.xml");
+        assert_eq!(res.headers()["content-type"], "application/xml");
+        assert_eq!(
+            body_bytes(res).await,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<rss><channel><title>A &amp; B</title></channel></rss>"
+        );
+    }
+
+    #[tokio::test]
+    async fn custom_statuses() {
+        let lua = lua_with_manifest(
+            "{ route = function(path)
+                if path == '/created' then
+                    return 201, reluax.headers(reluax.json({ id = 1 }), { Location = '/items/1' })
+                elseif path == '/teapot' then
+                    return 418, <p>I'm a teapot</p>
+                elseif path == '/fragment' then
+                    return 422, reluax.html(<p>invalid</p>)
+                end
+                return 303, reluax.redirect('/created')
+            end }",
+        );
+
+        let res = request(&lua, Method::GET, "/created");
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers()["content-type"], "application/json");
+        assert_eq!(res.headers()["location"], "/items/1");
+        assert_eq!(body_bytes(res).await, "{\"id\":1}");
+
+        let res = request(&lua, Method::GET, "/teapot");
+        assert_eq!(res.status(), StatusCode::IM_A_TEAPOT);
+        assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+        assert_eq!(
+            body_bytes(res).await,
+            "<!DOCTYPE html>\n<p>I'm a teapot</p>"
+        );
+
+        let res = request(&lua, Method::GET, "/fragment");
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let res = request(&lua, Method::GET, "/other");
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers()["location"], "/created");
+    }
+
+    #[test]
+    fn plain_text_errors() {
+        for manifest in ["{}", "{ route = function() return 404, 'Not found' end }"] {
+            let res = request(&lua_with_manifest(manifest), Method::GET, "/nowhere");
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            assert_eq!(res.headers()["content-type"], "text/plain; charset=utf-8");
+        }
+
+        let res =
+            super::mk_internal_error_response(color_eyre::eyre::eyre!("No route found")).unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(res.headers()["content-type"], "text/plain; charset=utf-8");
+    }
+
+    #[test]
+    fn html_charset() {
+        let lua = lua_with_manifest(
+            "{ route = function(path)
+                if path == '/fragment' then return 200, reluax.html(<p>zażółć</p>) end
+                return 200, <p>zażółć</p>
+            end }",
+        );
+
+        for path in ["/", "/fragment"] {
+            let res = request(&lua, Method::GET, path);
+            assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+        }
+    }
+
+    #[tokio::test]
+    async fn text_response() {
+        let lua = lua_with_manifest(
+            "{ route = function(path)
+                if path == '/robots.txt' then return 200, reluax.text('User-agent: *\\nDisallow: <none>') end
+                return 200, reluax.text('a,b\\n1,2', 'text/csv')
+            end }",
+        );
+
+        let res = request(&lua, Method::GET, "/robots.txt");
+        assert_eq!(res.headers()["content-type"], "text/plain; charset=utf-8");
+        assert_eq!(body_bytes(res).await, "User-agent: *\nDisallow: <none>");
+
+        let res = request(&lua, Method::GET, "/data.csv");
+        assert_eq!(res.headers()["content-type"], "text/csv");
+        assert_eq!(body_bytes(res).await, "a,b\n1,2");
+    }
+
+    #[tokio::test]
+    async fn live_reload_only_in_dev_mode() {
+        let lua =
+            lua_with_manifest("{ route = function() return 200, <html><body></body></html> end }");
+
+        let res = request(&lua, Method::GET, "/");
+        assert!(!String::from_utf8_lossy(&body_bytes(res).await).contains("<script>"));
+
+        let mut dev = state(&lua, usize::MAX);
+        dev.dev_mode = true;
+        let res = dev
+            .serve("/".to_string(), Method::GET, Collected::default(), vec![])
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body_bytes(res).await).contains("<script>"));
+    }
+
+    #[tokio::test]
+    async fn live_reload_event() {
+        let lua = lua_with_manifest("{ route = function() return 404, 'Not found' end }");
+
+        let mut dev = state(&lua, usize::MAX);
+        dev.dev_mode = true;
+        let req = Request::get(super::LIVE_RELOAD_PATH)
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let res = tokio::spawn(dev.handle(req));
+
+        // give the request a chance to start waiting
+        tokio::task::yield_now().await;
+        lua.reload(|| crate::luax::prepare_lua(true)).unwrap();
+
+        let res = res.await.unwrap().unwrap();
+        assert_eq!(res.headers()["content-type"], "text/event-stream");
+        assert_eq!(body_bytes(res).await, "data: reload\n\n");
+    }
+
+    #[tokio::test]
+    async fn http2() {
+        let lua = lua_with_manifest(
+            r#"{
+                route = function(path)
+                    return 200, "Hello over " .. path
+                end
+            }"#,
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(super::Server::accept(
+            listener,
+            state(&lua, usize::MAX),
+            connections(usize::MAX, true),
+            std::future::pending(),
+        ));
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (client, connection) = h2::client::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+
+        let mut client = client.ready().await.unwrap();
+        let req = Request::get(format!("http://{}/h2", addr))
+            .body(())
+            .unwrap();
+        let (res, _) = client.send_request(req, true).unwrap();
+        let res = res.await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut body = res.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(bytes, b"Hello over /h2");
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown() {
+        use std::time::Duration;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let lua = lua_with_manifest(
+            r#"{
+                route = function(path)
+                    if path == "/slow" then
+                        local start = os.clock()
+                        while os.clock() - start < 0.3 do end
+                    end
+                    return 200, 'hello'
+                end
+            }"#,
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(super::Server::accept(
+            listener,
+            state(&lua, usize::MAX),
+            connections(usize::MAX, true),
+            async {
+                stopped.await.ok();
+            },
+        ));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+
+        // the request is still being routed when the server is stopped
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stop.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        // by the time the server returns, it was answered, and the kept-alive
+        // connection closed
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK"));
+        assert!(res.ends_with("hello"));
+
+        // the listener is closed once the server returns
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn connection_limit() {
+        use std::time::Duration;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let lua = lua_with_manifest("{ route = function() return 200, 'hello' end }");
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(super::Server::accept(
+            listener,
+            state(&lua, usize::MAX),
+            connections(1, true),
+            std::future::pending(),
+        ));
+
+        async fn send(stream: &mut tokio::net::TcpStream) -> String {
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+
+            let mut buf = vec![0; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        }
+
+        // the first connection is kept alive, taking up the only slot
+        let mut first = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert!(send(&mut first).await.starts_with("HTTP/1.1 200 OK"));
+
+        // the second one waits until the first closes, instead of failing
+        let mut second = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let waiting = tokio::spawn(async move { send(&mut second).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!waiting.is_finished());
+
+        drop(first);
+        let res = tokio::time::timeout(Duration::from_secs(5), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        use std::time::Duration;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let lua = lua_with_manifest("{ route = function() return 200, 'hello' end }");
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(super::Server::accept(
+            listener,
+            state(&lua, usize::MAX),
+            super::ConnectionOptions {
+                idle_timeout: Duration::from_millis(200),
+                ..connections(1, true)
+            },
+            std::future::pending(),
+        ));
+
+        async fn send(stream: &mut tokio::net::TcpStream) -> String {
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+
+            let mut buf = vec![0; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        }
+
+        // the first connection is kept alive but never used again
+        let mut first = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert!(send(&mut first).await.starts_with("HTTP/1.1 200 OK"));
+
+        // the second one is served once the first was idle for too long
+        let mut second = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let res = tokio::time::timeout(Duration::from_secs(5), send(&mut second))
+            .await
+            .unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK"));
+
+        // and the server closed the first one
+        let mut buf = vec![0; 16];
+        assert_eq!(first.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn static_file_path() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("docs/empty")).unwrap();
+        std::fs::write(dir.path().join("docs/index.html"), "docs").unwrap();
+        std::fs::write(dir.path().join("about.html"), "about").unwrap();
+        std::fs::write(dir.path().join("style.css"), "css").unwrap();
+
+        let cases = vec![
+            ("/docs/", Some("docs/index.html")),
+            ("/docs", Some("docs/index.html")),
+            ("/docs/empty/", None),
+            ("/about", Some("about.html")),
+            ("/about.html", Some("about.html")),
+            ("/about/", None),
+            ("/style.css", Some("style.css")),
+            ("/style", None),
+            ("/missing", None),
+            ("/../about.html", None),
+            ("/docs/../../about.html", None),
+        ];
+
+        for (path, expected) in cases {
+            assert_eq!(
+                super::static_file_path(dir.path(), path),
+                expected.map(|file| dir.path().join(file)),
+                "resolving {}",
+                path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn static_directory_index() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("docs")).unwrap();
+        std::fs::write(dir.path().join("docs/index.html"), "<h1>Docs</h1>").unwrap();
+
+        let lua = lua_with_manifest("{}");
+        let state = super::State {
+            public_dir: Some(dir.path().to_path_buf()),
+            ..state(&lua, usize::MAX)
+        };
+        let serve = |path: &str| {
+            state
+                .serve(path.to_string(), Method::GET, Collected::default(), vec![])
+                .unwrap()
+        };
+
+        let res = serve("/docs/");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+        assert_eq!(body_bytes(res).await, "<h1>Docs</h1>");
+
+        assert_eq!(serve("/../docs/index.html").status(), StatusCode::NOT_FOUND);
+    }
+
+    fn static_state(lua: &Arc<LuaPool>, public_dir: &Path) -> super::State {
+        super::State {
+            public_dir: Some(public_dir.to_path_buf()),
+            ..state(lua, usize::MAX)
+        }
+    }
+
+    fn get(state: &super::State, path: &str) -> Response<ResponseBody> {
+        state
+            .serve(path.to_string(), Method::GET, Collected::default(), vec![])
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn route_first() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("about.html"), "static").unwrap();
+        std::fs::write(dir.path().join("app.js"), "js").unwrap();
+
+        let lua = lua_with_manifest(
+            "{ route = function(path)
+                if path == '/about' then return 200, 'route' end
+                return 404, 'Not found'
+            end }",
+        );
+        let state = static_state(&lua, dir.path());
+
+        assert_eq!(body_bytes(get(&state, "/about")).await, "route");
+        assert_eq!(body_bytes(get(&state, "/app.js")).await, "js");
+    }
+
+    #[tokio::test]
+    async fn static_first() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("about.html"), "static").unwrap();
+
+        let lua = lua_with_manifest(
+            "{ static_first = true, route = function(path)
+                if path == '/contact' then return 200, 'route' end
+                return 404, 'Not found'
+            end }",
+        );
+        let state = static_state(&lua, dir.path());
+
+        assert_eq!(body_bytes(get(&state, "/about")).await, "static");
+        assert_eq!(body_bytes(get(&state, "/contact")).await, "route");
+        assert_eq!(get(&state, "/missing").status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn delegate_to_static() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("about.html"), "static").unwrap();
+        std::fs::write(dir.path().join("gone.html"), "gone").unwrap();
+
+        let lua = lua_with_manifest(
+            "{ route = function(path)
+                if path == '/old-about' then return reluax.static('/about') end
+                if path == '/gone' then return 410, reluax.static() end
+                if path == '/missing' then return reluax.static() end
+                return 200, 'route'
+            end }",
+        );
+        let state = static_state(&lua, dir.path());
+
+        let res = get(&state, "/old-about");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body_bytes(res).await, "static");
+
+        let res = get(&state, "/gone");
+        assert_eq!(res.status(), StatusCode::GONE);
+        assert_eq!(body_bytes(res).await, "gone");
+
+        assert_eq!(get(&state, "/missing").status(), StatusCode::NOT_FOUND);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn static_file_outside_public_dir() {
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret.txt"), "secret").unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("public")).unwrap();
+        std::fs::write(dir.path().join("private.txt"), "private").unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("public/linked")).unwrap();
+
+        let public_dir = dir.path().join("public");
+        for path in [
+            "/../private.txt",
+            "/../../etc/passwd",
+            "/linked/secret.txt",
+            "/linked/../../private.txt",
+        ] {
+            assert_eq!(
+                super::static_file_path(&public_dir, path),
+                None,
+                "resolving {}",
+                path
+            );
+        }
     }
 }
@@ -0,0 +1,109 @@
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use color_eyre::{owo_colors::OwoColorize, Result};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::luax;
+use crate::server::LuaPool;
+use crate::{debug, error, info};
+
+/// How long to wait for more changes before reloading, so that saving several
+/// files at once only triggers one reload
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Watch the project sources for changes, preprocessing them again into
+/// `serve_dir` and rebuilding the Lua states, so that the next request sees
+/// the edits. The returned watcher stops watching when dropped.
+pub fn watch_for_changes(
+    source_dir: PathBuf,
+    serve_dir: PathBuf,
+    lua: Arc<LuaPool>,
+) -> Result<RecommendedWatcher> {
+    watch(vec![source_dir.clone()], Vec::new(), move || {
+        match reload(&source_dir, &serve_dir, &lua) {
+            Ok(preprocessed) => info!(
+                "🔄 Reloaded, {} Reluax files preprocessed",
+                preprocessed.bright_green()
+            ),
+            Err(e) => error!("🛑 Reload failed: {}", e.bright_red()),
+        }
+    })
+}
+
+/// Watch directories for changes, calling `on_change` on a background thread
+/// once each burst of changes settles down. Changes inside the `ignored`
+/// directories don't count. The returned watcher stops watching when dropped.
+pub fn watch<F>(
+    dirs: Vec<PathBuf>,
+    ignored: Vec<PathBuf>,
+    mut on_change: F,
+) -> Result<RecommendedWatcher>
+where
+    F: FnMut() + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel::<notify::Result<Event>>();
+
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for dir in dirs.iter() {
+        watcher.watch(dir, RecursiveMode::Recursive)?;
+
+        info!("👀 Watching {} for changes", dir.display().bright_blue());
+    }
+
+    std::thread::spawn(move || {
+        while let Ok(event) = rx.recv() {
+            if !is_source_change(&event, &ignored) {
+                continue;
+            }
+            if let Ok(event) = &event {
+                for path in event.paths.iter() {
+                    debug!("👀 {} changed", path.display());
+                }
+            }
+
+            // wait until the changes settle down
+            while rx.recv_timeout(DEBOUNCE).is_ok() {}
+
+            on_change();
+        }
+    });
+
+    Ok(watcher)
+}
+
+/// Check if an event changed the project, ignoring the `.lua` files generated
+/// from `.luax` files, which would otherwise trigger reloads in local mode,
+/// and anything in the `ignored` directories
+fn is_source_change(event: &notify::Result<Event>, ignored: &[PathBuf]) -> bool {
+    let event = match event {
+        Ok(event) => event,
+        Err(_) => return false,
+    };
+
+    if event.kind.is_access() || event.kind.is_other() {
+        return false;
+    }
+
+    event
+        .paths
+        .iter()
+        .any(|path| !is_generated(path) && !ignored.iter().any(|dir| path.starts_with(dir)))
+}
+
+fn is_generated(path: &Path) -> bool {
+    path.extension().unwrap_or_default() == "lua" && path.with_extension("luax").is_file()
+}
+
+fn reload(source_dir: &Path, serve_dir: &Path, lua: &LuaPool) -> Result<usize> {
+    if source_dir != serve_dir {
+        crate::recurse_copy_lua(source_dir, serve_dir)?;
+    }
+
+    let preprocessed = luax::preprocess_dir(serve_dir, serve_dir)?;
+
+    lua.reload(|| luax::prepare_lua(true))?;
+
+    Ok(preprocessed)
+}
@@ -0,0 +1,23 @@
+-- The posts of the blog, newest first, with their bodies written in Markdown
+return {
+    {
+        slug = "hello-world",
+        title = "Hello, world!",
+        published = 1704067200,
+        body = [[
+Welcome to **PROJECT_NAME**, a blog running on
+[Reluax](https://github.com/Duckonaut/reluax).
+
+Posts are kept in `posts.lua`, and can use anything Markdown offers:
+
+- lists,
+- `inline code` and code blocks,
+- and tables.
+
+| Page        | Path           |
+|-------------|----------------|
+| Post list   | `/`            |
+| This post   | `/posts/hello-world` |
+]],
+    },
+}